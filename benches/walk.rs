@@ -0,0 +1,73 @@
+// benches/walk.rs
+//! Compares reading a repository's files on one thread and with the parallel walker.
+//!
+//! Run with `cargo bench --bench walk`.
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use git2prompt::processing::read_repository_files;
+use git2prompt::tokenizer::{BpeTokenizer, CharEstimator, Tokenizer};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const DIRECTORIES: usize = 40;
+const FILES_PER_DIRECTORY: usize = 50;
+
+/// A repository of `DIRECTORIES * FILES_PER_DIRECTORY` source files of a few KB each.
+fn setup_repository() -> TempDir {
+    let dir = TempDir::new().expect("failed to create temporary directory");
+    let line = "fn main() { println!(\"Hello, world!\"); let x = vec![1, 2, 3]; }\n";
+    for d in 0..DIRECTORIES {
+        let folder = dir.path().join(format!("module_{}", d));
+        fs::create_dir_all(&folder).expect("failed to create directory");
+        for f in 0..FILES_PER_DIRECTORY {
+            let content = line.repeat(20 + (d * f) % 50);
+            fs::write(folder.join(format!("file_{}.rs", f)), content)
+                .expect("failed to write file");
+        }
+    }
+    dir
+}
+
+fn read(path: &Path, tokenizer: &dyn Tokenizer, threads: usize) -> usize {
+    read_repository_files(path, None, None, None, tokenizer, false, threads)
+        .expect("failed to read repository")
+        .len()
+}
+
+fn bench_walk(c: &mut Criterion) {
+    let repository = setup_repository();
+    let path = repository.path();
+    let tokenizers: [(&str, Box<dyn Tokenizer>); 2] = [
+        ("chars", Box::new(CharEstimator)),
+        ("cl100k", Box::new(BpeTokenizer::cl100k())),
+    ];
+
+    let mut group = c.benchmark_group("read_repository_files");
+    group.sample_size(10);
+    for (name, tokenizer) in &tokenizers {
+        // Both walks must produce the same files in the same order
+        let sequential =
+            read_repository_files(path, None, None, None, tokenizer.as_ref(), false, 1)
+                .expect("failed to read repository");
+        let parallel = read_repository_files(path, None, None, None, tokenizer.as_ref(), false, 0)
+            .expect("failed to read repository");
+        assert_eq!(sequential.len(), DIRECTORIES * FILES_PER_DIRECTORY);
+        assert!(
+            sequential
+                .iter()
+                .zip(&parallel)
+                .all(|(a, b)| a.path == b.path && a.tokens == b.tokens)
+        );
+
+        group.bench_function(BenchmarkId::new("sequential", name), |b| {
+            b.iter(|| read(path, tokenizer.as_ref(), 1))
+        });
+        group.bench_function(BenchmarkId::new("parallel", name), |b| {
+            b.iter(|| read(path, tokenizer.as_ref(), 0))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_walk);
+criterion_main!(benches);
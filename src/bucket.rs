@@ -0,0 +1,172 @@
+// src/bucket.rs
+use crate::error::Error;
+use crate::tokenizer::Tokenizer;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// A single file collected into a bucket.
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    /// Path relative to the repository root.
+    pub path: PathBuf,
+    /// Highlight.js language alias derived from the extension (may be empty).
+    pub language: String,
+    /// The file content, unmodified.
+    pub content: String,
+    /// Number of tokens in `content`.
+    pub tokens: usize,
+    /// Set when an oversized file was split across output parts: `(index, count)`, 1-based.
+    pub segment: Option<(usize, usize)>,
+    /// Whether `content` is the file itself or a diff of it.
+    pub kind: EntryKind,
+    /// File to read `content` from when it is not kept in memory; `content` stays empty
+    /// until [`FileEntry::load`] is called.
+    pub source: Option<PathBuf>,
+}
+
+/// What an entry's content represents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntryKind {
+    /// The file's content.
+    #[default]
+    File,
+    /// Diff hunks of the file (e.g. from a pull request).
+    Patch,
+    /// A description of the change rather than a file (e.g. a pull request's discussion).
+    Metadata,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Patch => "patch",
+            EntryKind::Metadata => "metadata",
+        }
+    }
+}
+
+impl FileEntry {
+    /// Build an entry and count the tokens of its content.
+    pub fn new(path: PathBuf, language: &str, content: String, tokenizer: &dyn Tokenizer) -> Self {
+        Self {
+            tokens: tokenizer.count(&content),
+            path,
+            language: language.to_string(),
+            content,
+            segment: None,
+            kind: EntryKind::File,
+            source: None,
+        }
+    }
+
+    /// Drop the content, which is read again from `source` when needed.
+    pub fn unloaded(self, source: PathBuf) -> Self {
+        Self {
+            content: String::new(),
+            source: Some(source),
+            ..self
+        }
+    }
+
+    /// Read the content back from `source`, if it was not kept in memory.
+    ///
+    /// The token count is the one taken when the file was first read, which output
+    /// headers and token budgets were planned with; it is not counted again. A file that
+    /// changed in between is written with its new content.
+    pub async fn load(&mut self) -> Result<(), Error> {
+        if let Some(source) = self.source.take() {
+            self.content = fs::read_to_string(&source)
+                .await
+                .map_err(|e| Error::io(format!("Failed to read {:?}", source), e))?;
+        }
+        Ok(())
+    }
+
+    /// Build an entry holding a diff of the file at `path`.
+    pub fn patch(path: PathBuf, patch: String, tokenizer: &dyn Tokenizer) -> Self {
+        Self {
+            kind: EntryKind::Patch,
+            ..Self::new(path, "diff", patch, tokenizer)
+        }
+    }
+
+    /// Build an entry holding Markdown metadata, named `name`.
+    pub fn metadata(name: &str, content: String, tokenizer: &dyn Tokenizer) -> Self {
+        Self {
+            kind: EntryKind::Metadata,
+            ..Self::new(PathBuf::from(name), "markdown", content, tokenizer)
+        }
+    }
+
+    /// The path as shown in output headers, marking patches, metadata and the segment
+    /// if the file was split.
+    pub fn label(&self) -> String {
+        let mut label = self.path.display().to_string();
+        if self.kind != EntryKind::File {
+            label.push_str(&format!(" ({})", self.kind.as_str()));
+        }
+        if let Some((index, count)) = self.segment {
+            label.push_str(&format!(" (segment {} of {})", index, count));
+        }
+        label
+    }
+}
+
+/// A file changed between two commits (in a pull request or a local diff),
+/// with the content requested for it.
+#[derive(Clone, Debug, Default)]
+pub struct ChangedFile {
+    /// Path relative to the repository root, after the change.
+    pub path: PathBuf,
+    /// The file as of the newer commit, unless removed, binary or not requested.
+    pub content: Option<String>,
+    /// Diff hunks of the change, unless binary or not requested.
+    pub patch: Option<String>,
+}
+
+/// The ordered files that end up in one output file
+/// (the repository's default content or one split folder).
+#[derive(Clone, Debug, Default)]
+pub struct Bucket {
+    pub files: Vec<FileEntry>,
+}
+
+impl Bucket {
+    pub fn push(&mut self, entry: FileEntry) {
+        self.files.push(entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn total_tokens(&self) -> usize {
+        self.files.iter().map(|f| f.tokens).sum()
+    }
+}
+
+/// A file placed in an output document, tagged with its repository when
+/// several repositories are merged into one document.
+#[derive(Clone, Debug)]
+pub struct DocumentItem {
+    pub repository: Option<String>,
+    pub file: FileEntry,
+}
+
+impl DocumentItem {
+    pub fn new(repository: Option<&str>, file: FileEntry) -> Self {
+        Self {
+            repository: repository.map(str::to_string),
+            file,
+        }
+    }
+
+    /// The label used for the file, prefixed with its repository when merging.
+    pub fn label(&self) -> String {
+        match &self.repository {
+            Some(repository) => format!("{}/{}", repository, self.file.label()),
+            None => self.file.label(),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+// src/cache.rs
+use crate::error::Error;
+use crate::repository::Repository;
+use same_file::Handle;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::info;
+use walkdir::WalkDir;
+
+/// File inside a clone's `.git` directory whose modification time records its last use.
+const LAST_USED_MARKER: &str = "git2prompt-last-used";
+
+/// A directory keeping clones between runs, so that repositories processed again are
+/// fetched incrementally instead of cloned from scratch.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub dir: PathBuf,
+    /// Total size in bytes above which the least recently used clones are removed.
+    pub max_size: Option<u64>,
+}
+
+/// A lock on a cached clone, taken by a run using it and released when dropped.
+#[derive(Debug)]
+pub struct CacheLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl CacheLock {
+    /// Removes the lock file while it is still locked, once its clone is removed.
+    /// Runs waiting on it find it gone when they get the lock, and lock a new one.
+    fn remove(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path)
+            .map_err(|e| Error::io(format!("Failed to remove lock file {:?}", self.path), e))
+    }
+
+    /// Whether the locked file is still the lock file of the clone, and not one removed
+    /// by a run that held it before.
+    fn is_current(&self) -> bool {
+        let locked = self.file.try_clone().and_then(Handle::from_file);
+        let current = Handle::from_path(&self.path);
+        matches!((locked, current), (Ok(locked), Ok(current)) if locked == current)
+    }
+}
+
+/// A clone kept in the cache.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    /// Size on disk in bytes.
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, max_size: Option<u64>) -> Self {
+        Self { dir, max_size }
+    }
+
+    /// The platform cache directory, e.g. `$XDG_CACHE_HOME/git2prompt` or
+    /// `~/.cache/git2prompt` on Linux.
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("git2prompt"))
+    }
+
+    /// Where the clone of `repository` is kept. The name is followed by a hash of the URL,
+    /// so that repositories with the same name on different hosts do not share a clone.
+    pub fn entry_path(&self, repository: &Repository) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{:08x}",
+            repository.name,
+            fnv1a(repository.url.as_bytes())
+        ))
+    }
+
+    /// Locks the clone at `path`, waiting while another run uses it. The lock file lies
+    /// beside the clone, which may be removed and cloned again while locked.
+    pub fn lock(&self, path: &Path) -> Result<CacheLock, Error> {
+        loop {
+            let lock = open_lock_file(path)?;
+            match lock.file.try_lock() {
+                Ok(()) => {}
+                Err(fs::TryLockError::WouldBlock) => {
+                    info!("Waiting for another run using the cached clone {:?}", path);
+                    lock.file.lock().map_err(|e| lock_error(path, e))?;
+                }
+                Err(fs::TryLockError::Error(e)) => return Err(lock_error(path, e)),
+            }
+            if lock.is_current() {
+                return Ok(lock);
+            }
+        }
+    }
+
+    /// Locks the clone at `path` unless another run uses it.
+    fn try_lock(&self, path: &Path) -> Result<Option<CacheLock>, Error> {
+        loop {
+            let lock = open_lock_file(path)?;
+            match lock.file.try_lock() {
+                Ok(()) if lock.is_current() => return Ok(Some(lock)),
+                Ok(()) => {}
+                Err(fs::TryLockError::WouldBlock) => return Ok(None),
+                Err(fs::TryLockError::Error(e)) => return Err(lock_error(path, e)),
+            }
+        }
+    }
+
+    /// Record that the clone at `path` was used now.
+    pub fn touch(&self, path: &Path) -> Result<(), Error> {
+        let marker = path.join(".git").join(LAST_USED_MARKER);
+        fs::write(&marker, b"")
+            .map_err(|e| Error::io(format!("Failed to update cache marker {:?}", marker), e))
+    }
+
+    /// Lists the cached clones, least recently used first. Only directories holding a git
+    /// repository are considered, so a misconfigured cache directory is never emptied.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let dir_entries = fs::read_dir(&self.dir)
+            .map_err(|e| Error::io(format!("Failed to read cache directory {:?}", self.dir), e))?;
+
+        let mut entries = Vec::new();
+        for dir_entry in dir_entries.flatten() {
+            let path = dir_entry.path();
+            let git_dir = path.join(".git");
+            if !git_dir.is_dir() {
+                continue;
+            }
+            // Clones made before markers existed count as the oldest
+            let last_used = fs::metadata(git_dir.join(LAST_USED_MARKER))
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push(CacheEntry {
+                size: directory_size(&path),
+                path,
+                last_used,
+            });
+        }
+        entries.sort_by(|a, b| a.last_used.cmp(&b.last_used).then(a.path.cmp(&b.path)));
+        Ok(entries)
+    }
+
+    /// Removes the least recently used clones until the cache fits in `max_size`.
+    /// Clones in `keep` (those of the current run) and clones locked by other runs are
+    /// never removed.
+    /// Returns the removed entries.
+    pub fn prune(&self, keep: &[PathBuf]) -> Result<Vec<CacheEntry>, Error> {
+        let Some(max_size) = self.max_size else {
+            return Ok(Vec::new());
+        };
+        let entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        let mut removed = Vec::new();
+        for entry in entries {
+            if total <= max_size {
+                break;
+            }
+            if keep.contains(&entry.path) {
+                continue;
+            }
+            let Some(lock) = self.try_lock(&entry.path)? else {
+                continue;
+            };
+            fs::remove_dir_all(&entry.path).map_err(|e| {
+                Error::io(format!("Failed to remove cached clone {:?}", entry.path), e)
+            })?;
+            lock.remove()?;
+            total -= entry.size;
+            removed.push(entry);
+        }
+        Ok(removed)
+    }
+
+    /// Removes every cached clone not locked by another run. Returns the removed entries.
+    pub fn clean(&self) -> Result<Vec<CacheEntry>, Error> {
+        let mut removed = Vec::new();
+        for entry in self.entries()? {
+            let Some(lock) = self.try_lock(&entry.path)? else {
+                info!(
+                    "Keeping cached clone {:?}, which another run uses",
+                    entry.path
+                );
+                continue;
+            };
+            fs::remove_dir_all(&entry.path).map_err(|e| {
+                Error::io(format!("Failed to remove cached clone {:?}", entry.path), e)
+            })?;
+            lock.remove()?;
+            removed.push(entry);
+        }
+        Ok(removed)
+    }
+}
+
+/// Opens (or creates) the lock file of the clone at `path`, without locking it.
+fn open_lock_file(path: &Path) -> Result<CacheLock, Error> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let path = PathBuf::from(lock_path);
+    let file = fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| Error::io(format!("Failed to open lock file {:?}", path), e))?;
+    Ok(CacheLock { file, path })
+}
+
+fn lock_error(path: &Path, e: std::io::Error) -> Error {
+    Error::io(format!("Failed to lock the cached clone {:?}", path), e)
+}
+
+/// Total size of the files below `path`.
+fn directory_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// 32-bit FNV-1a, stable across runs and platforms (unlike `DefaultHasher`).
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Parses a size such as "500MB", "2G", "1.5GiB" or "1048576" (bytes).
+/// Units are binary: 1K = 1024 bytes.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| Error::invalid(format!("Invalid size {:?}", size)))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => {
+            return Err(Error::invalid(format!(
+                "Invalid size unit {:?} in {:?}",
+                unit, size
+            )));
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a size in bytes for display, e.g. "1.5 GB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_size("500MB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1.5 GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("10k").unwrap(), 10 << 10);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(3 << 29), "1.5 GB");
+    }
+
+    #[test]
+    fn test_lock_file_removed_with_clone() {
+        let dir =
+            std::env::temp_dir().join(format!("git2prompt-cache-lock-{}", std::process::id()));
+        let cache = Cache::new(dir.clone(), None);
+        let clone = dir.join("owner-repo-00000000");
+        let lock_path = dir.join("owner-repo-00000000.lock");
+        fs::create_dir_all(clone.join(".git")).unwrap();
+
+        // A run waiting for the clone while it is cleaned locks a new lock file
+        let held = cache.lock(&clone).unwrap();
+        let waiting = {
+            let cache = cache.clone();
+            let clone = clone.clone();
+            std::thread::spawn(move || cache.lock(&clone).unwrap())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        fs::remove_dir_all(&clone).unwrap();
+        held.remove().unwrap();
+        drop(held);
+        let waited = waiting.join().unwrap();
+        assert!(waited.is_current());
+        assert!(cache.try_lock(&clone).unwrap().is_none());
+        drop(waited);
+
+        fs::create_dir_all(clone.join(".git")).unwrap();
+        assert_eq!(cache.clean().unwrap().len(), 1);
+        assert!(!clone.exists());
+        assert!(!lock_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry_path() {
+        let cache = Cache::new(PathBuf::from("cache"), None);
+        let github = Repository::new(Path::new("unused"), "owner/repo", None).unwrap();
+        let gitlab =
+            Repository::new(Path::new("unused"), "https://gitlab.com/owner/repo", None).unwrap();
+        assert_eq!(github.name, gitlab.name);
+
+        let path = cache.entry_path(&github);
+        assert_eq!(path, cache.entry_path(&github));
+        assert_ne!(path, cache.entry_path(&gitlab));
+        assert!(path.starts_with("cache"));
+        assert!(
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("owner-repo-")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// src/chunking.rs
+use crate::bucket::FileEntry;
+use crate::error::Error;
+use crate::tokenizer::Tokenizer;
+use std::ops::Range;
+
+/// Group consecutive items into parts whose summed cost stays within `budget`.
+/// Items keep their order; an item costing more than the budget gets a part of its own.
+pub fn plan_parts(costs: &[usize], budget: usize) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+
+    for (i, &cost) in costs.iter().enumerate() {
+        if i > start && used + cost > budget {
+            parts.push(start..i);
+            start = i;
+            used = 0;
+        }
+        used += cost;
+    }
+    if start < costs.len() {
+        parts.push(start..costs.len());
+    }
+    parts
+}
+
+/// Split an oversized file into segments whose content fits in `budget` tokens.
+/// `measure` gives the cost of a piece of content as it will be rendered.
+/// Splits happen at line boundaries; single lines longer than the budget are split by characters.
+pub fn split_entry(
+    entry: &FileEntry,
+    budget: usize,
+    measure: &dyn Fn(&str) -> usize,
+    tokenizer: &dyn Tokenizer,
+) -> Result<Vec<FileEntry>, Error> {
+    if budget == 0 {
+        return Err(Error::invalid(format!(
+            "Token budget is too small to hold any content of {}",
+            entry.path.display()
+        )));
+    }
+
+    let mut contents: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut used = 0;
+    for line in entry.content.split_inclusive('\n') {
+        for piece in split_long_line(line, budget, measure) {
+            let cost = measure(piece);
+            if !current.is_empty() && used + cost > budget {
+                contents.push(std::mem::take(&mut current));
+                used = 0;
+            }
+            current.push_str(piece);
+            used += cost;
+        }
+    }
+    if !current.is_empty() || contents.is_empty() {
+        contents.push(current);
+    }
+
+    let count = contents.len();
+    Ok(contents
+        .into_iter()
+        .enumerate()
+        .map(|(i, content)| {
+            // Renderers put the closing fence/tag on its own line already
+            let content = if i + 1 < count {
+                content
+                    .strip_suffix('\n')
+                    .map(str::to_string)
+                    .unwrap_or(content)
+            } else {
+                content
+            };
+            let mut segment =
+                FileEntry::new(entry.path.clone(), &entry.language, content, tokenizer);
+            segment.segment = Some((i + 1, count));
+            segment.kind = entry.kind;
+            segment
+        })
+        .collect())
+}
+
+/// Split a single line into character chunks that each fit in `budget` tokens.
+fn split_long_line<'a>(
+    line: &'a str,
+    budget: usize,
+    measure: &dyn Fn(&str) -> usize,
+) -> Vec<&'a str> {
+    if measure(line) <= budget {
+        return vec![line];
+    }
+
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        // A token covers at least one character, so `budget` characters is a good first guess
+        let mut len = rest
+            .char_indices()
+            .nth(budget)
+            .map_or(rest.len(), |(idx, _)| idx);
+        while len > 0 && measure(&rest[..len]) > budget {
+            len = rest[..len]
+                .char_indices()
+                .nth(rest[..len].chars().count() / 2)
+                .map_or(0, |(idx, _)| idx);
+        }
+        if len == 0 {
+            // Budget cannot fit a single character; take one anyway to make progress
+            len = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        pieces.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::CharEstimator;
+    use std::path::PathBuf;
+
+    fn measure(text: &str) -> usize {
+        CharEstimator.count(text)
+    }
+
+    #[test]
+    fn test_plan_parts() {
+        assert_eq!(plan_parts(&[], 10), Vec::<Range<usize>>::new());
+        assert_eq!(plan_parts(&[3, 3, 3], 10), vec![0..3]);
+        assert_eq!(plan_parts(&[4, 4, 4], 10), vec![0..2, 2..3]);
+        // Oversized items get a part of their own
+        assert_eq!(plan_parts(&[2, 20, 2], 10), vec![0..1, 1..2, 2..3]);
+    }
+
+    #[test]
+    fn test_split_entry() {
+        let content = (0..10)
+            .map(|i| format!("line {:02}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let entry = FileEntry::new(PathBuf::from("big.txt"), "", content, &CharEstimator);
+
+        let segments = split_entry(&entry, 6, &measure, &CharEstimator).unwrap();
+        assert!(segments.len() > 1);
+        for (i, segment) in segments.iter().enumerate() {
+            assert!(segment.tokens <= 6, "segment {} too large", i);
+            assert_eq!(segment.segment, Some((i + 1, segments.len())));
+        }
+        let rejoined: Vec<String> = segments.iter().map(|s| s.content.clone()).collect();
+        assert_eq!(rejoined.join("\n"), entry.content);
+    }
+
+    #[test]
+    fn test_split_long_line() {
+        let line = "x".repeat(100);
+        let pieces = split_long_line(&line, 5, &measure);
+        assert!(pieces.iter().all(|p| measure(p) <= 5));
+        assert_eq!(pieces.concat(), line);
+    }
+}
@@ -1,4 +1,10 @@
 // src/config.rs
+use crate::auth::Token;
+use crate::error::Error;
+use crate::format::OutputFormat;
+use crate::github::PrContent;
+use crate::order::FileOrder;
+use crate::tokenizer::TokenizerKind;
 use serde::Deserialize;
 use std::path::Path;
 use tokio::fs;
@@ -15,28 +21,60 @@ pub struct Config {
     pub no_headers: Option<bool>,
     /// Path to a custom ignore file
     pub ignore_file: Option<String>,
+    /// Tokenizer used for token counts: "cl100k" (default), "o200k" or "chars"
+    pub tokenizer: Option<TokenizerKind>,
+    /// Whether to write token counts into the output file headers (default: false)
+    pub token_header: Option<bool>,
+    /// Maximum number of tokens per output file; larger outputs are split into parts
+    pub max_tokens: Option<usize>,
+    /// Order of the files in each output file: "path" (default), "directory-first",
+    /// "docs-first", "size" or "recent"
+    pub order: Option<FileOrder>,
+    /// Patterns (gitignore syntax) of files written first, in the order listed
+    pub priority: Option<Vec<String>>,
+    /// Output format: "markdown" (default), "xml", "json" or "jsonl"
+    pub format: Option<OutputFormat>,
+    /// Path to a template file defining the output layout; replaces `format`
+    pub template: Option<String>,
+    /// What PR mode includes for each changed file: "patch" (default), "full" or "both"
+    pub pr_content: Option<PrContent>,
+    /// Whether PR mode leaves out the pull request's description and discussion (default: false)
+    pub no_pr_metadata: Option<bool>,
+    /// Base URL of the GitHub REST API, e.g. for GitHub Enterprise
+    pub api_url: Option<String>,
+    /// Access token for private GitHub repositories and the GitHub API (github.com and
+    /// the `api_url` host only).
+    /// Prefer the GIT2PROMPT_TOKEN or GITHUB_TOKEN environment variables.
+    pub token: Option<Token>,
+    /// Private key for SSH remotes, used when the SSH agent has no usable key
+    pub ssh_key: Option<String>,
+    /// Directory receiving the output files (default: "output")
+    pub output_dir: Option<String>,
+    /// Directory receiving clones when the cache is off (default: a new temporary directory)
+    pub work_dir: Option<String>,
+    /// Whether to clone into a temporary directory instead of the clone cache (default: false)
+    pub no_cache: Option<bool>,
+    /// Directory keeping clones between runs (default: the platform cache directory)
+    pub cache_dir: Option<String>,
+    /// Size above which the least recently used cached clones are removed, e.g. "2GB"
+    pub cache_max_size: Option<String>,
 }
 
 impl Config {
-    /// Attempt to load configuration from a file.
-    /// Returns default config if file doesn't exist or errors.
-    pub async fn load_from_file(path: &Path) -> Self {
+    /// Load configuration from a file.
+    /// Returns the default config if the file doesn't exist, and an error if it cannot be
+    /// read or parsed.
+    pub async fn load_from_file(path: &Path) -> Result<Self, Error> {
         if !path.exists() {
-            return Config::default();
+            return Ok(Config::default());
         }
 
-        match fs::read_to_string(path).await {
-            Ok(content) => match toml::from_str(&content) {
-                Ok(config) => config,
-                Err(e) => {
-                    eprintln!("Warning: Failed to parse config file {:?}: {}", path, e);
-                    Config::default()
-                }
-            },
-            Err(e) => {
-                eprintln!("Warning: Failed to read config file {:?}: {}", path, e);
-                Config::default()
-            }
-        }
+        let content = fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::io(format!("Failed to read config file {:?}", path), e))?;
+        toml::from_str(&content).map_err(|e| Error::Config {
+            message: format!("Failed to parse config file {:?}", path),
+            source: Some(Box::new(e)),
+        })
     }
 }
@@ -0,0 +1,57 @@
+// src/document.rs
+use crate::bucket::DocumentItem;
+use crate::format::DocumentInfo;
+
+/// A rendered output document: the content of one output file, with what went into it.
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// File name used when writing to a directory, e.g. "owner-repo_src_processed.md".
+    pub name: String,
+    /// The repository, unless several repositories are merged into the document.
+    pub repository: Option<String>,
+    /// The split folder, if the document holds one.
+    pub bucket: Option<String>,
+    /// `(index, count)`, 1-based, when the document was split into parts.
+    pub part: Option<(usize, usize)>,
+    /// The rendered content, in the configured format or template.
+    pub content: String,
+    /// Labels of the included files as written in the headers, prefixed with their
+    /// repository when several are merged.
+    pub files: Vec<String>,
+    pub stats: DocumentStats,
+}
+
+/// Sizes of a rendered document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DocumentStats {
+    /// Number of included files, counting each segment of a split file.
+    pub files: usize,
+    /// Tokens of the included files' content, as in the token report. Headers and
+    /// markup added by the format are not counted.
+    pub tokens: usize,
+    /// Length of the rendered content in bytes.
+    pub bytes: usize,
+}
+
+impl Document {
+    pub(crate) fn new(
+        name: String,
+        info: &DocumentInfo,
+        items: &[DocumentItem],
+        content: String,
+    ) -> Self {
+        Self {
+            name,
+            repository: info.repository.map(str::to_string),
+            bucket: info.bucket.map(str::to_string),
+            part: info.part,
+            files: items.iter().map(DocumentItem::label).collect(),
+            stats: DocumentStats {
+                files: items.len(),
+                tokens: items.iter().map(|item| item.file.tokens).sum(),
+                bytes: content.len(),
+            },
+            content,
+        }
+    }
+}
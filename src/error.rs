@@ -0,0 +1,162 @@
+// src/error.rs
+use reqwest::StatusCode;
+use std::fmt;
+
+/// Errors returned by the library.
+///
+/// Each variant carries a message describing what was being done, which is what it
+/// displays; the underlying error, if any, is returned by
+/// [`std::error::Error::source`] rather than repeated in the message.
+#[derive(Debug)]
+pub enum Error {
+    /// A git operation failed: opening, cloning, fetching, diffing, or resolving a
+    /// reference that does not exist.
+    Git {
+        message: String,
+        source: Option<git2::Error>,
+    },
+    /// An HTTP request could not be sent, or its response not be read or decoded.
+    Http {
+        message: String,
+        source: reqwest::Error,
+    },
+    /// The GitHub API answered with an unsuccessful status.
+    Api {
+        message: String,
+        status: StatusCode,
+        /// The request was refused because the rate limit was exhausted.
+        rate_limited: bool,
+    },
+    /// Reading or writing a file or directory failed.
+    Io {
+        message: String,
+        source: std::io::Error,
+    },
+    /// A configuration or template file could not be parsed.
+    Config {
+        message: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    /// An argument is invalid, or options do not fit together.
+    InvalidInput(String),
+    /// A worker task panicked or was cancelled.
+    Task(tokio::task::JoinError),
+}
+
+impl Error {
+    pub(crate) fn git(message: impl Into<String>, source: impl Into<Option<git2::Error>>) -> Self {
+        Error::Git {
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn http(message: impl Into<String>, source: reqwest::Error) -> Self {
+        Error::Http {
+            message: message.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn io(message: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            message: message.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        Error::InvalidInput(message.into())
+    }
+
+    /// The message followed by those of the underlying errors, separated by `: `.
+    pub fn full_message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            message.push_str(&format!(": {}", error));
+            source = error.source();
+        }
+        message
+    }
+
+    /// Whether a repository, pull request or file was not found by the GitHub API.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::Api { status, .. } if *status == StatusCode::NOT_FOUND)
+    }
+
+    /// Whether the GitHub API refused a request because of its rate limit.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            self,
+            Error::Api {
+                rate_limited: true,
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Git { message, .. }
+            | Error::Http { message, .. }
+            | Error::Io { message, .. }
+            | Error::Config { message, .. } => write!(f, "{}", message),
+            Error::Api {
+                message,
+                status,
+                rate_limited,
+            } => {
+                write!(f, "{}: {}", message, status)?;
+                if *rate_limited {
+                    write!(f, " (rate limit exceeded; set GITHUB_TOKEN to raise it)")?;
+                }
+                Ok(())
+            }
+            Error::InvalidInput(message) => write!(f, "{}", message),
+            Error::Task(_) => write!(f, "Task failed unexpectedly"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Git { source, .. } => source.as_ref().map(|e| e as _),
+            Error::Http { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            Error::Config { source, .. } => source.as_deref().map(|e| e as _),
+            Error::Task(source) => Some(source),
+            Error::Api { .. } | Error::InvalidInput(_) => None,
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(error: tokio::task::JoinError) -> Self {
+        Error::Task(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cause_is_displayed_once() {
+        let error = Error::io(
+            "Failed to read \"a.txt\"",
+            std::io::Error::other("disk on fire"),
+        );
+        assert_eq!(error.to_string(), "Failed to read \"a.txt\"");
+        assert_eq!(
+            error.full_message(),
+            "Failed to read \"a.txt\": disk on fire"
+        );
+
+        let error = Error::git("Reference not found", None);
+        assert_eq!(error.full_message(), "Reference not found");
+    }
+}
@@ -0,0 +1,451 @@
+// src/format.rs
+use crate::bucket::{DocumentItem, EntryKind};
+use crate::template::{Template, TemplateContext};
+use crate::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The layout used to write output files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Markdown with `## File:` headers and fenced code blocks.
+    #[default]
+    Markdown,
+    /// Anthropic-style `<documents><document><source>…</source><document_content>` XML.
+    Xml,
+    /// A JSON array of `{path, language, content, tokens}` objects.
+    Json,
+    /// One JSON object per line, with the same fields as `Json`.
+    Jsonl,
+}
+
+/// Settings controlling how output files are rendered.
+#[derive(Debug, Default, Clone)]
+pub struct RenderOptions {
+    pub format: OutputFormat,
+    /// Do not add file path headers above code blocks (Markdown only).
+    pub no_headers: bool,
+    /// Write token counts into the header of each output file.
+    pub token_header: bool,
+    /// Split output files larger than this many tokens into parts.
+    pub max_tokens: Option<usize>,
+    /// User-defined template; replaces `format` when set.
+    pub template: Option<Arc<Template>>,
+}
+
+/// Describes the output file being rendered.
+#[derive(Debug, Clone, Copy)]
+pub struct DocumentInfo<'a> {
+    /// Title written as the top-level header.
+    pub title: &'a str,
+    /// The repository, unless several repositories are merged into the file.
+    pub repository: Option<&'a str>,
+    /// The split folder, if the file holds one.
+    pub bucket: Option<&'a str>,
+    /// `(repository, commit SHA)` of the repositories whose files are included.
+    pub commits: &'a [(String, String)],
+    /// `(index, count)`, 1-based, when the document was split into parts.
+    pub part: Option<(usize, usize)>,
+}
+
+impl RenderOptions {
+    /// File extension used for output files.
+    pub fn extension(&self) -> &str {
+        match &self.template {
+            Some(template) => template.extension(),
+            None => self.format.extension(),
+        }
+    }
+
+    /// Render a complete output file with the template or the format.
+    pub fn render(
+        &self,
+        info: &DocumentInfo,
+        items: &[DocumentItem],
+        tokenizer: &dyn Tokenizer,
+    ) -> String {
+        match &self.template {
+            Some(template) => template.render(&TemplateContext::new(info, items, tokenizer)),
+            None => self.format.render(info, items, self, tokenizer),
+        }
+    }
+
+    /// Tokens used by a file with no content: the header, footer and any
+    /// per-document text, with room for a large part number.
+    pub(crate) fn fixed_cost(&self, info: &DocumentInfo, tokenizer: &dyn Tokenizer) -> usize {
+        let info = DocumentInfo {
+            part: Some((999, 999)),
+            ..*info
+        };
+        match &self.template {
+            Some(template) => {
+                tokenizer.count(&template.render(&TemplateContext::new(&info, &[], tokenizer)))
+            }
+            None => {
+                tokenizer.count(&self.format.render_header(&info, &[], self, tokenizer))
+                    + tokenizer.count(self.format.render_footer())
+            }
+        }
+    }
+
+    /// Tokens a file adds to an output file: its rendered block (assuming it opens
+    /// a repository section and is not the first file) and its line in the file list.
+    pub(crate) fn item_cost(
+        &self,
+        info: &DocumentInfo,
+        item: &DocumentItem,
+        tokenizer: &dyn Tokenizer,
+    ) -> usize {
+        match &self.template {
+            Some(template) => {
+                let context = TemplateContext::new(info, &[], tokenizer);
+                tokenizer.count(&template.render_file(&context, &TemplateContext::file(item, 999)))
+            }
+            None => {
+                tokenizer.count(&self.format.render_item(item, 1, None, self))
+                    + tokenizer.count(&self.format.list_line(item, self))
+            }
+        }
+    }
+
+    /// File content as embedded in the output. Templates insert it unchanged.
+    pub(crate) fn encode_content<'a>(&self, language: &str, content: &'a str) -> Cow<'a, str> {
+        match &self.template {
+            Some(_) => Cow::Borrowed(content),
+            None => self.format.encode_content(language, content),
+        }
+    }
+}
+
+/// A file as written by the JSON and JSONL formats.
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repository: Option<&'a str>,
+    path: String,
+    language: &'a str,
+    content: &'a str,
+    tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment: Option<[usize; 2]>,
+    /// "patch" for diffs; omitted for plain files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<&'static str>,
+}
+
+const MARKDOWN_NOTE: &str = "> **Note to AI agents:** Headers in this file have been modified (prepended with '##') to avoid conflict with the main document structure.\n\n";
+
+impl OutputFormat {
+    /// File extension used for output files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Markdown => "md",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+        }
+    }
+
+    /// Render a complete output file.
+    pub fn render(
+        self,
+        info: &DocumentInfo,
+        items: &[DocumentItem],
+        options: &RenderOptions,
+        tokenizer: &dyn Tokenizer,
+    ) -> String {
+        let mut output = self.render_header(info, items, options, tokenizer);
+        for (index, item) in items.iter().enumerate() {
+            let previous = index.checked_sub(1).map(|i| &items[i]);
+            output.push_str(&self.render_item(item, index, previous, options));
+        }
+        output.push_str(self.render_footer());
+        output
+    }
+
+    /// Everything written before the first file: title, part number, commits and file list.
+    pub fn render_header(
+        self,
+        info: &DocumentInfo,
+        items: &[DocumentItem],
+        options: &RenderOptions,
+        tokenizer: &dyn Tokenizer,
+    ) -> String {
+        let DocumentInfo {
+            title,
+            part,
+            commits,
+            ..
+        } = *info;
+        let total: usize = items.iter().map(|item| item.file.tokens).sum();
+        match self {
+            OutputFormat::Markdown => {
+                let mut header = format!("# {}", title);
+                if let Some((index, count)) = part {
+                    header.push_str(&format!(" (part {} of {})", index, count));
+                }
+                header.push('\n');
+
+                match commits {
+                    [] => {}
+                    [(_, commit)] if info.repository.is_some() => {
+                        header.push_str(&format!("Commit: {}\n\n", commit));
+                    }
+                    commits => {
+                        header.push_str("Commits:\n");
+                        for (repository, commit) in commits {
+                            header.push_str(&format!("- {}: {}\n", repository, commit));
+                        }
+                        header.push('\n');
+                    }
+                }
+
+                if options.token_header {
+                    header.push_str(&format!(
+                        "Total tokens: {} ({})\n\n",
+                        total,
+                        tokenizer.name()
+                    ));
+                } else if part.is_some() {
+                    header.push_str("Files in this part:\n\n");
+                }
+                if options.token_header || part.is_some() {
+                    for item in items {
+                        header.push_str(&self.list_line(item, options));
+                    }
+                    header.push('\n');
+                }
+                header
+            }
+            OutputFormat::Xml => {
+                let mut header = format!("<documents title=\"{}\"", escape_xml(title));
+                if let Some((index, count)) = part {
+                    header.push_str(&format!(" part=\"{}\" parts=\"{}\"", index, count));
+                }
+                if !commits.is_empty() {
+                    // "sha" for one repository, "repo@sha repo@sha" when merged
+                    let commits = match commits {
+                        [(_, commit)] if info.repository.is_some() => commit.clone(),
+                        commits => commits
+                            .iter()
+                            .map(|(repository, commit)| format!("{}@{}", repository, commit))
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    };
+                    header.push_str(&format!(" commit=\"{}\"", escape_xml(&commits)));
+                }
+                if options.token_header {
+                    header.push_str(&format!(
+                        " tokens=\"{}\" tokenizer=\"{}\"",
+                        total,
+                        escape_xml(tokenizer.name())
+                    ));
+                }
+                header.push_str(">\n");
+                header
+            }
+            OutputFormat::Json => "[".to_string(),
+            OutputFormat::Jsonl => String::new(),
+        }
+    }
+
+    /// A single file block. `index` is the file's 0-based position in the output file.
+    pub fn render_item(
+        self,
+        item: &DocumentItem,
+        index: usize,
+        previous: Option<&DocumentItem>,
+        options: &RenderOptions,
+    ) -> String {
+        let file = &item.file;
+        match self {
+            OutputFormat::Markdown => {
+                let mut block = String::new();
+
+                // Introduce each repository when several are merged into one file
+                if let Some(repository) = &item.repository
+                    && previous.and_then(|p| p.repository.as_ref()) != Some(repository)
+                {
+                    block.push_str(&format!("## Repository: {}\n", repository));
+                }
+
+                if !options.no_headers {
+                    // Use ### for merged files context, ## for single file context
+                    let prefix = if item.repository.is_some() {
+                        "###"
+                    } else {
+                        "##"
+                    };
+                    block.push_str(&format!("{} File: {}\n", prefix, file.label()));
+                }
+
+                let content = self.encode_content(&file.language, &file.content);
+                if file.language == "markdown" {
+                    // Wrap in five backticks to avoid conflicts with existing triple backticks
+                    block.push_str(&format!("`````{}\n", file.language));
+                    block.push_str(MARKDOWN_NOTE);
+                    block.push_str(&content);
+                    block.push_str("\n`````\n\n");
+                } else {
+                    block.push_str(&format!("```{}\n", file.language));
+                    block.push_str(&content);
+                    block.push_str("\n```\n\n");
+                }
+                block
+            }
+            OutputFormat::Xml => {
+                let mut block = format!("<document index=\"{}\"", index + 1);
+                if file.kind != EntryKind::File {
+                    block.push_str(&format!(" kind=\"{}\"", file.kind.as_str()));
+                }
+                if options.token_header {
+                    block.push_str(&format!(" tokens=\"{}\"", file.tokens));
+                }
+                block.push_str(&format!(
+                    ">\n<source>{}</source>\n<document_content>\n{}\n</document_content>\n</document>\n",
+                    escape_xml(&item.label()),
+                    self.encode_content(&file.language, &file.content)
+                ));
+                block
+            }
+            OutputFormat::Json | OutputFormat::Jsonl => {
+                let object = JsonFile {
+                    repository: item.repository.as_deref(),
+                    path: file.path.display().to_string(),
+                    language: &file.language,
+                    content: &file.content,
+                    tokens: file.tokens,
+                    segment: file.segment.map(|(i, n)| [i, n]),
+                    kind: (file.kind != EntryKind::File).then(|| file.kind.as_str()),
+                };
+                let json = serde_json::to_string(&object)
+                    .expect("serializing a file entry to JSON cannot fail");
+                if self == OutputFormat::Jsonl {
+                    json + "\n"
+                } else if index == 0 {
+                    format!("\n  {}", json)
+                } else {
+                    format!(",\n  {}", json)
+                }
+            }
+        }
+    }
+
+    /// Everything written after the last file.
+    pub fn render_footer(self) -> &'static str {
+        match self {
+            OutputFormat::Markdown | OutputFormat::Jsonl => "",
+            OutputFormat::Xml => "</documents>\n",
+            OutputFormat::Json => "\n]\n",
+        }
+    }
+
+    /// The line a file adds to the header's file list (empty if the format has none).
+    pub(crate) fn list_line(self, item: &DocumentItem, options: &RenderOptions) -> String {
+        match self {
+            OutputFormat::Markdown if options.token_header => {
+                format!("- {}: {} tokens\n", item.label(), item.file.tokens)
+            }
+            OutputFormat::Markdown => format!("- {}\n", item.label()),
+            _ => String::new(),
+        }
+    }
+
+    /// File content as embedded in the output (escaped or adjusted as needed).
+    pub(crate) fn encode_content<'a>(self, language: &str, content: &'a str) -> Cow<'a, str> {
+        match self {
+            // Demote markdown headers to avoid conflicts with the document structure
+            OutputFormat::Markdown if language == "markdown" => Cow::Owned(
+                content
+                    .lines()
+                    .map(|line| {
+                        if line.trim_start().starts_with('#') {
+                            format!("##{}", line)
+                        } else {
+                            line.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            OutputFormat::Markdown => Cow::Borrowed(content),
+            OutputFormat::Xml => escape_xml(content),
+            OutputFormat::Json | OutputFormat::Jsonl => Cow::Owned(
+                serde_json::to_string(content).expect("serializing a string cannot fail"),
+            ),
+        }
+    }
+}
+
+/// Escape the characters that would break XML well-formedness.
+fn escape_xml(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "markdown" | "md" => Ok(OutputFormat::Markdown),
+            "xml" => Ok(OutputFormat::Xml),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            other => Err(format!(
+                "Unknown output format {:?} (expected markdown, xml, json or jsonl)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Markdown => "markdown",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_xml() {
+        assert_eq!(escape_xml("plain"), "plain");
+        assert_eq!(
+            escape_xml("a < b && \"c\" > d"),
+            "a &lt; b &amp;&amp; &quot;c&quot; &gt; d"
+        );
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!("md".parse(), Ok(OutputFormat::Markdown));
+        assert_eq!("XML".parse(), Ok(OutputFormat::Xml));
+        assert_eq!("jsonl".parse(), Ok(OutputFormat::Jsonl));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,878 @@
+// src/generator.rs
+use crate::auth::{Auth, Token, redact_url, url_host};
+use crate::cache::{Cache, format_size, parse_size};
+use crate::config::Config;
+use crate::document::Document;
+use crate::error::Error;
+use crate::format::{OutputFormat, RenderOptions};
+use crate::git_utils::{ChangeScope, CloneOptions, DiffRange, LocalChanges, LocalDiff};
+use crate::github::{PrContent, PullRequestOptions};
+use crate::io_utils::{OutputSink, WorkDir, ensure_output_directory};
+use crate::order::{FileOrder, sort_repository};
+use crate::processing::{self, FileFilter, process_single_repository};
+use crate::repository::Repository;
+use crate::sink::{DirectorySink, DocumentSink, WriterSink};
+use crate::template::Template;
+use crate::tokenizer::{Tokenizer, TokenizerKind};
+use futures::future::join_all;
+use serde::Deserialize;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Everything controlling what is read from the sources and how it is written.
+///
+/// Deserializes from the keys of `.git2promptconfig`, so a configuration file can be
+/// read straight into it; the per-run settings (`merge_files`, `pr`, `diff`, ...) are
+/// accepted as well. Build one in code with [`PromptOptions::builder`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PromptOptions {
+    /// Merge the contents of all repositories into a single output file.
+    pub merge_files: bool,
+    /// Ignore file applied below the repositories' own ignore files.
+    pub ignore_file: Option<PathBuf>,
+    /// Patterns to ignore, applied on top of the ignore files.
+    pub ignore_patterns: Vec<String>,
+    /// Folders written to separate output files.
+    pub split_folders: Vec<String>,
+    /// Process only this folder of each repository.
+    pub folder: Option<String>,
+    /// Branch, tag or commit checked out for sources that do not select one. Remote
+    /// sources only.
+    pub git_ref: Option<String>,
+    /// Clone the complete history instead of the processed commit only.
+    pub full_history: bool,
+    /// Process only the files changed in this pull request. Remote sources only; cannot
+    /// be combined with `diff` or `folder`.
+    pub pr: Option<u32>,
+    /// Process only the files changed between two commits.
+    pub diff: Option<DiffRange>,
+    /// Read a local repository's files from this commit instead of the working directory.
+    /// Cannot be combined with `diff` or `changes`.
+    pub rev: Option<String>,
+    /// Process only the uncommitted changes of a local repository. Cannot be combined
+    /// with `diff`.
+    pub changes: Option<ChangeScope>,
+    /// What `pr`, `diff` and `changes` include for each changed file. Defaults to
+    /// patch, or full for `changes`.
+    pub pr_content: Option<PrContent>,
+    /// Leave out the pull request's description and discussion.
+    pub no_pr_metadata: bool,
+    /// Base URL of the GitHub REST API, e.g. for GitHub Enterprise.
+    pub api_url: Option<String>,
+    /// Access token for private repositories and the GitHub API, used when the
+    /// GIT2PROMPT_TOKEN and GITHUB_TOKEN environment variables are unset.
+    pub token: Option<Token>,
+    /// Private key for SSH remotes, used when the SSH agent has no usable key.
+    pub ssh_key: Option<PathBuf>,
+    /// Tokenizer used for token counts.
+    pub tokenizer: TokenizerKind,
+    /// Do not add file path headers above code blocks (Markdown only).
+    pub no_headers: bool,
+    /// Write token counts into the header of each output file.
+    pub token_header: bool,
+    /// Split output files larger than this many tokens into parts.
+    pub max_tokens: Option<usize>,
+    /// Order of the files in each output file.
+    pub order: FileOrder,
+    /// Patterns (gitignore syntax) of files written first, in the order listed, ahead
+    /// of the files sorted by `order`.
+    pub priority: Vec<String>,
+    pub format: OutputFormat,
+    /// Template file defining the output layout; replaces `format`.
+    pub template: Option<PathBuf>,
+    /// Directory receiving the output files (default: "output").
+    pub output_dir: Option<PathBuf>,
+    /// Directory receiving clones when the cache is off (default: a new temporary directory).
+    pub work_dir: Option<PathBuf>,
+    /// Clone into the work directory instead of the clone cache.
+    pub no_cache: bool,
+    /// Directory keeping clones between runs (default: the platform cache directory).
+    pub cache_dir: Option<PathBuf>,
+    /// Size above which the least recently used cached clones are removed, e.g. "2GB".
+    pub cache_max_size: Option<String>,
+}
+
+impl PromptOptions {
+    pub fn builder() -> PromptOptionsBuilder {
+        PromptOptionsBuilder::default()
+    }
+
+    /// The format, template and header settings used for output files.
+    pub async fn render_options(&self) -> Result<RenderOptions, Error> {
+        let template = match &self.template {
+            Some(path) => Some(Arc::new(Template::load(path).await?)),
+            None => None,
+        };
+        Ok(RenderOptions {
+            format: self.format,
+            no_headers: self.no_headers,
+            token_header: self.token_header,
+            max_tokens: self.max_tokens,
+            template,
+        })
+    }
+
+    /// The clone cache, unless `no_cache` is set or there is no cache directory.
+    pub fn cache(&self) -> Result<Option<Cache>, Error> {
+        if self.no_cache {
+            return Ok(None);
+        }
+        let Some(dir) = self.cache_dir.clone().or_else(Cache::default_dir) else {
+            warn!("No cache directory found; cloning into a temporary directory.");
+            return Ok(None);
+        };
+        let max_size = match &self.cache_max_size {
+            Some(size) => Some(parse_size(size)?),
+            None => None,
+        };
+        Ok(Some(Cache::new(dir, max_size)))
+    }
+
+    /// Credentials from the environment, then `token` and `ssh_key`.
+    pub fn auth(&self) -> Auth {
+        let mut auth = Auth::from_env(self.token.clone(), self.ssh_key.clone());
+        // A GitHub Enterprise server also receives GITHUB_TOKEN
+        if let Some(api_url) = &self.api_url {
+            auth.github_hosts.push(url_host(api_url));
+        }
+        auth
+    }
+
+    fn pull_request(&self) -> Option<PullRequestOptions> {
+        self.pr.map(|number| PullRequestOptions {
+            number,
+            api_url: self.api_url.clone(),
+            content: self.pr_content.unwrap_or_default(),
+            metadata: !self.no_pr_metadata,
+        })
+    }
+
+    fn local_diff(&self) -> Option<LocalDiff> {
+        self.diff.clone().map(|range| LocalDiff {
+            range,
+            content: self.pr_content.unwrap_or_default(),
+        })
+    }
+
+    fn local_changes(&self) -> Option<LocalChanges> {
+        // Full files unless patches are asked for
+        self.changes.map(|scope| LocalChanges {
+            scope,
+            content: self.pr_content.unwrap_or(PrContent::Full),
+        })
+    }
+}
+
+impl From<Config> for PromptOptions {
+    fn from(config: Config) -> Self {
+        Self {
+            ignore_file: config.ignore_file.map(PathBuf::from),
+            ignore_patterns: config.ignore_patterns.unwrap_or_default(),
+            split_folders: config.split_folders.unwrap_or_default(),
+            pr_content: config.pr_content,
+            no_pr_metadata: config.no_pr_metadata.unwrap_or(false),
+            api_url: config.api_url,
+            token: config.token,
+            ssh_key: config.ssh_key.map(PathBuf::from),
+            tokenizer: config.tokenizer.unwrap_or_default(),
+            no_headers: config.no_headers.unwrap_or(false),
+            token_header: config.token_header.unwrap_or(false),
+            max_tokens: config.max_tokens,
+            order: config.order.unwrap_or_default(),
+            priority: config.priority.unwrap_or_default(),
+            format: config.format.unwrap_or_default(),
+            template: config.template.map(PathBuf::from),
+            output_dir: config.output_dir.map(PathBuf::from),
+            work_dir: config.work_dir.map(PathBuf::from),
+            no_cache: config.no_cache.unwrap_or(false),
+            cache_dir: config.cache_dir.map(PathBuf::from),
+            cache_max_size: config.cache_max_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// Builds [`PromptOptions`] one setting at a time; unset settings keep their defaults.
+#[derive(Debug, Clone, Default)]
+pub struct PromptOptionsBuilder {
+    options: PromptOptions,
+}
+
+impl PromptOptionsBuilder {
+    pub fn merge_files(mut self, merge_files: bool) -> Self {
+        self.options.merge_files = merge_files;
+        self
+    }
+
+    pub fn ignore_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.ignore_file = Some(path.into());
+        self
+    }
+
+    pub fn ignore_patterns<S: Into<String>>(
+        mut self,
+        patterns: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.options.ignore_patterns = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn split_folders<S: Into<String>>(mut self, folders: impl IntoIterator<Item = S>) -> Self {
+        self.options.split_folders = folders.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn folder(mut self, folder: impl Into<String>) -> Self {
+        self.options.folder = Some(folder.into());
+        self
+    }
+
+    pub fn git_ref(mut self, git_ref: impl Into<String>) -> Self {
+        self.options.git_ref = Some(git_ref.into());
+        self
+    }
+
+    pub fn full_history(mut self, full_history: bool) -> Self {
+        self.options.full_history = full_history;
+        self
+    }
+
+    pub fn pr(mut self, number: u32) -> Self {
+        self.options.pr = Some(number);
+        self
+    }
+
+    pub fn diff(mut self, range: DiffRange) -> Self {
+        self.options.diff = Some(range);
+        self
+    }
+
+    pub fn rev(mut self, rev: impl Into<String>) -> Self {
+        self.options.rev = Some(rev.into());
+        self
+    }
+
+    pub fn changes(mut self, scope: ChangeScope) -> Self {
+        self.options.changes = Some(scope);
+        self
+    }
+
+    pub fn pr_content(mut self, content: PrContent) -> Self {
+        self.options.pr_content = Some(content);
+        self
+    }
+
+    pub fn no_pr_metadata(mut self, no_pr_metadata: bool) -> Self {
+        self.options.no_pr_metadata = no_pr_metadata;
+        self
+    }
+
+    pub fn api_url(mut self, api_url: impl Into<String>) -> Self {
+        self.options.api_url = Some(api_url.into());
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.options.token = Some(Token::new(token, "options"));
+        self
+    }
+
+    pub fn ssh_key(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.ssh_key = Some(path.into());
+        self
+    }
+
+    pub fn tokenizer(mut self, tokenizer: TokenizerKind) -> Self {
+        self.options.tokenizer = tokenizer;
+        self
+    }
+
+    pub fn no_headers(mut self, no_headers: bool) -> Self {
+        self.options.no_headers = no_headers;
+        self
+    }
+
+    pub fn token_header(mut self, token_header: bool) -> Self {
+        self.options.token_header = token_header;
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.options.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn order(mut self, order: FileOrder) -> Self {
+        self.options.order = order;
+        self
+    }
+
+    pub fn priority<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.options.priority = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn template(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.template = Some(path.into());
+        self
+    }
+
+    pub fn output_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.output_dir = Some(path.into());
+        self
+    }
+
+    pub fn work_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.work_dir = Some(path.into());
+        self
+    }
+
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.options.no_cache = no_cache;
+        self
+    }
+
+    pub fn cache_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.cache_dir = Some(path.into());
+        self
+    }
+
+    pub fn cache_max_size(mut self, size: impl Into<String>) -> Self {
+        self.options.cache_max_size = Some(size.into());
+        self
+    }
+
+    pub fn build(self) -> PromptOptions {
+        self.options
+    }
+}
+
+/// Processes repositories with a set of [`PromptOptions`].
+///
+/// [`Generator::new`] resolves the options once (loading the template, building the
+/// tokenizer, reading credentials and locating the clone cache); the generator can then
+/// process any number of sources.
+pub struct Generator {
+    pub(crate) options: PromptOptions,
+    pub(crate) auth: Auth,
+    pub(crate) cache: Option<Cache>,
+    pub(crate) tokenizer: Arc<dyn Tokenizer>,
+    pub(crate) render_options: RenderOptions,
+    pub(crate) sink: OutputSink,
+    pub(crate) pr: Option<PullRequestOptions>,
+    pub(crate) diff: Option<LocalDiff>,
+    pub(crate) changes: Option<LocalChanges>,
+}
+
+impl Generator {
+    /// Output documents are written to files in `output_dir` unless
+    /// [`with_sink`](Self::with_sink) selects another sink.
+    pub async fn new(options: PromptOptions) -> Result<Self, Error> {
+        let output_dir = options
+            .output_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("output"));
+        Ok(Self {
+            auth: options.auth(),
+            cache: options.cache()?,
+            tokenizer: options.tokenizer.build(),
+            render_options: options.render_options().await?,
+            sink: OutputSink::Directory(output_dir),
+            pr: options.pull_request(),
+            diff: options.local_diff(),
+            changes: options.local_changes(),
+            options,
+        })
+    }
+
+    /// Replace the credentials read from the environment and options.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Count tokens with a tokenizer other than the built-in ones.
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    pub fn with_sink(mut self, sink: OutputSink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn options(&self) -> &PromptOptions {
+        &self.options
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub fn tokenizer(&self) -> &dyn Tokenizer {
+        self.tokenizer.as_ref()
+    }
+
+    pub fn sink(&self) -> &OutputSink {
+        &self.sink
+    }
+
+    fn ignore_patterns(&self) -> Option<&[String]> {
+        Some(self.options.ignore_patterns.as_slice()).filter(|patterns| !patterns.is_empty())
+    }
+
+    fn split_folders(&self) -> Option<&[String]> {
+        Some(self.options.split_folders.as_slice()).filter(|folders| !folders.is_empty())
+    }
+
+    /// Rejects options that cannot apply to remote sources, or to each other.
+    fn check_remote_options(&self) -> Result<(), Error> {
+        let options = &self.options;
+        if options.rev.is_some() || options.changes.is_some() {
+            return Err(Error::invalid(
+                "A revision or uncommitted changes can only be selected for a local directory",
+            ));
+        }
+        if self.pr.is_some() {
+            if self.diff.is_some() {
+                return Err(Error::invalid("PR mode and diff mode cannot be combined"));
+            }
+            if options.folder.is_some() {
+                return Err(Error::invalid("A folder cannot be selected in PR mode"));
+            }
+            // Pull requests are read through the API, without the history to order by
+            if options.order == FileOrder::Recent {
+                return Err(Error::invalid(
+                    "The recent file order is not supported in PR mode",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects options that cannot apply to a local directory, or to each other.
+    fn check_local_options(&self) -> Result<(), Error> {
+        let options = &self.options;
+        if self.pr.is_some() || options.git_ref.is_some() {
+            return Err(Error::invalid(
+                "A pull request or ref can only be selected for remote repositories",
+            ));
+        }
+        let modes = [
+            options.rev.is_some(),
+            self.diff.is_some(),
+            self.changes.is_some(),
+        ];
+        if modes.into_iter().filter(|&set| set).count() > 1 {
+            return Err(Error::invalid(
+                "Only one of a revision, a diff and uncommitted changes can be selected",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Processes a list of repositories like [`stream_sources`](Self::stream_sources),
+    /// writing the documents to the generator's sink. Returns the paths of the written files.
+    pub async fn process_sources(&self, urls: &[String]) -> Result<Vec<PathBuf>, Error> {
+        match &self.sink {
+            OutputSink::Directory(output_dir) => {
+                // Fail before cloning anything if the output cannot be written
+                ensure_output_directory(output_dir).await?;
+                let mut sink = DirectorySink::new(output_dir.clone());
+                self.stream_sources(urls, &mut sink).await
+            }
+            OutputSink::Stdout { separator } => {
+                let mut sink = WriterSink::new(tokio::io::stdout(), separator.clone());
+                self.stream_sources(urls, &mut sink).await
+            }
+        }
+    }
+
+    /// Processes a local directory like [`stream_local`](Self::stream_local), writing the
+    /// documents to the generator's sink. Returns the paths of the written files.
+    pub async fn process_local(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        match &self.sink {
+            OutputSink::Directory(output_dir) => {
+                let mut sink = DirectorySink::new(output_dir.clone());
+                self.stream_local(path, &mut sink).await
+            }
+            OutputSink::Stdout { separator } => {
+                let mut sink = WriterSink::new(tokio::io::stdout(), separator.clone());
+                self.stream_local(path, &mut sink).await
+            }
+        }
+    }
+
+    /// Processes a list of repositories and writes the documents to `sink` while they are
+    /// rendered, so that file contents are not held in memory, except in PR and diff mode
+    /// (see [`processing::stream_results`]). Returns the paths of the written files, if any.
+    pub async fn stream_sources<S: DocumentSink>(
+        &self,
+        urls: &[String],
+        sink: &mut S,
+    ) -> Result<Vec<PathBuf>, Error> {
+        self.with_repositories(urls, |repositories| async move {
+            let paths = processing::stream_results(
+                &repositories,
+                self.options.merge_files,
+                sink,
+                self.tokenizer.as_ref(),
+                &self.render_options,
+            )
+            .await?;
+            self.log_token_report(&repositories);
+            Ok(paths)
+        })
+        .await
+    }
+
+    /// Processes a list of repositories and returns the rendered documents without
+    /// writing them.
+    pub async fn generate_sources(&self, urls: &[String]) -> Result<Vec<Document>, Error> {
+        self.with_repositories(urls, |repositories| async move {
+            let documents = processing::render_results(
+                &repositories,
+                self.options.merge_files,
+                self.tokenizer.as_ref(),
+                &self.render_options,
+            )
+            .await?;
+            self.log_token_report(&repositories);
+            Ok(documents)
+        })
+        .await
+    }
+
+    /// Processes a list of repositories concurrently, downloads and processes content,
+    /// and hands the repositories to `consume` while their clones still exist.
+    /// Sources may be `owner/repo` GitHub shorthand, URLs, scp-style `user@host:path`
+    /// remotes or local repository paths, and may select a branch, tag or commit with
+    /// `owner/repo@ref`; `git_ref` applies to the others.
+    /// With a cache, clones are kept in its directory and updated on the next run;
+    /// otherwise they are made in `work_dir` (a new temporary directory by default) and
+    /// removed afterwards.
+    async fn with_repositories<T, F, Fut>(&self, urls: &[String], consume: F) -> Result<T, Error>
+    where
+        F: FnOnce(Vec<Repository>) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        debug!(
+            "Library received URLs: {:?}, no_headers: {}, merge_files: {}",
+            urls.iter().map(|url| redact_url(url)).collect::<Vec<_>>(),
+            self.render_options.no_headers,
+            self.options.merge_files
+        );
+
+        self.check_remote_options()?;
+
+        // Parse every source before touching the file system
+        let mut sources = Vec::new();
+        for url in urls {
+            let repository = Repository::new(Path::new(""), url, self.options.git_ref.as_deref())?;
+            if sources
+                .iter()
+                .any(|r: &Repository| r.name == repository.name)
+            {
+                return Err(Error::invalid(format!(
+                    "Repository {:?} is listed more than once",
+                    repository.name
+                )));
+            }
+            sources.push(repository);
+        }
+
+        // Prepare directories; cached clones stay locked until the run is done with them
+        let mut locks = Vec::new();
+        let work_dir = match &self.cache {
+            Some(cache) => {
+                fs::create_dir_all(&cache.dir).await.map_err(|e| {
+                    Error::io(
+                        format!("Failed to create cache directory {:?}", cache.dir),
+                        e,
+                    )
+                })?;
+                for repository in &mut sources {
+                    repository.path = cache.entry_path(repository);
+                }
+                // In a fixed order, so that runs locking the same clones cannot deadlock
+                let mut paths: Vec<PathBuf> = sources.iter().map(|r| r.path.clone()).collect();
+                paths.sort();
+                let cache = cache.clone();
+                locks = tokio::task::spawn_blocking(move || {
+                    paths
+                        .iter()
+                        .map(|path| cache.lock(path))
+                        .collect::<Result<Vec<_>, Error>>()
+                })
+                .await??;
+                None
+            }
+            None => {
+                let work_dir = match &self.options.work_dir {
+                    Some(path) => WorkDir::at(path).await?,
+                    None => WorkDir::temporary()?,
+                };
+                for repository in &mut sources {
+                    repository.path = work_dir.path().join(&repository.name);
+                    // Cloning replaces the destination, which must not be someone else's
+                    if repository.path.exists() {
+                        return Err(Error::invalid(format!(
+                            "{:?} already exists in the work directory",
+                            repository.path
+                        )));
+                    }
+                }
+                Some(work_dir)
+            }
+        };
+        let clones: Vec<PathBuf> = sources.iter().map(|r| r.path.clone()).collect();
+
+        let clone_options = CloneOptions {
+            // Dating files by their last change needs the history
+            full_history: self.options.full_history || self.options.order == FileOrder::Recent,
+            sparse_folder: None,
+            auth: self.auth.clone(),
+            reuse_existing: self.cache.is_some(),
+        };
+
+        let result = async {
+            // Spawn processing tasks
+            let tasks: Vec<_> = sources
+                .into_iter()
+                .map(|repository| {
+                    let filter = FileFilter {
+                        ignore_file: self.options.ignore_file.clone(),
+                        ignore_patterns: self.ignore_patterns().map(<[String]>::to_vec),
+                        split_folders: self.split_folders().map(<[String]>::to_vec),
+                        folder: self.options.folder.clone(),
+                    };
+                    let tokenizer = self.tokenizer.clone();
+                    let clone_options = clone_options.clone();
+                    let pr = self.pr.clone();
+                    let diff = self.diff.clone();
+                    tokio::spawn(async move {
+                        process_single_repository(
+                            repository,
+                            filter,
+                            pr,
+                            diff,
+                            clone_options,
+                            tokenizer,
+                        )
+                        .await
+                    })
+                })
+                .collect();
+
+            let results = join_all(tasks).await;
+
+            let mut repositories = Vec::new();
+            for result in results {
+                match result {
+                    Ok(Ok(mut repo)) => {
+                        sort_repository(&mut repo, self.options.order, &self.options.priority)
+                            .await;
+                        repositories.push(repo);
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            consume(repositories).await
+        }
+        .await;
+
+        // Remove the clones of this run, whether it succeeded or not
+        if let Some(work_dir) = work_dir {
+            work_dir.clean_up(&clones).await?;
+        }
+
+        if let Some(cache) = &self.cache
+            && result.is_ok()
+        {
+            // Mark the clones of this run as used, then evict the least recently used others
+            let paths: Vec<PathBuf> = clones
+                .into_iter()
+                .filter(|path| path.join(".git").is_dir())
+                .collect();
+            for path in &paths {
+                cache.touch(path)?;
+            }
+            for entry in cache.prune(&paths)? {
+                info!(
+                    "Removed cached clone {:?} ({})",
+                    entry.path,
+                    format_size(entry.size)
+                );
+            }
+        }
+        drop(locks);
+
+        result
+    }
+
+    /// Processes a single local directory and writes the documents to `sink` while they
+    /// are rendered. Files read from a revision, a diff or the uncommitted changes are held
+    /// in memory. Returns the paths of the written files, if any.
+    pub async fn stream_local<S: DocumentSink>(
+        &self,
+        path: &Path,
+        sink: &mut S,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let repositories = [self.local_repository(path).await?];
+        let paths = processing::stream_results(
+            &repositories,
+            false,
+            sink,
+            self.tokenizer.as_ref(),
+            &self.render_options,
+        )
+        .await?;
+        self.log_token_report(&repositories);
+        Ok(paths)
+    }
+
+    /// Processes a single local directory and returns the rendered documents without
+    /// writing them.
+    pub async fn generate_local(&self, path: &Path) -> Result<Vec<Document>, Error> {
+        let repositories = [self.local_repository(path).await?];
+        let documents = processing::render_results(
+            &repositories,
+            false,
+            self.tokenizer.as_ref(),
+            &self.render_options,
+        )
+        .await?;
+        self.log_token_report(&repositories);
+        Ok(documents)
+    }
+
+    /// Logs the token counts of the repositories once their output is done: the totals
+    /// at info level and the counts of each file at debug level.
+    fn log_token_report(&self, repositories: &[Repository]) {
+        debug!("{}", processing::token_details(repositories));
+        info!(
+            "{}",
+            processing::token_report(repositories, self.tokenizer.as_ref())
+        );
+    }
+
+    /// Collects the files of a local directory. With `rev`, `diff` or `changes`, the
+    /// directory must be a git repository, and the files of that commit, the changes
+    /// between two commits or its uncommitted changes are processed instead of the files
+    /// on disk.
+    async fn local_repository(&self, path: &Path) -> Result<Repository, Error> {
+        self.check_local_options()?;
+        if !path.is_dir() {
+            return Err(Error::invalid(format!(
+                "Local path {:?} is not a directory.",
+                path
+            )));
+        }
+
+        // A missing ignore file is skipped; only a custom one is worth a warning
+        let ignore_file = match &self.options.ignore_file {
+            Some(ignore_file) => match ignore_file.canonicalize() {
+                Ok(canonical) => Some(canonical),
+                Err(_) => {
+                    if ignore_file != Path::new(".git2promptignore") {
+                        warn!(
+                            "Ignore file {:?} not found. Proceeding without it.",
+                            ignore_file
+                        );
+                    }
+                    None
+                }
+            },
+            None => None,
+        };
+        let ignore_patterns = self.ignore_patterns();
+        let split_folders = self.split_folders();
+        let folder = self.options.folder.as_deref();
+        let tokenizer = self.tokenizer.as_ref();
+
+        // Create a repository object from the local path
+        let mut repository = Repository::from_local_path(path);
+        // Print full path for debugging
+        info!("Processing local repository at path: {:?}", repository.path);
+
+        // Process the files in the local directory, or in a commit, the changes between
+        // two commits or the uncommitted changes
+        let content = match (&self.options.rev, &self.diff, &self.changes) {
+            (Some(rev), _, _) => {
+                let (commit, content) = processing::process_tree_files(
+                    &repository.path,
+                    rev,
+                    ignore_file.as_deref(),
+                    ignore_patterns,
+                    split_folders,
+                    folder,
+                    tokenizer,
+                )
+                .await?;
+                // Keep outputs of different revisions apart, as for remote refs
+                repository.name = format!("{}@{}", repository.name, rev.replace(['/', '\\'], "-"));
+                repository.commit = Some(commit);
+                content
+            }
+            (None, Some(diff), _) => {
+                let (commit, content) = processing::process_diff_files(
+                    &repository.path,
+                    diff,
+                    ignore_file.as_deref(),
+                    ignore_patterns,
+                    split_folders,
+                    folder,
+                    tokenizer,
+                )
+                .await?;
+                repository.commit = Some(commit);
+                content
+            }
+            (None, None, Some(changes)) => {
+                let (commit, content) = processing::process_working_tree_changes(
+                    &repository.path,
+                    changes,
+                    ignore_file.as_deref(),
+                    ignore_patterns,
+                    split_folders,
+                    folder,
+                    tokenizer,
+                )
+                .await?;
+                repository.commit = commit;
+                content
+            }
+            (None, None, None) => {
+                processing::list_repository_files(
+                    &repository.path,
+                    ignore_file.as_deref(),
+                    ignore_patterns,
+                    split_folders,
+                    folder,
+                    tokenizer,
+                )
+                .await?
+            }
+        };
+        repository.content = Some(content);
+        sort_repository(&mut repository, self.options.order, &self.options.priority).await;
+
+        Ok(repository)
+    }
+}
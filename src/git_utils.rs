@@ -1,79 +1,883 @@
+use crate::auth::{Auth, redact_url};
+use crate::bucket::ChangedFile;
+use crate::error::Error;
+use crate::github::PrContent;
 use crate::repository::Repository;
 use git2::Repository as Git2Repository;
-use reqwest::Client;
-use serde::Deserialize;
-use std::path::Path;
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{Commit, Delta, FetchOptions, FileMode, ResetType, Status, StatusOptions, Tree};
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::fs;
+use tracing::{info, warn};
 
-#[derive(Deserialize)]
-struct GitHubPRFile {
-    filename: String,
-    patch: Option<String>, // not always present (binary files)
+/// How a repository is cloned.
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// Fetch the complete history instead of only the commits being processed (depth 1).
+    pub full_history: bool,
+    /// Only check out this folder (plus the ignore files of its parent directories).
+    pub sparse_folder: Option<String>,
+    /// Credentials for private repositories.
+    pub auth: Auth,
+    /// Update an existing clone at the repository path (fetch and check out the requested
+    /// ref) instead of deleting it and cloning again.
+    pub reuse_existing: bool,
 }
 
-pub async fn clone_repository(repository: &Repository) -> Result<Git2Repository, String> {
+impl CloneOptions {
+    fn fetch_options(&self, url: &str) -> FetchOptions<'static> {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(self.auth.remote_callbacks());
+        // libgit2 cannot make shallow fetches from local remotes, which are cheap anyway
+        if !self.full_history && !is_local_url(url) {
+            fetch_options.depth(1);
+        }
+        fetch_options
+    }
+
+    fn checkout_builder(&self) -> CheckoutBuilder<'static> {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        if let Some(folder) = &self.sparse_folder {
+            for path in sparse_paths(folder) {
+                checkout.path(path);
+            }
+        }
+        checkout
+    }
+}
+
+/// Clone a repository and check out `repository.git_ref`, if set.
+pub async fn clone_repository(
+    repository: &Repository,
+    options: &CloneOptions,
+) -> Result<Git2Repository, Error> {
     let repo_url = repository.url.clone();
     let path = repository.path.clone();
+    let git_ref = repository.git_ref.clone();
+    let options = options.clone();
+
+    if options.reuse_existing && path.exists() {
+        let (repo_url, path, git_ref, options) = (
+            repo_url.clone(),
+            path.clone(),
+            git_ref.clone(),
+            options.clone(),
+        );
+        let updated = tokio::task::spawn_blocking(move || {
+            update_clone(&path, &repo_url, git_ref.as_deref(), &options)
+        })
+        .await?;
+        match updated {
+            Ok(repo) => return Ok(repo),
+            Err(e) => warn!(
+                "Could not update the existing clone of {} ({}); cloning again",
+                redact_url(&repository.url),
+                e.full_message()
+            ),
+        }
+    }
 
     // Remove existing folder if it exists
     if repository.path.exists() {
         fs::remove_dir_all(&repository.path).await.map_err(|e| {
-            format!(
-                "Failed to remove existing directory {:?}: {}",
-                repository.path, e
+            Error::io(
+                format!("Failed to remove existing directory {:?}", repository.path),
+                e,
             )
         })?;
     }
 
     tokio::task::spawn_blocking(move || {
-        Git2Repository::clone(&repo_url, &path).map_err(|e| format!("Git clone error: {}", e))
+        let repo = RepoBuilder::new()
+            .fetch_options(options.fetch_options(&repo_url))
+            .with_checkout(options.checkout_builder())
+            .clone(&repo_url, &path)
+            .map_err(|e| Error::git(format!("Git clone error for {}", redact_url(&repo_url)), e))?;
+        if let Some(git_ref) = git_ref {
+            checkout_ref(&repo, &git_ref, &options)?;
+        }
+        Ok(repo)
+    })
+    .await?
+}
+
+/// Fetch into an existing clone and check out `git_ref`, or the latest commit of the
+/// remote's default branch.
+fn update_clone(
+    path: &Path,
+    url: &str,
+    git_ref: Option<&str>,
+    options: &CloneOptions,
+) -> Result<Git2Repository, Error> {
+    let repo = Git2Repository::open(path).map_err(|e| Error::git("not a git repository", e))?;
+    if options.full_history && repo.is_shallow() {
+        return Err(Error::git(
+            "the clone is shallow, but the full history is needed",
+            None,
+        ));
+    }
+    {
+        let mut remote = repo
+            .find_remote("origin")
+            .map_err(|e| Error::git("no remote origin", e))?;
+        if remote.url() != Some(url) {
+            return Err(Error::git("its remote origin has another URL", None));
+        }
+        // The requested ref explicitly, as the refspecs configured by the clone only
+        // update its branches
+        let refspecs = match git_ref {
+            Some(git_ref) if is_full_sha(git_ref) => vec![git_ref.to_string()],
+            Some(git_ref) => vec![
+                format!("+refs/heads/{0}:refs/remotes/origin/{0}", git_ref),
+                format!("+refs/tags/{0}:refs/tags/{0}", git_ref),
+            ],
+            None => Vec::new(),
+        };
+        remote
+            .fetch(&refspecs, Some(&mut options.fetch_options(url)), None)
+            .map_err(|e| Error::git("fetch failed", e))?;
+    }
+
+    match git_ref {
+        Some(git_ref) => {
+            checkout_ref(&repo, git_ref, options)?;
+        }
+        None => {
+            // HEAD is detached if the clone was last checked out at a ref
+            let commit = repo
+                .find_reference("refs/remotes/origin/HEAD")
+                .and_then(|reference| reference.peel_to_commit())
+                .map_err(|e| Error::git("Failed to resolve the default branch", e))?;
+            repo.reset(
+                commit.as_object(),
+                ResetType::Hard,
+                Some(&mut options.checkout_builder()),
+            )
+            .map_err(|e| Error::git("Failed to check out the default branch", e))?;
+        }
+    }
+    Ok(repo)
+}
+
+/// Check out a branch, tag or commit SHA as a detached HEAD.
+/// Tags and full SHAs that the clone did not include are fetched on demand;
+/// abbreviated SHAs only resolve against history that is already present.
+/// Returns the SHA of the checked out commit.
+pub fn checkout_ref(
+    repo: &Git2Repository,
+    reference: &str,
+    options: &CloneOptions,
+) -> Result<String, Error> {
+    // Branches only exist as remote-tracking refs after a clone
+    let resolve = || {
+        [
+            format!("refs/remotes/origin/{}", reference),
+            format!("refs/tags/{}", reference),
+            reference.to_string(),
+        ]
+        .iter()
+        .find_map(|candidate| repo.revparse_single(candidate).ok())
+    };
+
+    let object = match resolve() {
+        Some(object) => object,
+        None => {
+            let mut remote = repo
+                .find_remote("origin")
+                .map_err(|e| Error::git("Failed to find remote origin", e))?;
+            let url = remote.url().unwrap_or_default().to_string();
+            let refspec = if is_full_sha(reference) {
+                reference.to_string()
+            } else {
+                format!("+refs/tags/{0}:refs/tags/{0}", reference)
+            };
+            remote
+                .fetch(&[refspec], Some(&mut options.fetch_options(&url)), None)
+                .map_err(|e| Error::git(format!("Failed to fetch {:?}", reference), e))?;
+
+            resolve().ok_or_else(|| {
+                let hint = if repo.is_shallow() {
+                    " (abbreviated SHAs need --full-history)"
+                } else {
+                    ""
+                };
+                Error::git(
+                    format!(
+                        "Reference {:?} not found (expected a branch, tag or commit SHA){}",
+                        reference, hint
+                    ),
+                    None,
+                )
+            })?
+        }
+    };
+
+    let commit = object.peel_to_commit().map_err(|e| {
+        Error::git(
+            format!("Reference {:?} does not point to a commit", reference),
+            e,
+        )
+    })?;
+    repo.checkout_tree(commit.as_object(), Some(&mut options.checkout_builder()))
+        .map_err(|e| Error::git(format!("Failed to check out {:?}", reference), e))?;
+    repo.set_head_detached(commit.id())
+        .map_err(|e| Error::git(format!("Failed to check out {:?}", reference), e))?;
+    Ok(commit.id().to_string())
+}
+
+fn is_full_sha(reference: &str) -> bool {
+    reference.len() == 40 && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether a remote URL points at the local file system.
+fn is_local_url(url: &str) -> bool {
+    url.starts_with("file://") || Path::new(url).is_absolute() || !url.contains(':')
+}
+
+/// Paths checked out for a sparse checkout of `folder`: the folder itself and
+/// the ignore files of the directories above it, which still apply to it.
+fn sparse_paths(folder: &str) -> Vec<String> {
+    let folder = folder.trim_start_matches("./").trim_matches('/');
+    let mut paths = vec![folder.to_string()];
+    let mut parent = String::new();
+    for component in std::iter::once("").chain(folder.split('/').filter(|c| !c.is_empty())) {
+        if !component.is_empty() {
+            parent.push_str(component);
+            parent.push('/');
+        }
+        if parent.trim_end_matches('/') == folder {
+            break;
+        }
+        for name in [".gitignore", ".ignore", ".git2promptignore"] {
+            paths.push(format!("{}{}", parent, name));
+        }
+    }
+    paths
+}
+
+/// SHA of the commit checked out in a repository.
+pub fn head_commit(repo: &Git2Repository) -> Result<String, Error> {
+    repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .map(|commit| commit.id().to_string())
+        .map_err(|e| Error::git("Failed to resolve HEAD commit", e))
+}
+
+/// Most commits of history [`last_changed`] looks through.
+pub const LAST_CHANGED_COMMITS: usize = 10_000;
+
+/// When each of `paths` (relative to the git repository at `path`) was last changed as of
+/// `commit` (HEAD if `None`), in seconds since the epoch.
+///
+/// Files of the commit's tree date from the newest commit reachable from it that changed
+/// them. A merge only counts as changing a file that differs from all of its parents, so
+/// changes brought in by a merge date from the commit that made them. The search stops
+/// after [`LAST_CHANGED_COMMITS`] commits or at the end of the history present (in a
+/// shallow clone, the oldest fetched commit); files not found by then date from the
+/// oldest commit looked at. Other files, such as untracked ones, date from their
+/// modification time on disk, and are left out if they do not exist.
+pub async fn last_changed(
+    path: &Path,
+    commit: Option<&str>,
+    paths: Vec<PathBuf>,
+) -> Result<HashMap<PathBuf, i64>, Error> {
+    let path = path.to_path_buf();
+    let commit = commit.unwrap_or("HEAD").to_string();
+    tokio::task::spawn_blocking(move || {
+        let repo = Git2Repository::open(&path)
+            .map_err(|e| Error::git(format!("Failed to open git repository {:?}", path), e))?;
+        let start = repo
+            .revparse_single(&commit)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| Error::git(format!("Failed to resolve {:?}", commit), e))?;
+        let tree = start
+            .tree()
+            .map_err(|e| Error::git(format!("Failed to read commit {}", start.id()), e))?;
+
+        let mut changed = HashMap::new();
+        let mut wanted = HashSet::new();
+        for file in paths {
+            if tree.get_path(&file).is_ok() {
+                wanted.insert(file);
+            } else if let Some(time) = modification_time(&path.join(&file)) {
+                changed.insert(file, time);
+            }
+        }
+
+        let revwalk = repo
+            .revwalk()
+            .and_then(|mut revwalk| {
+                revwalk.push(start.id())?;
+                revwalk.set_sorting(git2::Sort::TIME)?;
+                Ok(revwalk)
+            })
+            .map_err(|e| Error::git(format!("Failed to walk the history of {:?}", commit), e))?;
+        let mut oldest = None;
+        for oid in revwalk.take(LAST_CHANGED_COMMITS) {
+            if wanted.is_empty() {
+                break;
+            }
+            let commit = oid
+                .and_then(|oid| repo.find_commit(oid))
+                .map_err(|e| Error::git(format!("Failed to read a commit of {:?}", commit), e))?;
+            let time = commit.time().seconds();
+            oldest = Some(time);
+            for file in changed_paths(&repo, &commit)? {
+                if wanted.remove(&file) {
+                    changed.insert(file, time);
+                }
+            }
+        }
+        if let Some(oldest) = oldest {
+            changed.extend(wanted.into_iter().map(|file| (file, oldest)));
+        }
+        Ok(changed)
+    })
+    .await?
+}
+
+/// The paths `commit` changed: those that differ from every one of its parents.
+fn changed_paths(repo: &Git2Repository, commit: &Commit) -> Result<HashSet<PathBuf>, Error> {
+    let tree = commit
+        .tree()
+        .map_err(|e| Error::git(format!("Failed to read commit {}", commit.id()), e))?;
+    // The first commit, or the last fetched one of a shallow clone, adds every file
+    let parent_trees: Vec<Option<Tree>> = match commit
+        .parents()
+        .map(|parent| parent.tree().ok().map(Some))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(trees) if !trees.is_empty() => trees,
+        _ => vec![None],
+    };
+
+    let mut changed: Option<HashSet<PathBuf>> = None;
+    for parent_tree in &parent_trees {
+        let diff = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .map_err(|e| Error::git(format!("Failed to diff commit {}", commit.id()), e))?;
+        let paths: HashSet<PathBuf> = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+            .collect();
+        changed = Some(match changed {
+            Some(changed) => changed.intersection(&paths).cloned().collect(),
+            None => paths,
+        });
+    }
+    Ok(changed.unwrap_or_default())
+}
+
+/// When the file at `path` was last modified, in seconds since the epoch.
+fn modification_time(path: &Path) -> Option<i64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    i64::try_from(since_epoch.as_secs()).ok()
+}
+
+/// Two commits to compare, written `base..head` or `base...head`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffRange {
+    pub base: String,
+    pub head: String,
+    /// `base...head`: compare `head` with the merge base of both, like `git diff base...head`.
+    pub merge_base: bool,
+}
+
+impl FromStr for DiffRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, head, merge_base) = match s.split_once("...") {
+            Some((base, head)) => (base, head, true),
+            None => match s.split_once("..") {
+                Some((base, head)) => (base, head, false),
+                None => {
+                    return Err(format!(
+                        "Invalid diff range {:?} (expected base..head or base...head)",
+                        s
+                    ));
+                }
+            },
+        };
+        if base.is_empty() {
+            return Err(format!("Diff range {:?} has no base", s));
+        }
+        Ok(DiffRange {
+            base: base.to_string(),
+            // `base..` compares with HEAD, as git does
+            head: if head.is_empty() { "HEAD" } else { head }.to_string(),
+            merge_base,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for DiffRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for DiffRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dots = if self.merge_base { "..." } else { ".." };
+        write!(f, "{}{}{}", self.base, dots, self.head)
+    }
+}
+
+/// Settings for processing the changes between two commits instead of a whole repository.
+#[derive(Debug, Clone)]
+pub struct LocalDiff {
+    pub range: DiffRange,
+    pub content: PrContent,
+}
+
+/// The files changed between two commits.
+#[derive(Debug, Clone)]
+pub struct ChangeSet {
+    /// SHA of the commit compared against (the merge base for `base...head`).
+    pub base: String,
+    /// SHA of the newer commit.
+    pub head: String,
+    pub files: Vec<ChangedFile>,
+}
+
+/// Diff two commits of the repository at `path`, optionally limited to `folder`.
+/// The git work runs on a blocking thread.
+pub async fn diff_repository(
+    path: &Path,
+    diff: &LocalDiff,
+    folder: Option<&str>,
+) -> Result<ChangeSet, Error> {
+    let path = path.to_path_buf();
+    let diff = diff.clone();
+    let folder = folder.map(str::to_string);
+    tokio::task::spawn_blocking(move || {
+        let repo = Git2Repository::open(&path)
+            .map_err(|e| Error::git(format!("Failed to open git repository {:?}", path), e))?;
+        diff_commits(&repo, &diff, folder.as_deref())
+    })
+    .await?
+}
+
+/// Diff two commits, with renames detected. Depending on `diff.content`, each file
+/// gets its content at the head commit, its patch, or both; removed files only have
+/// a patch. Submodules are skipped.
+pub fn diff_commits(
+    repo: &Git2Repository,
+    diff: &LocalDiff,
+    folder: Option<&str>,
+) -> Result<ChangeSet, Error> {
+    let range = &diff.range;
+    let head = resolve_commit(repo, &range.head)?;
+    let mut base = resolve_commit(repo, &range.base)?;
+    if range.merge_base {
+        let merge_base = repo
+            .merge_base(base.id(), head.id())
+            .map_err(|e| Error::git(format!("No merge base for {}", range), e))?;
+        base = repo
+            .find_commit(merge_base)
+            .map_err(|e| Error::git(format!("Failed to read merge base of {}", range), e))?;
+    }
+
+    let tree_error = |e: git2::Error| Error::git(format!("Failed to read tree for {}", range), e);
+    let mut options = git2::DiffOptions::new();
+    if let Some(folder) = folder {
+        options.pathspec(folder.trim_start_matches("./").trim_matches('/'));
+    }
+    let mut changes = repo
+        .diff_tree_to_tree(
+            Some(&base.tree().map_err(tree_error)?),
+            Some(&head.tree().map_err(tree_error)?),
+            Some(&mut options),
+        )
+        .map_err(|e| Error::git(format!("Failed to diff {}", range), e))?;
+    changes
+        .find_similar(None)
+        .map_err(|e| Error::git(format!("Failed to detect renames in {}", range), e))?;
+
+    let mut files = Vec::new();
+    for (index, delta) in changes.deltas().enumerate() {
+        let removed = delta.status() == Delta::Deleted;
+        let file = if removed {
+            delta.old_file()
+        } else {
+            delta.new_file()
+        };
+        if file.mode() == FileMode::Commit {
+            continue;
+        }
+        let Some(path) = file.path().map(PathBuf::from) else {
+            continue;
+        };
+
+        let mut content = None;
+        if diff.content.includes_full() && !removed {
+            let blob = repo.find_blob(file.id()).map_err(|e| {
+                Error::git(format!("Failed to read {:?} at {}", path, range.head), e)
+            })?;
+            content = String::from_utf8(blob.content().to_vec()).ok();
+            if content.is_none() {
+                info!("Skipping file {} (not UTF-8 text)", path.display());
+            }
+        }
+
+        let mut patch = None;
+        if diff.content.includes_patch() || removed {
+            patch = patch_text(&changes, index)?;
+            if patch.is_none() {
+                info!("Skipping patch of {} (binary)", path.display());
+            }
+        }
+
+        files.push(ChangedFile {
+            path,
+            content,
+            patch,
+        });
+    }
+
+    Ok(ChangeSet {
+        base: base.id().to_string(),
+        head: head.id().to_string(),
+        files,
+    })
+}
+
+/// Which uncommitted changes of a working tree to process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeScope {
+    /// Changes in the index, compared with HEAD.
+    Staged,
+    /// Changes in the working tree not yet staged, including untracked files.
+    Unstaged,
+    /// Everything that differs from HEAD, staged or not, including untracked files.
+    All,
+}
+
+impl FromStr for ChangeScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "staged" => Ok(ChangeScope::Staged),
+            "unstaged" => Ok(ChangeScope::Unstaged),
+            "all" => Ok(ChangeScope::All),
+            other => Err(format!(
+                "Unknown change scope {:?} (expected staged, unstaged or all)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChangeScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChangeScope::Staged => "staged",
+            ChangeScope::Unstaged => "unstaged",
+            ChangeScope::All => "all",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ChangeScope {
+    fn flags(self) -> Status {
+        let staged = Status::INDEX_NEW
+            | Status::INDEX_MODIFIED
+            | Status::INDEX_DELETED
+            | Status::INDEX_RENAMED
+            | Status::INDEX_TYPECHANGE;
+        let unstaged = Status::WT_NEW
+            | Status::WT_MODIFIED
+            | Status::WT_DELETED
+            | Status::WT_RENAMED
+            | Status::WT_TYPECHANGE
+            | Status::CONFLICTED;
+        match self {
+            ChangeScope::Staged => staged,
+            ChangeScope::Unstaged => unstaged,
+            ChangeScope::All => staged | unstaged,
+        }
+    }
+}
+
+/// Settings for processing the uncommitted changes of a working tree.
+#[derive(Debug, Clone)]
+pub struct LocalChanges {
+    pub scope: ChangeScope,
+    pub content: PrContent,
+}
+
+/// List the uncommitted changes of the repository at `path`, optionally limited to
+/// `folder`. Returns the SHA of HEAD (`None` before the first commit) and the files.
+/// The git work runs on a blocking thread.
+pub async fn working_tree_changes(
+    path: &Path,
+    changes: &LocalChanges,
+    folder: Option<&str>,
+) -> Result<(Option<String>, Vec<ChangedFile>), Error> {
+    let path = path.to_path_buf();
+    let changes = changes.clone();
+    let folder = folder.map(str::to_string);
+    tokio::task::spawn_blocking(move || {
+        let repo = Git2Repository::open(&path)
+            .map_err(|e| Error::git(format!("Failed to open git repository {:?}", path), e))?;
+        let files = status_changes(&repo, &changes, folder.as_deref())?;
+        Ok((head_commit(&repo).ok(), files))
     })
-    .await
-    .map_err(|e| format!("Blocking task join error: {}", e))?
-}
-
-pub async fn fetch_and_reconstruct_pr_files(
-    repo: &str,
-    pr_number: u32,
-    base_path: &Path,
-) -> Result<(), String> {
-    let api_url = format!(
-        "https://api.github.com/repos/{}/pulls/{}/files",
-        repo, pr_number
-    );
-
-    let client = Client::new();
-    let resp = client
-        .get(&api_url)
-        .header("User-Agent", "git2prompt")
-        .send()
-        .await
-        .map_err(|e| format!("Failed to call GitHub API: {}", e))?;
-
-    if !resp.status().is_success() {
-        return Err(format!("GitHub API error: {}", resp.status()));
-    }
-
-    let files: Vec<GitHubPRFile> = resp
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse GitHub API response: {}", e))?;
-
-    for file in files {
-        if let Some(patch) = file.patch {
-            let file_path = base_path.join(&file.filename);
-            if let Some(parent) = file_path.parent() {
-                fs::create_dir_all(parent)
-                    .await
-                    .map_err(|e| e.to_string())?;
+    .await?
+}
+
+/// Files with uncommitted changes in `changes.scope`, found with `git status`.
+/// Depending on `changes.content`, each file gets its staged content (for
+/// [`ChangeScope::Staged`]) or working tree content, its patch, or both; deleted
+/// files only have a patch.
+pub fn status_changes(
+    repo: &Git2Repository,
+    changes: &LocalChanges,
+    folder: Option<&str>,
+) -> Result<Vec<ChangedFile>, Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| Error::invalid("Cannot list changes of a bare repository"))?
+        .to_path_buf();
+    let folder = folder.map(|folder| folder.trim_start_matches("./").trim_matches('/'));
+    let untracked = changes.scope != ChangeScope::Staged;
+
+    let mut status_options = StatusOptions::new();
+    status_options
+        .include_untracked(untracked)
+        .recurse_untracked_dirs(untracked)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    if let Some(folder) = folder {
+        status_options.pathspec(folder);
+    }
+    let statuses = repo
+        .statuses(Some(&mut status_options))
+        .map_err(|e| Error::git("Failed to read git status", e))?;
+
+    let mut entries = Vec::new();
+    for entry in statuses.iter() {
+        let status = entry.status();
+        if !status.intersects(changes.scope.flags()) {
+            continue;
+        }
+        let Some(path) = entry.path().map(PathBuf::from) else {
+            continue;
+        };
+        let deleted = match changes.scope {
+            ChangeScope::Staged => status.contains(Status::INDEX_DELETED),
+            _ => !workdir.join(&path).exists(),
+        };
+        entries.push((path, deleted));
+    }
+
+    let patches = if changes.content.includes_patch() || entries.iter().any(|(_, deleted)| *deleted)
+    {
+        status_patches(repo, changes.scope, folder)?
+    } else {
+        HashMap::new()
+    };
+    let index = repo
+        .index()
+        .map_err(|e| Error::git("Failed to read git index", e))?;
+
+    let mut files = Vec::new();
+    for (path, deleted) in entries {
+        let mut content = None;
+        if changes.content.includes_full() && !deleted {
+            let bytes = match changes.scope {
+                ChangeScope::Staged => index
+                    .get_path(&path, 0)
+                    .and_then(|entry| repo.find_blob(entry.id).ok())
+                    .map(|blob| blob.content().to_vec()),
+                _ => std::fs::read(workdir.join(&path)).ok(),
+            };
+            content = bytes.and_then(|bytes| String::from_utf8(bytes).ok());
+            if content.is_none() {
+                info!("Skipping file {} (not UTF-8 text)", path.display());
+            }
+        }
+
+        let mut patch = None;
+        if changes.content.includes_patch() || deleted {
+            patch = patches.get(&path).cloned().flatten();
+            if patch.is_none() {
+                info!("Skipping patch of {} (binary)", path.display());
             }
-            fs::write(&file_path, patch)
-                .await
-                .map_err(|e| e.to_string())?;
+        }
+
+        files.push(ChangedFile {
+            path,
+            content,
+            patch,
+        });
+    }
+    Ok(files)
+}
+
+/// The patch of every file changed in `scope`, by path.
+fn status_patches(
+    repo: &Git2Repository,
+    scope: ChangeScope,
+    folder: Option<&str>,
+) -> Result<HashMap<PathBuf, Option<String>>, Error> {
+    let mut options = git2::DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    if let Some(folder) = folder {
+        options.pathspec(folder);
+    }
+
+    // Before the first commit, everything is compared with an empty tree
+    let head_tree = match repo.head() {
+        Ok(head) => Some(
+            head.peel_to_tree()
+                .map_err(|e| Error::git("Failed to read HEAD tree", e))?,
+        ),
+        Err(_) => None,
+    };
+    let diff = match scope {
+        ChangeScope::Staged => {
+            repo.diff_tree_to_index(head_tree.as_ref(), None, Some(&mut options))
+        }
+        ChangeScope::Unstaged => repo.diff_index_to_workdir(None, Some(&mut options)),
+        ChangeScope::All => {
+            repo.diff_tree_to_workdir_with_index(head_tree.as_ref(), Some(&mut options))
+        }
+    }
+    .map_err(|e| Error::git(format!("Failed to diff {} changes", scope), e))?;
+
+    let mut patches = HashMap::new();
+    for (index, delta) in diff.deltas().enumerate() {
+        let file = if delta.status() == Delta::Deleted {
+            delta.old_file()
         } else {
-            eprintln!("Skipping file {} (no patch, maybe binary)", file.filename);
+            delta.new_file()
+        };
+        if let Some(path) = file.path() {
+            patches.insert(path.to_path_buf(), patch_text(&diff, index)?);
         }
     }
+    Ok(patches)
+}
+
+/// The hunks of one file's patch, without the file header lines, like the `patch`
+/// field of the GitHub API. `None` for binary files.
+fn patch_text(diff: &git2::Diff, index: usize) -> Result<Option<String>, Error> {
+    let patch =
+        git2::Patch::from_diff(diff, index).map_err(|e| Error::git("Failed to build patch", e))?;
+    let Some(mut patch) = patch.filter(|patch| !patch.delta().flags().is_binary()) else {
+        return Ok(None);
+    };
 
-    Ok(())
+    let mut text = String::new();
+    patch
+        .print(&mut |_, _, line| {
+            match line.origin() {
+                // File header lines
+                'F' => return true,
+                origin @ ('+' | '-' | ' ') => text.push(origin),
+                _ => {}
+            }
+            text.push_str(&String::from_utf8_lossy(line.content()));
+            true
+        })
+        .map_err(|e| Error::git("Failed to print patch", e))?;
+    Ok(Some(text.trim_end_matches('\n').to_string()))
+}
+
+/// Resolve a branch (local first, then remote-tracking), tag or commit to a commit.
+pub fn resolve_commit<'repo>(
+    repo: &'repo Git2Repository,
+    reference: &str,
+) -> Result<Commit<'repo>, Error> {
+    [
+        reference.to_string(),
+        format!("refs/remotes/origin/{}", reference),
+        format!("refs/tags/{}", reference),
+    ]
+    .iter()
+    .find_map(|candidate| repo.revparse_single(candidate).ok())
+    .ok_or_else(|| {
+        Error::git(
+            format!(
+                "Reference {:?} not found (expected a branch, tag or commit SHA)",
+                reference
+            ),
+            None,
+        )
+    })?
+    .peel_to_commit()
+    .map_err(|e| {
+        Error::git(
+            format!("Reference {:?} does not point to a commit", reference),
+            e,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("/tmp/repo"));
+        assert!(is_local_url("relative/repo"));
+        assert!(is_local_url("file:///tmp/repo"));
+        assert!(!is_local_url("https://github.com/owner/repo.git"));
+        assert!(!is_local_url("git@github.com:owner/repo.git"));
+    }
+
+    #[test]
+    fn test_parse_diff_range() {
+        let range: DiffRange = "main..feature/x".parse().unwrap();
+        assert_eq!(
+            range,
+            DiffRange {
+                base: "main".to_string(),
+                head: "feature/x".to_string(),
+                merge_base: false,
+            }
+        );
+        let range: DiffRange = "v1.0...".parse().unwrap();
+        assert_eq!((range.head.as_str(), range.merge_base), ("HEAD", true));
+        assert_eq!(range.to_string(), "v1.0...HEAD");
+        assert!("main".parse::<DiffRange>().is_err());
+        assert!("..main".parse::<DiffRange>().is_err());
+    }
+
+    #[test]
+    fn test_sparse_paths() {
+        assert_eq!(
+            sparse_paths("src"),
+            vec!["src", ".gitignore", ".ignore", ".git2promptignore"]
+        );
+        assert_eq!(
+            sparse_paths("./crates/core/"),
+            vec![
+                "crates/core",
+                ".gitignore",
+                ".ignore",
+                ".git2promptignore",
+                "crates/.gitignore",
+                "crates/.ignore",
+                "crates/.git2promptignore",
+            ]
+        );
+    }
 }
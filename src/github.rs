@@ -0,0 +1,573 @@
+// src/github.rs
+use crate::auth::{Auth, Token};
+use crate::error::Error;
+use futures::stream::{self, StreamExt};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Base URL of the public GitHub REST API.
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+
+/// List endpoints return at most 100 items per page.
+const PER_PAGE: usize = 100;
+/// The files endpoint lists at most 3000 files per pull request.
+const MAX_FILES: usize = 3000;
+/// The commits endpoint lists at most 250 commits per pull request.
+const MAX_COMMITS: usize = 250;
+/// Reviews and comments fetched per pull request, for each kind.
+const MAX_COMMENTS: usize = 1000;
+
+/// How many file contents are downloaded at once.
+const CONCURRENT_DOWNLOADS: usize = 8;
+
+/// What to include for each file changed in a pull request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrContent {
+    /// Only the diff hunks of each file.
+    #[default]
+    Patch,
+    /// The complete file as of the pull request's head commit.
+    Full,
+    /// The complete file followed by its diff hunks.
+    Both,
+}
+
+impl PrContent {
+    pub fn includes_patch(self) -> bool {
+        matches!(self, PrContent::Patch | PrContent::Both)
+    }
+
+    pub fn includes_full(self) -> bool {
+        matches!(self, PrContent::Full | PrContent::Both)
+    }
+}
+
+impl FromStr for PrContent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "patch" => Ok(PrContent::Patch),
+            "full" => Ok(PrContent::Full),
+            "both" => Ok(PrContent::Both),
+            other => Err(format!(
+                "Unknown PR content {:?} (expected patch, full or both)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PrContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PrContent::Patch => "patch",
+            PrContent::Full => "full",
+            PrContent::Both => "both",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings for processing a pull request instead of a whole repository.
+#[derive(Debug, Clone, Default)]
+pub struct PullRequestOptions {
+    pub number: u32,
+    /// REST API root; [`DEFAULT_API_URL`] if unset.
+    pub api_url: Option<String>,
+    pub content: PrContent,
+    /// Add the title, description, commits and review discussion as a leading entry.
+    pub metadata: bool,
+}
+
+/// A file changed in a pull request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrFile {
+    pub filename: String,
+    /// "added", "modified", "removed", "renamed", ...
+    pub status: String,
+    /// Not present for binary files and very large diffs.
+    pub patch: Option<String>,
+}
+
+/// A pull request's description, head commit and changed files.
+#[derive(Debug, Clone)]
+pub struct PullRequest {
+    pub number: u32,
+    pub title: String,
+    pub body: Option<String>,
+    /// Login of the author; `None` for deleted accounts.
+    pub author: Option<String>,
+    pub base_ref: String,
+    pub head_ref: String,
+    pub head_sha: String,
+    pub url: Option<String>,
+    pub files: Vec<PrFile>,
+}
+
+/// Commits and review discussion of a pull request, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct Discussion {
+    pub commits: Vec<PrCommit>,
+    pub reviews: Vec<Review>,
+    pub review_comments: Vec<ReviewComment>,
+    pub comments: Vec<Comment>,
+}
+
+/// A commit of a pull request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrCommit {
+    pub sha: String,
+    pub commit: CommitDetails,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommitDetails {
+    pub message: String,
+    pub author: Option<GitActor>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitActor {
+    pub name: String,
+}
+
+/// A GitHub account; absent from responses for deleted accounts.
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub login: String,
+}
+
+/// A submitted review: its verdict and summary.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Review {
+    pub user: Option<User>,
+    /// "APPROVED", "CHANGES_REQUESTED", "COMMENTED", ...
+    pub state: String,
+    pub body: Option<String>,
+}
+
+/// A review comment on a line of the diff.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReviewComment {
+    pub user: Option<User>,
+    pub body: String,
+    pub path: String,
+    /// Not set when the comment is outdated.
+    pub line: Option<u32>,
+    pub diff_hunk: Option<String>,
+}
+
+/// A comment on the pull request's conversation.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Comment {
+    pub user: Option<User>,
+    pub body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PullRequestResponse {
+    title: String,
+    body: Option<String>,
+    user: Option<User>,
+    html_url: Option<String>,
+    base: BranchRef,
+    head: BranchRef,
+}
+
+#[derive(Deserialize)]
+struct BranchRef {
+    #[serde(rename = "ref")]
+    name: String,
+    sha: String,
+}
+
+/// A small client for the GitHub REST API.
+pub struct GitHubClient {
+    client: Client,
+    base_url: String,
+    /// Resolved once, since asking the credential helper may start a process.
+    token: Option<Token>,
+}
+
+impl GitHubClient {
+    /// `api_url` is the REST API root, e.g. [`DEFAULT_API_URL`] or
+    /// `https://github.example.com/api/v3` for GitHub Enterprise.
+    pub async fn new(api_url: Option<&str>, auth: Auth) -> Self {
+        let base_url = api_url
+            .unwrap_or(DEFAULT_API_URL)
+            .trim_end_matches('/')
+            .to_string();
+        Self {
+            client: Client::new(),
+            token: auth.api_token(&base_url).await,
+            base_url,
+        }
+    }
+
+    /// Fetch a pull request's description, head commit and all of its changed files.
+    pub async fn pull_request(&self, repo: &str, number: u32) -> Result<PullRequest, Error> {
+        let info: PullRequestResponse = self
+            .get_json(&format!("/repos/{}/pulls/{}", repo, number))
+            .await?;
+
+        let files: Vec<PrFile> = self
+            .get_pages(format!("/repos/{}/pulls/{}/files", repo, number), MAX_FILES)
+            .await?;
+        if files.len() >= MAX_FILES {
+            warn!(
+                "Pull request #{} lists {} files, the most the GitHub API returns; \
+                 later files are missing",
+                number,
+                files.len()
+            );
+        }
+
+        Ok(PullRequest {
+            number,
+            title: info.title,
+            body: info.body,
+            author: info.user.map(|user| user.login),
+            base_ref: info.base.name,
+            head_ref: info.head.name,
+            head_sha: info.head.sha,
+            url: info.html_url,
+            files,
+        })
+    }
+
+    /// Fetch a pull request's commits, reviews, review comments and conversation comments.
+    pub async fn discussion(&self, repo: &str, number: u32) -> Result<Discussion, Error> {
+        let pulls = format!("/repos/{}/pulls/{}", repo, number);
+        // The conversation lives on the issue behind the pull request
+        let issue = format!("/repos/{}/issues/{}", repo, number);
+        let (commits, reviews, review_comments, comments) = tokio::try_join!(
+            self.get_pages(format!("{}/commits", pulls), MAX_COMMITS),
+            self.get_pages(format!("{}/reviews", pulls), MAX_COMMENTS),
+            self.get_pages(format!("{}/comments", pulls), MAX_COMMENTS),
+            self.get_pages(format!("{}/comments", issue), MAX_COMMENTS),
+        )?;
+        Ok(Discussion {
+            commits,
+            reviews,
+            review_comments,
+            comments,
+        })
+    }
+
+    /// Download files as of commit `sha`, in order. Files that are not UTF-8 text are `None`.
+    pub async fn file_contents(
+        &self,
+        repo: &str,
+        sha: &str,
+        paths: &[&str],
+    ) -> Result<Vec<Option<String>>, Error> {
+        // Collected first: a stream holding the closure would make spawned tasks not `Send`
+        let downloads: Vec<_> = paths
+            .iter()
+            .map(|path| self.file_content(repo, sha, path))
+            .collect();
+        stream::iter(downloads)
+            .buffered(CONCURRENT_DOWNLOADS)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    async fn file_content(
+        &self,
+        repo: &str,
+        sha: &str,
+        path: &str,
+    ) -> Result<Option<String>, Error> {
+        let request = self
+            .get(&format!(
+                "/repos/{}/contents/{}?ref={}",
+                repo,
+                encode_path(path),
+                sha
+            ))
+            .header("Accept", "application/vnd.github.raw");
+        let bytes = self
+            .send(request, path)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| Error::http(format!("Failed to download {}", path), e))?;
+        Ok(String::from_utf8(bytes.to_vec()).ok())
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        let url = format!("{}{}", self.base_url, path);
+        let mut request = self
+            .client
+            .get(&url)
+            .header("User-Agent", "git2prompt")
+            .header("Accept", "application/vnd.github+json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token.expose());
+        }
+        request
+    }
+
+    async fn send(&self, request: RequestBuilder, what: &str) -> Result<Response, Error> {
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Error::http("Failed to call GitHub API", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            // GitHub answers 403 (or 429) once the rate limit is exhausted
+            let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+                || (status == StatusCode::FORBIDDEN
+                    && response
+                        .headers()
+                        .get("x-ratelimit-remaining")
+                        .is_some_and(|remaining| remaining == "0"));
+            return Err(Error::Api {
+                message: format!("GitHub API error for {}", what),
+                status,
+                rate_limited,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Fetch the pages of a list endpoint until a short page or `max_items` items.
+    async fn get_pages<T: DeserializeOwned>(
+        &self,
+        path: String,
+        max_items: usize,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = Vec::new();
+        for page in 1..=max_items.div_ceil(PER_PAGE) {
+            let batch: Vec<T> = self
+                .get_json(&format!("{}?per_page={}&page={}", path, PER_PAGE, page))
+                .await?;
+            let last_page = batch.len() < PER_PAGE;
+            items.extend(batch);
+            if last_page {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        self.send(self.get(path), path)
+            .await?
+            .json()
+            .await
+            .map_err(|e| {
+                Error::http(
+                    format!("Failed to parse GitHub API response for {}", path),
+                    e,
+                )
+            })
+    }
+}
+
+impl PullRequest {
+    /// A Markdown summary of the pull request: title, refs, description and,
+    /// if given, its commits and review discussion.
+    pub fn describe(&self, discussion: Option<&Discussion>) -> String {
+        let mut text = format!("# Pull Request #{}: {}\n\n", self.number, self.title);
+        text.push_str(&format!(
+            "- Author: {}\n",
+            user_name(self.author.as_deref())
+        ));
+        text.push_str(&format!("- Base: {}\n", self.base_ref));
+        text.push_str(&format!("- Head: {} ({})\n", self.head_ref, self.head_sha));
+        if let Some(url) = &self.url {
+            text.push_str(&format!("- URL: {}\n", url));
+        }
+
+        text.push_str("\n## Description\n\n");
+        match self.body.as_deref().map(str::trim) {
+            Some(body) if !body.is_empty() => text.push_str(body),
+            _ => text.push_str("_No description provided._"),
+        }
+        text.push('\n');
+
+        let Some(discussion) = discussion else {
+            return text;
+        };
+
+        if !discussion.commits.is_empty() {
+            text.push_str("\n## Commits\n\n");
+            for commit in &discussion.commits {
+                let mut lines = commit.commit.message.trim().lines();
+                let author = commit
+                    .commit
+                    .author
+                    .as_ref()
+                    .map(|author| format!(" ({})", author.name))
+                    .unwrap_or_default();
+                text.push_str(&format!(
+                    "- `{}` {}{}\n",
+                    &commit.sha[..commit.sha.len().min(7)],
+                    lines.next().unwrap_or_default(),
+                    author
+                ));
+                // The rest of the message, indented under its bullet
+                for line in lines {
+                    if line.trim().is_empty() {
+                        text.push('\n');
+                    } else {
+                        text.push_str(&format!("  {}\n", line));
+                    }
+                }
+            }
+        }
+
+        let reviews: Vec<&Review> = discussion
+            .reviews
+            .iter()
+            .filter(|review| review.state != "PENDING")
+            .collect();
+        if !reviews.is_empty() {
+            text.push_str("\n## Reviews\n");
+            for review in reviews {
+                text.push_str(&format!(
+                    "\n### {}: {}\n",
+                    user_name(review.user.as_ref().map(|u| u.login.as_str())),
+                    review.state
+                ));
+                if let Some(body) = review.body.as_deref().map(str::trim)
+                    && !body.is_empty()
+                {
+                    text.push_str(&format!("\n{}\n", body));
+                }
+            }
+        }
+
+        if !discussion.review_comments.is_empty() {
+            text.push_str("\n## Review Comments\n");
+            for comment in &discussion.review_comments {
+                let location = match comment.line {
+                    Some(line) => format!("{}:{}", comment.path, line),
+                    None => format!("{} (outdated)", comment.path),
+                };
+                text.push_str(&format!(
+                    "\n### {} ({})\n\n",
+                    location,
+                    user_name(comment.user.as_ref().map(|u| u.login.as_str()))
+                ));
+                if let Some(hunk) = &comment.diff_hunk {
+                    text.push_str(&format!("```diff\n{}\n```\n\n", hunk.trim_end()));
+                }
+                text.push_str(&format!("{}\n", comment.body.trim()));
+            }
+        }
+
+        if !discussion.comments.is_empty() {
+            text.push_str("\n## Comments\n");
+            for comment in &discussion.comments {
+                text.push_str(&format!(
+                    "\n### {}\n\n{}\n",
+                    user_name(comment.user.as_ref().map(|u| u.login.as_str())),
+                    comment.body.as_deref().unwrap_or_default().trim()
+                ));
+            }
+        }
+
+        text
+    }
+}
+
+/// `@login`, or GitHub's placeholder for deleted accounts.
+fn user_name(login: Option<&str>) -> String {
+    format!("@{}", login.unwrap_or("ghost"))
+}
+
+/// Percent-encode a repository path for use in a URL, keeping the `/` separators.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("src/main.rs"), "src/main.rs");
+        assert_eq!(encode_path("docs/my file#1.md"), "docs/my%20file%231.md");
+        assert_eq!(encode_path("naïve.txt"), "na%C3%AFve.txt");
+    }
+
+    #[test]
+    fn test_describe_pull_request() {
+        let pull_request = PullRequest {
+            number: 7,
+            title: "Add feature".to_string(),
+            body: Some("Does things.\r\n".to_string()),
+            author: Some("alice".to_string()),
+            base_ref: "main".to_string(),
+            head_ref: "feature".to_string(),
+            head_sha: "abc123".to_string(),
+            url: None,
+            files: Vec::new(),
+        };
+        let discussion = Discussion {
+            commits: vec![PrCommit {
+                sha: "0123456789abcdef".to_string(),
+                commit: CommitDetails {
+                    message: "Add feature\n\nWith details".to_string(),
+                    author: Some(GitActor {
+                        name: "Alice".to_string(),
+                    }),
+                },
+            }],
+            reviews: vec![Review {
+                user: None,
+                state: "APPROVED".to_string(),
+                body: Some(String::new()),
+            }],
+            review_comments: vec![ReviewComment {
+                user: Some(User {
+                    login: "bob".to_string(),
+                }),
+                body: "Typo".to_string(),
+                path: "src/lib.rs".to_string(),
+                line: Some(3),
+                diff_hunk: None,
+            }],
+            comments: Vec::new(),
+        };
+
+        assert_eq!(
+            pull_request.describe(Some(&discussion)),
+            "# Pull Request #7: Add feature\n\n\
+             - Author: @alice\n- Base: main\n- Head: feature (abc123)\n\n\
+             ## Description\n\nDoes things.\n\n\
+             ## Commits\n\n- `0123456` Add feature (Alice)\n\n  With details\n\n\
+             ## Reviews\n\n### @ghost: APPROVED\n\n\
+             ## Review Comments\n\n### src/lib.rs:3 (@bob)\n\nTypo\n"
+        );
+        assert!(pull_request.describe(None).ends_with("Does things.\n"));
+    }
+
+    #[test]
+    fn test_pr_content_from_str() {
+        assert_eq!("Full".parse(), Ok(PrContent::Full));
+        assert!(PrContent::Both.includes_patch() && PrContent::Both.includes_full());
+        assert!(!PrContent::Patch.includes_full());
+        assert!("diff".parse::<PrContent>().is_err());
+    }
+}
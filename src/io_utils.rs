@@ -1,33 +1,118 @@
+use crate::document::Document;
+use crate::error::Error;
+use crate::sink::{DirectorySink, WriterSink, write_documents};
 use std::path::{Path, PathBuf};
+use tempfile::TempDir;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// Ensure the output directory exists
+pub async fn ensure_output_directory(output_dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(output_dir).await.map_err(|e| {
+        Error::io(
+            format!("Failed to create output directory {:?}", output_dir),
+            e,
+        )
+    })
+}
 
 /// Ensure necessary directories exist
-pub async fn ensure_directories(download_dir: &Path, output_dir: &Path) -> Result<(), String> {
-    fs::create_dir_all(download_dir)
-        .await
-        .map_err(|e| format!("Failed to create download directory: {}", e))?;
-    fs::create_dir_all(output_dir)
+#[deprecated(note = "use `ensure_output_directory`")]
+pub async fn ensure_directories(download_dir: &Path, output_dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(download_dir).await.map_err(|e| {
+        Error::io(
+            format!("Failed to create download directory {:?}", download_dir),
+            e,
+        )
+    })?;
+    ensure_output_directory(output_dir).await
+}
+
+/// Write content to a file
+#[deprecated(note = "use `sink::DirectorySink` or `sink::write_documents`")]
+pub async fn write_content_to_file(path: &Path, content: &str) -> Result<(), Error> {
+    fs::write(path, content)
         .await
-        .map_err(|e| format!("Failed to create output directory: {}", e))?;
-    Ok(())
+        .map_err(|e| Error::io(format!("Failed to write output file {:?}", path), e))
+}
+
+/// Directory receiving the clones of one run. On cleanup only what the run created is
+/// removed: the clones, and the directory itself if it did not exist before.
+#[derive(Debug)]
+pub struct WorkDir {
+    path: PathBuf,
+    created: bool,
+    // Removes a temporary directory when dropped, even if the run fails
+    temp: Option<TempDir>,
+}
+
+impl WorkDir {
+    /// A new, uniquely named directory in the system's temporary directory.
+    pub fn temporary() -> Result<Self, Error> {
+        let temp = tempfile::Builder::new()
+            .prefix("git2prompt-")
+            .tempdir()
+            .map_err(|e| Error::io("Failed to create temporary work directory", e))?;
+        Ok(Self {
+            path: temp.path().to_path_buf(),
+            created: true,
+            temp: Some(temp),
+        })
+    }
+
+    /// A directory chosen by the user, created if missing.
+    pub async fn at(path: &Path) -> Result<Self, Error> {
+        let created = !path.exists();
+        fs::create_dir_all(path)
+            .await
+            .map_err(|e| Error::io(format!("Failed to create work directory {:?}", path), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            created,
+            temp: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the given clones, then the directory if this run created it.
+    pub async fn clean_up(self, clones: &[PathBuf]) -> Result<(), Error> {
+        for clone in clones {
+            if clone.exists() {
+                fs::remove_dir_all(clone)
+                    .await
+                    .map_err(|e| Error::io(format!("Failed to remove clone {:?}", clone), e))?;
+            }
+        }
+        // A temporary directory is removed when dropped. Otherwise only an empty directory
+        // is removed, keeping files someone else put there in the meantime.
+        if self.created
+            && self.temp.is_none()
+            && let Err(e) = fs::remove_dir(&self.path).await
+        {
+            warn!("Failed to remove work directory {:?}: {}", self.path, e);
+        }
+        Ok(())
+    }
 }
 
 /// Read ignore patterns from a file
-pub async fn read_ignore_patterns(ignore_file: Option<PathBuf>) -> Result<Vec<String>, String> {
+pub async fn read_ignore_patterns(ignore_file: Option<PathBuf>) -> Result<Vec<String>, Error> {
     if let Some(path) = ignore_file {
         if path.exists() {
             let content = fs::read_to_string(&path)
                 .await
-                .map_err(|e| format!("Failed to read ignore file {:?}: {}", path, e))?;
+                .map_err(|e| Error::io(format!("Failed to read ignore file {:?}", path), e))?;
             return Ok(content
                 .lines()
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty())
                 .collect());
         } else {
-            eprintln!(
-                "Warning: Ignore file {:?} not found. Proceeding without ignore patterns.",
+            warn!(
+                "Ignore file {:?} not found. Proceeding without ignore patterns.",
                 path
             );
         }
@@ -35,15 +120,38 @@ pub async fn read_ignore_patterns(ignore_file: Option<PathBuf>) -> Result<Vec<St
     Ok(Vec::new())
 }
 
-/// Write content to a file
-pub async fn write_content_to_file(path: &Path, content: &str) -> Result<(), String> {
-    let mut file = fs::File::create(path)
-        .await
-        .map_err(|e| format!("Failed to create output file {:?}: {}", path, e))?;
-    file.write_all(content.as_bytes())
-        .await
-        .map_err(|e| format!("Failed to write to output file {:?}: {}", path, e))?;
-    Ok(())
+/// Where rendered output documents are written.
+#[derive(Clone, Debug)]
+pub enum OutputSink {
+    /// One file per document in this directory.
+    Directory(PathBuf),
+    /// Standard output. Several documents are only allowed with a `separator`, a line
+    /// written before each document in which `{name}` is replaced by its file name.
+    Stdout { separator: Option<String> },
+}
+
+impl OutputSink {
+    /// The output directory, if documents are written to files.
+    pub fn directory(&self) -> Option<&Path> {
+        match self {
+            OutputSink::Directory(dir) => Some(dir),
+            OutputSink::Stdout { .. } => None,
+        }
+    }
+
+    /// Write rendered documents. Returns the paths of the written files, which are none
+    /// when writing to stdout. Nothing is written if stdout cannot take every document.
+    pub async fn write(&self, documents: &[Document]) -> Result<Vec<PathBuf>, Error> {
+        match self {
+            OutputSink::Directory(output_dir) => {
+                write_documents(&mut DirectorySink::new(output_dir.clone()), documents).await
+            }
+            OutputSink::Stdout { separator } => {
+                let mut sink = WriterSink::new(tokio::io::stdout(), separator.clone());
+                write_documents(&mut sink, documents).await
+            }
+        }
+    }
 }
 
 /// A helper function to map file extensions to a programming language alias.
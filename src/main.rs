@@ -1,13 +1,34 @@
 // src/main.rs
-use clap::Parser;
-use git2prompt::{config::Config, process_github_urls, process_local_path};
+use clap::{Parser, Subcommand};
+use git2prompt::{
+    Error, Generator, PromptOptions,
+    auth::redact_url,
+    cache::{Cache, format_size, parse_size},
+    config::Config,
+    format::OutputFormat,
+    git_utils::{ChangeScope, DiffRange},
+    github::{DEFAULT_API_URL, PrContent},
+    io_utils::OutputSink,
+    order::FileOrder,
+    tokenizer::TokenizerKind,
+};
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use tracing::{debug, error, info};
+use tracing_subscriber::EnvFilter;
 
 /// A command-line tool to process repository contents and format them for AI tools.
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// GitHub repository URLs (e.g., "owner/repo") or a single local path with --local.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Repositories to process: GitHub "owner/repo" shorthand, git URLs (https://, ssh://,
+    /// git://, file://), scp-style "git@host:path" or paths to local (bare) repositories.
+    /// Append "@ref" to select a branch, tag or commit (e.g. "owner/repo@v1.0").
+    /// With --local, a single directory whose files are read as they are on disk.
     #[clap(required = true)]
     sources: Vec<String>,
 
@@ -46,14 +67,204 @@ struct Args {
     /// Incompatible with --local.
     #[clap(long, value_name = "PULL REQUEST NUMBER", conflicts_with_all = ["folder", "local"])]
     pr: Option<u32>,
+
+    /// Process only the files changed between two commits, e.g. "main..feature" or
+    /// "v1.0...HEAD" (changes since the merge base). Works offline with --local and
+    /// with any remote, which is cloned with its full history.
+    #[clap(long, value_name = "BASE..HEAD", conflicts_with_all = ["pr", "git_ref"])]
+    diff: Option<DiffRange>,
+
+    /// With --local, read the files of a branch, tag or commit straight from the git
+    /// repository instead of the working directory, which is left untouched.
+    #[clap(long, value_name = "REV", requires = "local", conflicts_with_all = ["diff", "changes"])]
+    rev: Option<String>,
+
+    /// With --local, process only uncommitted changes of the git working tree:
+    /// staged, unstaged (including untracked files) or all. Files are included in full
+    /// unless --pr-content asks for patches against HEAD (or the index, for unstaged).
+    #[clap(
+        long,
+        value_name = "SCOPE",
+        requires = "local",
+        conflicts_with = "diff"
+    )]
+    changes: Option<ChangeScope>,
+
+    /// What --pr, --diff and --changes include for each changed file: patch (the diff hunks),
+    /// full (the complete file at the head commit or in the working tree) or both.
+    /// Defaults to patch, or full for --changes.
+    #[clap(long, value_name = "CONTENT")]
+    pr_content: Option<PrContent>,
+
+    /// Leave out the pull request's title, description, commits and review comments,
+    /// which --pr otherwise writes ahead of the changed files.
+    #[clap(long, action, requires = "pr")]
+    no_pr_metadata: bool,
+
+    /// Branch, tag or commit SHA to check out after cloning, for every repository
+    /// that does not select one with "owner/repo@ref".
+    #[clap(long = "ref", value_name = "REF", conflicts_with_all = ["pr", "local"])]
+    git_ref: Option<String>,
+
+    /// Clone the complete history. By default only the processed commit is fetched
+    /// (depth 1), which cannot resolve abbreviated commit SHAs.
+    #[clap(long, action, conflicts_with_all = ["pr", "local"])]
+    full_history: bool,
+
+    /// Base URL of the GitHub REST API used by --pr, e.g. https://github.example.com/api/v3
+    /// for GitHub Enterprise. Defaults to GIT2PROMPT_API_URL, GITHUB_API_URL, the config,
+    /// then https://api.github.com.
+    #[clap(long, value_name = "URL", conflicts_with = "local")]
+    api_url: Option<String>,
+
+    /// Private key used for SSH remotes when the SSH agent has no usable key
+    /// (default: ~/.ssh/id_ed25519, id_ecdsa or id_rsa). Tokens for HTTPS remotes and the
+    /// GitHub API are read from GIT2PROMPT_TOKEN or GITHUB_TOKEN.
+    #[clap(long, value_name = "PATH", conflicts_with = "local")]
+    ssh_key: Option<PathBuf>,
+
+    /// Tokenizer used to count tokens: cl100k, o200k, or chars (a cheap chars/4 estimate).
+    #[clap(long, value_name = "TOKENIZER")]
+    tokenizer: Option<TokenizerKind>,
+
+    /// Write per-file token counts into the header of each output file.
+    #[clap(long, action)]
+    token_header: bool,
+
+    /// Maximum number of tokens per output file. Larger outputs are split into
+    /// numbered parts at file boundaries (e.g. repo_part1_processed.md).
+    #[clap(long, value_name = "N")]
+    max_tokens: Option<usize>,
+
+    /// Order of the files in each output file: path (default), directory-first (files of
+    /// subdirectories before those of their parent), docs-first (READMEs, then
+    /// documentation), size (smallest first) or recent (most recently changed in git first;
+    /// remote repositories are then cloned with their full history; not supported with
+    /// --pr).
+    #[clap(long, value_name = "ORDER")]
+    order: Option<FileOrder>,
+
+    /// Files to write first, in the order given, ahead of the files sorted by --order.
+    /// Uses .gitignore syntax (e.g. "README.md", "src/lib.rs", "docs/"). Can be used
+    /// multiple times; replaces the configured priority list.
+    #[clap(long, value_name = "PATTERN")]
+    priority: Vec<String>,
+
+    /// Output format: markdown (default), xml, json, or jsonl.
+    #[clap(long, value_name = "FORMAT")]
+    format: Option<OutputFormat>,
+
+    /// Template file defining the output layout; replaces --format.
+    /// The output extension is taken from the template name (e.g. prompt.xml.tmpl -> xml).
+    #[clap(long, value_name = "PATH", conflicts_with = "format")]
+    template: Option<PathBuf>,
+
+    /// Directory receiving the output files. Defaults to the config, then "output" in the
+    /// current directory.
+    #[clap(long, value_name = "PATH", conflicts_with = "stdout")]
+    output_dir: Option<PathBuf>,
+
+    /// Write the output to stdout instead of files, e.g. to pipe it into another tool.
+    /// Progress messages always go to stderr. Fails if several output files would be
+    /// produced (split folders, --max-tokens parts, several repositories) unless
+    /// --separator is given.
+    #[clap(long, action)]
+    stdout: bool,
+
+    /// With --stdout, a line written before each output document, allowing several of
+    /// them. "{name}" is replaced by the file name the document would have had.
+    #[clap(
+        long,
+        value_name = "LINE",
+        requires = "stdout",
+        allow_hyphen_values = true
+    )]
+    separator: Option<String>,
+
+    /// Directory receiving the clones when the cache is off. Only the clones (and the
+    /// directory, if it did not exist) are removed afterwards. Defaults to the config,
+    /// then a new temporary directory.
+    #[clap(long, value_name = "PATH", conflicts_with = "local")]
+    work_dir: Option<PathBuf>,
+
+    /// Clone into the work directory and remove the clones afterwards, instead of keeping
+    /// them in the cache and updating them on the next run.
+    #[clap(long, action, conflicts_with = "local")]
+    no_cache: bool,
+
+    /// Directory keeping clones between runs. Defaults to the config, then the platform
+    /// cache directory (e.g. ~/.cache/git2prompt).
+    #[clap(long, value_name = "PATH", global = true)]
+    cache_dir: Option<PathBuf>,
+
+    /// Remove the least recently used cached clones once the cache grows beyond this size,
+    /// e.g. "500MB" or "2G".
+    #[clap(long, value_name = "SIZE", conflicts_with = "local")]
+    cache_max_size: Option<String>,
+
+    /// Print more details: the settings in use and skipped files (-v), or everything (-vv).
+    /// RUST_LOG overrides the verbosity, e.g. RUST_LOG=git2prompt=debug.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print errors.
+    #[clap(short, long, action, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the clone cache.
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// List the cached clones with their size, least recently used first.
+    List,
+    /// Remove every cached clone.
+    Clean,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     let args = Args::parse();
 
-    println!("Starting git2prompt...");
+    init_logging(args.verbose, args.quiet);
+    debug!("Starting git2prompt...");
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e.full_message());
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+/// Exit status for a failed run, so that scripts can tell failures apart.
+fn exit_code(error: &Error) -> u8 {
+    match error {
+        Error::Task(_) => 1,
+        Error::InvalidInput(_) => 2,
+        Error::Config { .. } => 3,
+        Error::Io { .. } => 4,
+        Error::Git { .. } => 5,
+        Error::Http { .. } => 6,
+        Error::Api {
+            rate_limited: false,
+            ..
+        } => 7,
+        Error::Api {
+            rate_limited: true, ..
+        } => 8,
+    }
+}
 
+async fn run(args: Args) -> Result<(), Error> {
     // --- Smart Config Lookup Logic ---
     // 1. Start with the path provided by CLI or the default ".git2promptconfig"
     let mut config_path = args.config.clone();
@@ -62,7 +273,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !config_path.exists() && args.local && args.sources.len() == 1 {
         let local_repo_config = PathBuf::from(&args.sources[0]).join(".git2promptconfig");
         if local_repo_config.exists() {
-            println!(
+            info!(
                 "Configuration file found in local repository: {:?}",
                 local_repo_config
             );
@@ -71,105 +282,249 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // 3. Load configuration
-    let config = Config::load_from_file(&config_path).await;
+    let config = Config::load_from_file(&config_path).await?;
+    let config_ignore_file = config.ignore_file.is_some();
+    let mut options = PromptOptions::from(config);
 
     // --- Merge Settings (CLI takes precedence) ---
 
+    // Clone cache: CLI arg OR Config OR the platform cache directory
+    options.cache_dir = args.cache_dir.clone().or(options.cache_dir);
+    options.cache_max_size = args.cache_max_size.clone().or(options.cache_max_size);
+
+    if let Some(Command::Cache { action }) = &args.command {
+        let cache_max_size = match &options.cache_max_size {
+            Some(size) => Some(parse_size(size)?),
+            None => None,
+        };
+        let cache = Cache::new(
+            options
+                .cache_dir
+                .clone()
+                .or_else(Cache::default_dir)
+                .ok_or_else(|| {
+                    Error::InvalidInput("No cache directory; set one with --cache-dir.".to_string())
+                })?,
+            cache_max_size,
+        );
+        return run_cache_command(&cache, action);
+    }
+    options.no_cache |= args.no_cache;
+
     // Headers: CLI arg OR Config file OR default(false)
-    let final_no_headers = if args.no_headers {
-        true
-    } else {
-        config.no_headers.unwrap_or(false)
-    };
+    options.no_headers |= args.no_headers;
 
     // Ignore file: CLI arg OR Config OR default
     // We check if the user provided a custom path or if we should fall back to config
-    let final_ignore_file = if args.ignore_file.to_string_lossy() == ".git2promptignore"
-        && config.ignore_file.is_some()
-    {
-        PathBuf::from(config.ignore_file.unwrap())
-    } else {
-        args.ignore_file
-    };
+    if args.ignore_file.to_string_lossy() != ".git2promptignore" || !config_ignore_file {
+        options.ignore_file = Some(args.ignore_file);
+    }
 
     // Split folders: Merge CLI and Config
-    let mut final_split_folders = config.split_folders.unwrap_or_default();
-    final_split_folders.extend(args.split_folder);
-    let final_split_folders_opt = if final_split_folders.is_empty() {
-        None
-    } else {
-        Some(final_split_folders)
-    };
+    options.split_folders.extend(args.split_folder);
+
+    // Directories: CLI arg OR Config OR default ("output", a temporary work directory)
+    options.output_dir = args.output_dir.or(options.output_dir);
+    options.work_dir = args.work_dir.or(options.work_dir);
+
+    // Tokenizer and output layout: CLI arg OR Config OR default
+    if let Some(tokenizer) = args.tokenizer {
+        options.tokenizer = tokenizer;
+    }
+    options.token_header |= args.token_header;
+    options.max_tokens = args.max_tokens.or(options.max_tokens);
+    if let Some(order) = args.order {
+        options.order = order;
+    }
+    if !args.priority.is_empty() {
+        options.priority = args.priority;
+    }
+    // A --format on the CLI overrides a configured template
+    if let Some(format) = args.format {
+        options.format = format;
+        options.template = None;
+    }
+    options.template = args.template.or(options.template);
+
+    // API URL: CLI arg OR environment OR Config OR default (api.github.com)
+    options.api_url = args
+        .api_url
+        .or_else(|| std::env::var("GIT2PROMPT_API_URL").ok())
+        .or_else(|| std::env::var("GITHUB_API_URL").ok())
+        .or(options.api_url)
+        .filter(|url| !url.trim().is_empty());
+
+    // What to process: the whole repository, a pull request, a diff, a commit or the
+    // uncommitted changes
+    options.merge_files = args.merge_files;
+    options.folder = args.folder;
+    options.git_ref = args.git_ref;
+    options.full_history = args.full_history;
+    options.pr = args.pr;
+    options.diff = args.diff;
+    options.rev = args.rev;
+    options.changes = args.changes;
+    options.pr_content = args.pr_content.or(options.pr_content);
+    options.no_pr_metadata |= args.no_pr_metadata;
+
+    // Credentials: environment first, then config (see auth::Auth)
+    options.ssh_key = args.ssh_key.or(options.ssh_key);
+
+    let mut generator = Generator::new(options).await?;
+    if args.stdout {
+        generator = generator.with_sink(OutputSink::Stdout {
+            separator: args.separator,
+        });
+    }
+    let options = generator.options();
 
     let result = if args.local {
         // --- LOCAL PATH MODE ---
         if args.sources.len() != 1 {
-            return Err(Box::from(
-                "Error: When using --local, exactly one directory path must be provided.",
+            return Err(Error::InvalidInput(
+                "When using --local, exactly one directory path must be provided.".to_string(),
             ));
         }
         let local_path = PathBuf::from(&args.sources[0]);
-        let ignore_canonical = match final_ignore_file.canonicalize() {
-            Ok(p) => Some(p),
-            Err(_) => {
-                // Warning only if it's a custom path that failed
-                if final_ignore_file.to_string_lossy() != ".git2promptignore" {
-                    eprintln!(
-                        "Warning: Ignore file {:?} not found. Proceeding without it.",
-                        final_ignore_file
-                    );
-                }
-                None
-            }
-        };
 
-        println!("Processing local repository at: {:?}", local_path);
-        println!("No file headers: {}", final_no_headers);
-        println!("Split folders: {:?}", final_split_folders_opt);
-        println!("----------------------------------------");
-
-        process_local_path(
-            local_path,
-            final_no_headers,
-            ignore_canonical,
-            final_split_folders_opt,
-            args.folder,
-        )
-        .await
+        debug!("Processing local repository at: {:?}", local_path);
+        debug!("No file headers: {}", options.no_headers);
+        debug!("Ignore patterns: {:?}", options.ignore_patterns);
+        debug!("Split folders: {:?}", options.split_folders);
+        debug!(
+            "Diff: {:?}",
+            options.diff.as_ref().map(|range| range.to_string())
+        );
+        debug!("Revision: {:?}", options.rev);
+        debug!(
+            "Changes: {:?}",
+            options.changes.map(|scope| scope.to_string())
+        );
+        debug!("Tokenizer: {}", generator.tokenizer().name());
+        debug!("Max tokens per file: {:?}", options.max_tokens);
+        debug!("File order: {}", options.order);
+        debug!("Priority patterns: {:?}", options.priority);
+        debug!("Output format: {}", options.format);
+        debug!("Output template: {:?}", options.template);
+        debug!("Output: {}", describe_sink(generator.sink()));
+
+        generator.process_local(&local_path).await
     } else {
-        // --- GITHUB URL MODE (default) ---
-        println!("Repositories to process: {:?}", args.sources);
-        println!("No file headers: {}", final_no_headers);
-        println!("Merge into a single output file: {}", args.merge_files);
-        println!("Ignore file path: {:?}", final_ignore_file);
-        println!("Split folders: {:?}", final_split_folders_opt);
-        println!("Folder to process: {:?}", args.folder);
-        println!("Pull request number: {:?}", args.pr);
-        println!("----------------------------------------");
-
-        process_github_urls(
-            args.sources,
-            final_no_headers,
-            args.merge_files,
-            Some(final_ignore_file), // Pass raw path, let logic handle existence
-            final_split_folders_opt,
-            args.folder,
-            args.pr,
-        )
-        .await
+        // --- REMOTE REPOSITORY MODE (default) ---
+        debug!(
+            "Repositories to process: {:?}",
+            args.sources
+                .iter()
+                .map(|source| redact_url(source))
+                .collect::<Vec<_>>()
+        );
+        debug!("No file headers: {}", options.no_headers);
+        debug!("Merge into a single output file: {}", options.merge_files);
+        debug!("Ignore file path: {:?}", options.ignore_file);
+        debug!("Ignore patterns: {:?}", options.ignore_patterns);
+        debug!("Split folders: {:?}", options.split_folders);
+        debug!("Folder to process: {:?}", options.folder);
+        debug!("Pull request number: {:?}", options.pr);
+        if options.pr.is_some() {
+            debug!(
+                "Pull request content: {}",
+                options.pr_content.unwrap_or_default()
+            );
+            debug!("Pull request metadata: {}", !options.no_pr_metadata);
+        }
+        debug!("Ref: {:?}", options.git_ref);
+        debug!(
+            "Diff: {:?}",
+            options.diff.as_ref().map(|range| range.to_string())
+        );
+        debug!("Full history: {}", options.full_history);
+        debug!(
+            "Clone cache: {:?}",
+            generator.cache().map(|cache| &cache.dir)
+        );
+        if generator.cache().is_none() {
+            debug!("Work directory: {:?}", options.work_dir);
+        }
+        debug!("Output: {}", describe_sink(generator.sink()));
+        debug!(
+            "GitHub API: {}",
+            options.api_url.as_deref().unwrap_or(DEFAULT_API_URL)
+        );
+        debug!("Credentials: {}", generator.auth().describe());
+        debug!("Tokenizer: {}", generator.tokenizer().name());
+        debug!("Max tokens per file: {:?}", options.max_tokens);
+        debug!("File order: {}", options.order);
+        debug!("Priority patterns: {:?}", options.priority);
+        debug!("Output format: {}", options.format);
+        debug!("Output template: {:?}", options.template);
+
+        generator.process_sources(&args.sources).await
     };
 
-    match result {
-        Ok(output_paths) => {
-            println!("Processing complete. Output files created:");
-            for path in output_paths {
-                println!(" - {}", path.display());
+    let output_paths = result?;
+    if args.stdout {
+        info!("Processing complete. Output written to stdout.");
+    } else {
+        info!("Processing complete. Output files created:");
+        for path in output_paths {
+            info!(" - {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Runs a `cache` subcommand.
+fn run_cache_command(cache: &Cache, action: &CacheAction) -> Result<(), Error> {
+    match action {
+        CacheAction::List => {
+            let entries = cache.entries()?;
+            println!("Clone cache: {:?}", cache.dir);
+            for entry in &entries {
+                println!(" - {} ({})", entry.path.display(), format_size(entry.size));
             }
-            Ok(())
+            let total = entries.iter().map(|entry| entry.size).sum();
+            println!("{} cached clone(s), {}", entries.len(), format_size(total));
         }
-        Err(e) => {
-            eprintln!("Error during processing: {}", e);
-            Err(e.into())
+        CacheAction::Clean => {
+            let entries = cache.clean()?;
+            let total = entries.iter().map(|entry| entry.size).sum();
+            println!(
+                "Removed {} cached clone(s) from {:?}, freeing {}",
+                entries.len(),
+                cache.dir,
+                format_size(total)
+            );
         }
     }
+    Ok(())
+}
+
+/// Describes where output documents go, for the settings summary.
+fn describe_sink(sink: &OutputSink) -> String {
+    match sink {
+        OutputSink::Directory(dir) => format!("{:?}", dir),
+        OutputSink::Stdout { separator: None } => "stdout".to_string(),
+        OutputSink::Stdout {
+            separator: Some(separator),
+        } => format!("stdout, separated by {:?}", separator),
+    }
+}
+
+/// Sends diagnostics to stderr, at info level unless -v or -q change it.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => "error",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("git2prompt={}", level)));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false)
+        .without_time()
+        .init();
 }
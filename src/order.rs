@@ -0,0 +1,316 @@
+// src/order.rs
+use crate::bucket::{EntryKind, FileEntry};
+use crate::git_utils::last_changed;
+use crate::processing::build_pattern_matcher;
+use crate::repository::Repository;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::warn;
+
+/// How the files of each output document are ordered.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileOrder {
+    /// By path, as in a sorted file tree.
+    #[default]
+    Path,
+    /// By path, with the files of subdirectories ahead of those of their parent directory.
+    DirectoryFirst,
+    /// READMEs first (shallowest first), then documentation (files in `docs` or `doc`
+    /// folders, Markdown, reStructuredText and AsciiDoc files), then the rest, each by path.
+    DocsFirst,
+    /// Smallest files first, by token count.
+    Size,
+    /// Most recently changed first: by the last commit that changed them as of the commit
+    /// processed, or for files outside it, such as untracked ones, by their modification
+    /// time (see [`last_changed`]). Remote repositories are then cloned with their full
+    /// history. Not supported in PR mode.
+    Recent,
+}
+
+impl FromStr for FileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "path" => Ok(FileOrder::Path),
+            "directory-first" | "dirs-first" => Ok(FileOrder::DirectoryFirst),
+            "docs-first" | "readme-first" => Ok(FileOrder::DocsFirst),
+            "size" => Ok(FileOrder::Size),
+            "recent" => Ok(FileOrder::Recent),
+            other => Err(format!(
+                "Unknown file order {:?} (expected path, directory-first, docs-first, size or recent)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileOrder::Path => "path",
+            FileOrder::DirectoryFirst => "directory-first",
+            FileOrder::DocsFirst => "docs-first",
+            FileOrder::Size => "size",
+            FileOrder::Recent => "recent",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Sorts the files of each of `repository`'s buckets. Files matching one of the
+/// `priority` patterns (gitignore syntax, e.g. "README.md", "src/lib.rs" or "docs/")
+/// come first, in the order of the patterns; `order` sorts the rest and breaks ties.
+/// Pull request metadata always stays ahead of the files.
+pub async fn sort_repository(repository: &mut Repository, order: FileOrder, priority: &[String]) {
+    let Some(buckets) = &mut repository.content else {
+        return;
+    };
+
+    let changed = if order == FileOrder::Recent {
+        let paths = buckets
+            .values()
+            .flat_map(|bucket| &bucket.files)
+            .map(|file| file.path.clone())
+            .collect();
+        // Dated as of the commit processed, which need not be HEAD (e.g. with --rev)
+        match last_changed(&repository.path, repository.commit.as_deref(), paths).await {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!(
+                    "Cannot order the files of {} by their last change, ordering them by path: {}",
+                    repository.name,
+                    e.full_message()
+                );
+                HashMap::new()
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+
+    let priority: Vec<_> = priority
+        .iter()
+        // Bucket paths are relative to the repository
+        .map(|pattern| build_pattern_matcher(Path::new(""), std::slice::from_ref(pattern)))
+        .collect();
+    let priority_rank = |path: &Path| {
+        priority
+            .iter()
+            .position(|matcher| matcher.matched_path_or_any_parents(path, false).is_ignore())
+            .unwrap_or(priority.len())
+    };
+
+    for bucket in buckets.values_mut() {
+        // A file may appear twice, e.g. in full and as a patch; both stay together
+        let mut sizes: HashMap<&Path, usize> = HashMap::new();
+        for file in &bucket.files {
+            *sizes.entry(file.path.as_path()).or_default() += file.tokens;
+        }
+        let keys: HashMap<PathBuf, SortKey> = bucket
+            .files
+            .iter()
+            .map(|file| {
+                let key = SortKey {
+                    metadata: file.kind == EntryKind::Metadata,
+                    priority: priority_rank(&file.path),
+                    rank: order_rank(order, &file.path, sizes[file.path.as_path()], &changed),
+                    components: path_components(order, &file.path),
+                };
+                (file.path.clone(), key)
+            })
+            .collect();
+        // Stable, so that entries of the same path keep their order
+        bucket
+            .files
+            .sort_by(|a: &FileEntry, b| keys[&a.path].cmp(&keys[&b.path]));
+    }
+}
+
+/// Where a file goes, compared field by field.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SortKey {
+    // `false` first: metadata leads
+    metadata: bool,
+    priority: usize,
+    rank: (u8, i64),
+    components: Vec<(bool, String)>,
+}
+
+/// The part of the sort key specific to `order`, compared before the path.
+fn order_rank(
+    order: FileOrder,
+    path: &Path,
+    size: usize,
+    changed: &HashMap<PathBuf, i64>,
+) -> (u8, i64) {
+    match order {
+        FileOrder::Path | FileOrder::DirectoryFirst => (0, 0),
+        FileOrder::DocsFirst => {
+            let is_readme = path
+                .file_stem()
+                .is_some_and(|stem| stem.to_string_lossy().eq_ignore_ascii_case("readme"));
+            let in_docs = path.parent().is_some_and(|parent| {
+                parent
+                    .components()
+                    .any(|c| matches!(c.as_os_str().to_str(), Some("docs" | "doc")))
+            });
+            let is_doc = path.extension().is_some_and(|ext| {
+                matches!(
+                    ext.to_string_lossy().to_lowercase().as_str(),
+                    "md" | "markdown" | "rst" | "adoc"
+                )
+            });
+            if is_readme {
+                (0, path.components().count() as i64)
+            } else if in_docs || is_doc {
+                (1, 0)
+            } else {
+                (2, 0)
+            }
+        }
+        FileOrder::Size => (0, size as i64),
+        // Newest first; a file without a date counts as newest
+        FileOrder::Recent => (0, changed.get(path).map_or(i64::MIN, |time| -time)),
+    }
+}
+
+/// The path, split for comparison. With [`FileOrder::DirectoryFirst`] the file name is
+/// marked to sort after the directories beside it.
+fn path_components(order: FileOrder, path: &Path) -> Vec<(bool, String)> {
+    let components: Vec<String> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let last = components.len().saturating_sub(1);
+    components
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| (order == FileOrder::DirectoryFirst && index == last, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(order: FileOrder, paths: &[&str]) -> Vec<String> {
+        let mut paths: Vec<&str> = paths.to_vec();
+        paths.sort_by_cached_key(|path| SortKey {
+            metadata: false,
+            priority: 0,
+            rank: order_rank(order, Path::new(path), 0, &HashMap::new()),
+            components: path_components(order, Path::new(path)),
+        });
+        paths.into_iter().map(str::to_string).collect()
+    }
+
+    const PATHS: [&str; 7] = [
+        "src/main.rs",
+        "README.md",
+        "docs/guide.txt",
+        "Cargo.toml",
+        "src/cli/README.md",
+        "src/cli/args.rs",
+        "CHANGELOG.md",
+    ];
+
+    #[test]
+    fn test_path_order() {
+        assert_eq!(
+            sorted(FileOrder::Path, &PATHS),
+            [
+                "CHANGELOG.md",
+                "Cargo.toml",
+                "README.md",
+                "docs/guide.txt",
+                "src/cli/README.md",
+                "src/cli/args.rs",
+                "src/main.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_first_order() {
+        assert_eq!(
+            sorted(FileOrder::DirectoryFirst, &PATHS),
+            [
+                "docs/guide.txt",
+                "src/cli/README.md",
+                "src/cli/args.rs",
+                "src/main.rs",
+                "CHANGELOG.md",
+                "Cargo.toml",
+                "README.md",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_docs_first_order() {
+        assert_eq!(
+            sorted(FileOrder::DocsFirst, &PATHS),
+            [
+                "README.md",
+                "src/cli/README.md",
+                "CHANGELOG.md",
+                "docs/guide.txt",
+                "Cargo.toml",
+                "src/cli/args.rs",
+                "src/main.rs",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_priority_with_relative_repository_path() {
+        use crate::bucket::Bucket;
+        use crate::tokenizer::CharEstimator;
+
+        // A local repository given as "src", holding a "src" folder of its own
+        let mut repository = Repository::from_local_path(Path::new("src"));
+        let mut bucket = Bucket::default();
+        for path in ["README.md", "src/lib.rs"] {
+            bucket.push(FileEntry::new(
+                PathBuf::from(path),
+                "",
+                String::new(),
+                &CharEstimator,
+            ));
+        }
+        repository.content = Some(HashMap::from([("default".to_string(), bucket)]));
+
+        sort_repository(
+            &mut repository,
+            FileOrder::Path,
+            &["/src/lib.rs".to_string()],
+        )
+        .await;
+        let paths: Vec<_> = repository.content.unwrap()["default"]
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        assert_eq!(paths, [Path::new("src/lib.rs"), Path::new("README.md")]);
+    }
+
+    #[test]
+    fn test_parse_file_order() {
+        for order in [
+            FileOrder::Path,
+            FileOrder::DirectoryFirst,
+            FileOrder::DocsFirst,
+            FileOrder::Size,
+            FileOrder::Recent,
+        ] {
+            assert_eq!(order.to_string().parse::<FileOrder>(), Ok(order));
+        }
+        assert_eq!("Dirs-First".parse(), Ok(FileOrder::DirectoryFirst));
+        assert!("random".parse::<FileOrder>().is_err());
+    }
+}
@@ -1,72 +1,181 @@
 // src/processing.rs
-use crate::git_utils::{clone_repository, fetch_and_reconstruct_pr_files};
-use crate::io_utils::{get_language_alias, write_content_to_file};
+use crate::auth::redact_url;
+use crate::bucket::{Bucket, ChangedFile, DocumentItem, FileEntry};
+use crate::chunking;
+use crate::document::Document;
+use crate::error::Error;
+use crate::format::{DocumentInfo, OutputFormat, RenderOptions};
+use crate::git_utils::{
+    CloneOptions, LocalChanges, LocalDiff, clone_repository, diff_repository, head_commit,
+    resolve_commit, working_tree_changes,
+};
+use crate::github::{GitHubClient, PrContent, PullRequest, PullRequestOptions};
+use crate::io_utils::{OutputSink, get_language_alias};
+use crate::remote::RemoteSpec;
 use crate::repository::Repository;
-use ignore::WalkBuilder;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use tokio::fs;
+use crate::sink::{
+    DirectorySink, DocumentSink, WriterSink, several_documents_error, write_document,
+    write_documents,
+};
+use crate::tokenizer::{CharEstimator, Tokenizer};
+use futures::{StreamExt, TryStreamExt, stream};
+use git2::{FileMode, ObjectType, Repository as Git2Repository, Tree};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{DirEntry, WalkBuilder, WalkState};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::RuntimeFlavor;
+use tracing::{debug, info, warn};
 
-/// Process a single repository: clone and process files
+/// Most threads used to walk and read a repository when no thread count is given.
+pub const MAX_WALK_THREADS: usize = 8;
+
+/// How many files are read ahead of the one being written to a document.
+pub const CONCURRENT_READS: usize = 32;
+
+/// Which files of a repository are processed, and the folders split into their own buckets.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    /// An additional ignore file, with the lowest precedence.
+    pub ignore_file: Option<PathBuf>,
+    /// Ignore patterns (e.g. from `.git2promptconfig`), with the highest precedence.
+    pub ignore_patterns: Option<Vec<String>>,
+    pub split_folders: Option<Vec<String>>,
+    /// Only process this folder.
+    pub folder: Option<String>,
+}
+
+/// Process a single repository: clone and process files.
+/// `clone_options.sparse_folder` is set from `filter.folder`.
 pub async fn process_single_repository(
     mut repository: Repository,
-    no_headers: bool,
-    merge_files: bool,
-    ignore_file_path: Option<PathBuf>,
-    split_folders: Option<Vec<String>>,
-    folder: Option<String>,
-    pr: Option<u32>,
-) -> Result<Repository, String> {
+    filter: FileFilter,
+    pr: Option<PullRequestOptions>,
+    diff: Option<LocalDiff>,
+    clone_options: CloneOptions,
+    tokenizer: Arc<dyn Tokenizer>,
+) -> Result<Repository, Error> {
     // Case 1: PR mode → don’t clone repo, reconstruct from API
-    if let Some(pr_number) = pr {
-        println!(
+    if let Some(pr) = pr {
+        if let Some(git_ref) = &repository.git_ref {
+            return Err(Error::invalid(format!(
+                "Cannot select ref {:?} for {} in PR mode",
+                git_ref, repository.name
+            )));
+        }
+        info!(
             "Processing repository {} in PR mode (PR #{})",
-            repository.url, pr_number
+            redact_url(&repository.url),
+            pr.number
         );
 
-        // Extract "owner/repo" from "https://github.com/owner/repo.git"
-        let repo_name = repository
-            .url
-            .trim_end_matches(".git")
-            .trim_start_matches("https://github.com/")
-            .to_string();
-
-        let pr_temp_path = repository.path.join(format!("pr-{}", pr_number));
+        // With a custom API (GitHub Enterprise) the repository may live on any host
+        let remote = RemoteSpec::parse(&repository.url)?;
+        let repo_name = match &pr.api_url {
+            Some(_) => remote.slug(),
+            None => remote.github_slug(),
+        }
+        .ok_or_else(|| {
+            Error::invalid(format!(
+                "PR mode is only supported for GitHub repositories, not {}",
+                redact_url(&repository.url)
+            ))
+        })?;
 
-        fetch_and_reconstruct_pr_files(&repo_name, pr_number, &pr_temp_path).await?;
+        let client = GitHubClient::new(pr.api_url.as_deref(), clone_options.auth).await;
+        let pull_request = client.pull_request(&repo_name, pr.number).await?;
+        repository.commit = Some(pull_request.head_sha.clone());
 
-        let content = process_repository_files(
-            &pr_temp_path,
-            no_headers,
-            merge_files,
-            ignore_file_path.as_deref(),
-            split_folders.as_deref(),
-            None, // folder restriction not applied in PR mode
+        let mut content = process_pull_request_files(
+            &client,
+            &repo_name,
+            &pull_request,
+            pr.content,
+            &filter,
+            tokenizer.as_ref(),
         )
         .await?;
 
+        // The description and discussion lead the default output
+        if pr.metadata {
+            let discussion = client.discussion(&repo_name, pr.number).await?;
+            let entry = FileEntry::metadata(
+                &format!("PR-{}.md", pr.number),
+                pull_request.describe(Some(&discussion)),
+                tokenizer.as_ref(),
+            );
+            if let Some(bucket) = content.get_mut("default") {
+                bucket.files.insert(0, entry);
+            }
+        }
+
         repository.content = Some(content);
         return Ok(repository);
     }
 
-    // Case 2: Normal mode → clone repo
-    println!(
+    // Case 2: Diff mode → clone the full history, diff two commits
+    if let Some(diff) = diff {
+        if let Some(git_ref) = &repository.git_ref {
+            return Err(Error::invalid(format!(
+                "Cannot select ref {:?} for {} in diff mode",
+                git_ref, repository.name
+            )));
+        }
+        info!(
+            "Processing repository {} in diff mode ({})",
+            redact_url(&repository.url),
+            diff.range
+        );
+        // Both commits (and their merge base) must be present
+        let clone_options = CloneOptions {
+            full_history: true,
+            sparse_folder: None,
+            ..clone_options
+        };
+        clone_repository(&repository, &clone_options).await?;
+
+        let (commit, content) = process_diff_files(
+            &repository.path,
+            &diff,
+            filter.ignore_file.as_deref(),
+            filter.ignore_patterns.as_deref(),
+            filter.split_folders.as_deref(),
+            filter.folder.as_deref(),
+            tokenizer.as_ref(),
+        )
+        .await?;
+        repository.commit = Some(commit);
+        repository.content = Some(content);
+        return Ok(repository);
+    }
+
+    // Case 3: Normal mode → clone repo
+    info!(
         "Preparing to clone {} to {:?}",
-        repository.url, repository.path
+        redact_url(&repository.url),
+        repository.path
     );
-    clone_repository(&repository).await?;
-    println!(
-        "Successfully cloned {} to {:?}",
-        repository.name, repository.path
+    // Shallow clone by default; with --folder only that folder is checked out
+    let clone_options = CloneOptions {
+        sparse_folder: filter.folder.clone(),
+        ..clone_options
+    };
+    let commit = head_commit(&clone_repository(&repository, &clone_options).await?)?;
+    info!(
+        "Successfully cloned {} to {:?} at commit {}",
+        repository.name, repository.path, commit
     );
+    repository.commit = Some(commit);
 
-    let content = process_repository_files(
+    let content = list_repository_files(
         &repository.path,
-        no_headers,
-        merge_files,
-        ignore_file_path.as_deref(),
-        split_folders.as_deref(),
-        folder.as_deref(),
+        filter.ignore_file.as_deref(),
+        filter.ignore_patterns.as_deref(),
+        filter.split_folders.as_deref(),
+        filter.folder.as_deref(),
+        tokenizer.as_ref(),
     )
     .await?;
     repository.content = Some(content);
@@ -76,7 +185,39 @@ pub async fn process_single_repository(
 
 /// Process all files in a repository using the `ignore` crate for advanced filtering.
 /// Returns a HashMap where keys are bucket names ("default" or split folder names)
-/// and values are the concatenated content strings.
+/// and values are the files of that bucket, each with its token count.
+///
+/// Files are excluded if any of the following sources ignores them:
+/// - `ignore_patterns` (e.g. from `.git2promptconfig`), matched relative to `repo_path`.
+///   These are applied last and cannot be re-included by the other sources.
+/// - `.git2promptignore` files found in the repository.
+/// - `.gitignore` files found in the repository.
+/// - The explicit `ignore_file_path` (lowest precedence, so a `!pattern` in a
+///   `.gitignore` or `.git2promptignore` can re-include what it excludes).
+pub async fn collect_repository_files(
+    repo_path: &Path,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+) -> Result<HashMap<String, Bucket>, Error> {
+    walk_repository_files(
+        repo_path,
+        ignore_file_path,
+        ignore_patterns,
+        split_folders,
+        folder,
+        tokenizer,
+        true,
+    )
+    .await
+}
+
+/// Process all files in a repository like [`collect_repository_files`] and render the file
+/// blocks of each bucket as Markdown. With `merge_files` they are introduced by the
+/// repository's folder name, as in merged output.
+#[deprecated(note = "use `collect_repository_files` and render the buckets with `OutputFormat`")]
 pub async fn process_repository_files(
     repo_path: &Path,
     no_headers: bool,
@@ -84,30 +225,161 @@ pub async fn process_repository_files(
     ignore_file_path: Option<&Path>,
     split_folders: Option<&[String]>,
     folder: Option<&str>,
-) -> Result<HashMap<String, String>, String> {
-    let mut content_buckets: HashMap<String, String> = HashMap::new();
+) -> Result<HashMap<String, String>, Error> {
+    // The token counts are not returned, so the cheapest tokenizer does
+    let buckets = collect_repository_files(
+        repo_path,
+        ignore_file_path,
+        None,
+        split_folders,
+        folder,
+        &CharEstimator,
+    )
+    .await?;
+    let name = Repository::from_local_path(repo_path).name;
+    let options = RenderOptions {
+        no_headers,
+        ..Default::default()
+    };
+    Ok(buckets
+        .into_iter()
+        .map(|(key, bucket)| {
+            let items: Vec<DocumentItem> = bucket
+                .files
+                .into_iter()
+                .map(|file| DocumentItem::new(merge_files.then_some(name.as_str()), file))
+                .collect();
+            let content = items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let previous = index.checked_sub(1).map(|previous| &items[previous]);
+                    OutputFormat::Markdown.render_item(item, index, previous, &options)
+                })
+                .collect();
+            (key, content)
+        })
+        .collect())
+}
+
+/// Like [`collect_repository_files`], but the content of each file is dropped once its
+/// tokens are counted and read again from disk when the output is written
+/// (see [`FileEntry::load`]), so memory use does not grow with the repository's size.
+pub async fn list_repository_files(
+    repo_path: &Path,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+) -> Result<HashMap<String, Bucket>, Error> {
+    walk_repository_files(
+        repo_path,
+        ignore_file_path,
+        ignore_patterns,
+        split_folders,
+        folder,
+        tokenizer,
+        false,
+    )
+    .await
+}
+
+async fn walk_repository_files(
+    repo_path: &Path,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+    keep_content: bool,
+) -> Result<HashMap<String, Bucket>, Error> {
+    let mut content_buckets: HashMap<String, Bucket> = HashMap::new();
 
     // Initialize default bucket
-    content_buckets.insert("default".to_string(), String::new());
+    content_buckets.insert("default".to_string(), Bucket::default());
 
     // If split folders are provided, initialize their buckets
     if let Some(folders) = split_folders {
         for f in folders {
-            content_buckets.insert(f.to_string(), String::new());
+            content_buckets.insert(f.to_string(), Bucket::default());
         }
     }
 
+    let files = run_blocking(|| {
+        read_repository_files(
+            repo_path,
+            ignore_file_path,
+            ignore_patterns,
+            folder,
+            tokenizer,
+            keep_content,
+            0,
+        )
+    })?;
+
+    for entry in files {
+        // Determine which bucket this file belongs to
+        let bucket_key = determine_bucket(&entry.path, split_folders);
+
+        // Append to the correct bucket
+        if let Some(bucket) = content_buckets.get_mut(&bucket_key) {
+            bucket.push(entry);
+        } else {
+            // Fallback to default if bucket somehow missing
+            if let Some(default_bucket) = content_buckets.get_mut("default") {
+                default_bucket.push(entry);
+            }
+        }
+    }
+
+    Ok(content_buckets)
+}
+
+/// Walk the repository at `repo_path` (or its `folder`) and read and tokenize the files
+/// the ignore rules keep, sorted by path. Files that cannot be read as text are skipped.
+/// Unless `keep_content` is set, each file's content is dropped as soon as its tokens are
+/// counted (see [`FileEntry::unloaded`]), so only one file per thread is held at a time.
+///
+/// With `threads` other than 1 the files are walked and read on several threads at once;
+/// 0 uses one per core, up to [`MAX_WALK_THREADS`]. The result is the same either way.
+/// This blocks the calling thread until every file is read.
+pub fn read_repository_files(
+    repo_path: &Path,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+    keep_content: bool,
+    threads: usize,
+) -> Result<Vec<FileEntry>, Error> {
+    let threads = match threads {
+        0 => std::thread::available_parallelism()
+            .map_or(1, usize::from)
+            .min(MAX_WALK_THREADS),
+        threads => threads,
+    };
     let base_path = if let Some(folder) = folder {
+        // Joining an absolute path or one with `..` would walk outside the repository
+        if !Path::new(folder)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::invalid(format!(
+                "Folder {:?} must be a relative path inside the repository",
+                folder
+            )));
+        }
         repo_path.join(folder)
     } else {
         repo_path.to_path_buf()
     };
 
     if !base_path.exists() {
-        return Err(format!(
+        return Err(Error::invalid(format!(
             "Specified folder {:?} not found in repo",
             base_path
-        ));
+        )));
     }
 
     // Use ignore::WalkBuilder for standard gitignore compliance
@@ -116,114 +388,529 @@ pub async fn process_repository_files(
     // Configure standard filters
     builder.hidden(false); // Do not ignore hidden files by default (except .git)
     builder.git_ignore(true);
+    builder.threads(threads);
 
     // Add custom ignore file if provided
     if let Some(ignore_path) = ignore_file_path
         && let Some(err) = builder.add_ignore(ignore_path)
     {
-        eprintln!(
-            "Warning: Error adding ignore file {:?}: {}",
-            ignore_path, err
-        );
+        warn!("Error adding ignore file {:?}: {}", ignore_path, err);
     }
 
     // Also look for .git2promptignore in the root by default if no custom file is passed
     // or as a standard practice for this tool
     builder.add_custom_ignore_filename(".git2promptignore");
 
-    let walker = builder.build();
-
-    for result in walker {
-        match result {
-            Ok(entry) => {
-                let path = entry.path();
+    // Layer the configured ignore patterns on top of everything else
+    if let Some(patterns) = ignore_patterns
+        && !patterns.is_empty()
+    {
+        // Patterns are matched on paths relative to the repository root
+        let matcher = build_pattern_matcher(Path::new(""), patterns);
+        let root = repo_path.to_path_buf();
+        builder.filter_entry(move |entry| {
+            let Ok(relative_path) = entry.path().strip_prefix(&root) else {
+                return true;
+            };
+            let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+            !matcher
+                .matched_path_or_any_parents(relative_path, is_dir)
+                .is_ignore()
+        });
+    }
 
-                // Skip directories and .git internal files (WalkBuilder handles .gitignore,
-                // but we still check .git dir structure just in case)
-                if path.is_dir() || path.components().any(|c| c.as_os_str() == ".git") {
-                    continue;
+    let mut files = if threads == 1 {
+        builder
+            .build()
+            .filter_map(|result| read_walk_entry(repo_path, result, tokenizer, keep_content))
+            .collect()
+    } else {
+        let files = Mutex::new(Vec::new());
+        builder.build_parallel().run(|| {
+            Box::new(|result| {
+                if let Some(entry) = read_walk_entry(repo_path, result, tokenizer, keep_content) {
+                    files.lock().unwrap().push(entry);
                 }
+                WalkState::Continue
+            })
+        });
+        files.into_inner().unwrap()
+    };
+    // Walk order depends on the file system, and on timing when walking in parallel
+    files.sort_by(|a: &FileEntry, b| a.path.cmp(&b.path));
+    Ok(files)
+}
 
-                // Additional binary check using extension (WalkBuilder doesn't check binary content)
-                if is_binary_extension(path) {
-                    continue;
+/// Read one file found by the walker, unless it is skipped.
+fn read_walk_entry(
+    repo_path: &Path,
+    result: Result<DirEntry, ignore::Error>,
+    tokenizer: &dyn Tokenizer,
+    keep_content: bool,
+) -> Option<FileEntry> {
+    let entry = match result {
+        Ok(entry) => entry,
+        Err(err) => {
+            warn!("Error walking directory: {}", err);
+            return None;
+        }
+    };
+    let path = entry.path();
+
+    // Skip directories and .git internal files (WalkBuilder handles .gitignore,
+    // but we still check .git dir structure just in case)
+    if path.is_dir() || path.components().any(|c| c.as_os_str() == ".git") {
+        return None;
+    }
+
+    // Additional binary check using extension (WalkBuilder doesn't check binary content)
+    if is_binary_extension(path) {
+        return None;
+    }
+
+    let relative_path = match path.strip_prefix(repo_path) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Failed to strip prefix for {:?}: {}", path, e);
+            return None;
+        }
+    };
+
+    match fs::read_to_string(path) {
+        Ok(raw_content) => {
+            let alias = get_language_alias(path);
+            let entry = FileEntry::new(relative_path.to_path_buf(), alias, raw_content, tokenizer);
+            // Only the token count is kept; the content is read again when it is written
+            Some(match keep_content {
+                true => entry,
+                false => entry.unloaded(path.to_path_buf()),
+            })
+        }
+        Err(_) => {
+            // Read errors are likely binary files or permissions; only logged verbosely
+            debug!("Could not read file {:?}", path);
+            None
+        }
+    }
+}
+
+/// Run blocking work that borrows from the caller. On a multi-threaded runtime the
+/// worker thread's other tasks are handed to another thread meanwhile.
+fn run_blocking<T>(work: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(work)
+        }
+        _ => work(),
+    }
+}
+
+/// Process the files of a commit's tree in the git repository at `repo_path`, reading
+/// the blobs directly, so the working directory is neither read nor changed.
+/// `rev` is a branch, tag or commit. Returns the commit SHA and the buckets.
+///
+/// The same ignore rules as in [`collect_repository_files`] apply, with the
+/// `.gitignore`, `.ignore` and `.git2promptignore` files read from the tree itself.
+pub async fn process_tree_files(
+    repo_path: &Path,
+    rev: &str,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+) -> Result<(String, HashMap<String, Bucket>), Error> {
+    let (commit, files) = {
+        let repo_path = repo_path.to_path_buf();
+        let rev = rev.to_string();
+        let ignore_file_path = ignore_file_path.map(Path::to_path_buf);
+        let ignore_patterns = ignore_patterns.unwrap_or_default().to_vec();
+        let folder = folder.map(str::to_string);
+        tokio::task::spawn_blocking(move || {
+            read_tree_files(
+                &repo_path,
+                &rev,
+                ignore_file_path.as_deref(),
+                &ignore_patterns,
+                folder.as_deref(),
+            )
+        })
+        .await??
+    };
+
+    let mut content_buckets: HashMap<String, Bucket> = HashMap::new();
+    content_buckets.insert("default".to_string(), Bucket::default());
+    for f in split_folders.unwrap_or_default() {
+        content_buckets.insert(f.to_string(), Bucket::default());
+    }
+    for (path, content) in files {
+        let bucket_key = determine_bucket(&path, split_folders);
+        if let Some(bucket) = content_buckets.get_mut(&bucket_key) {
+            let alias = get_language_alias(&path);
+            bucket.push(FileEntry::new(path, alias, content, tokenizer));
+        }
+    }
+    Ok((commit, content_buckets))
+}
+
+/// Read the text files of `rev`'s tree that the ignore rules keep.
+fn read_tree_files(
+    repo_path: &Path,
+    rev: &str,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: &[String],
+    folder: Option<&str>,
+) -> Result<(String, Vec<(PathBuf, String)>), Error> {
+    let repo = Git2Repository::open(repo_path)
+        .map_err(|e| Error::git(format!("Failed to open git repository {:?}", repo_path), e))?;
+    let commit = resolve_commit(&repo, rev)?;
+    let tree = commit
+        .tree()
+        .map_err(|e| Error::git(format!("Failed to read tree of {:?}", rev), e))?;
+
+    let folder =
+        folder.map(|folder| PathBuf::from(folder.trim_start_matches("./").trim_matches('/')));
+    if let Some(folder) = &folder
+        && tree.get_path(folder).is_err()
+    {
+        return Err(Error::invalid(format!(
+            "Specified folder {:?} not found at {:?}",
+            folder, rev
+        )));
+    }
+
+    let mut walk = TreeWalk {
+        repo: &repo,
+        folder,
+        patterns: build_pattern_matcher(Path::new(""), ignore_patterns),
+        ignore_file: change_matcher(ignore_file_path, None),
+        scopes: Vec::new(),
+        files: Vec::new(),
+    };
+    walk.walk(&tree, Path::new(""))?;
+    Ok((commit.id().to_string(), walk.files))
+}
+
+/// Ignore files read from each directory of a tree, in increasing precedence.
+const TREE_IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".git2promptignore"];
+
+/// A depth-first walk of a git tree that applies ignore files like `ignore::WalkBuilder`:
+/// configured patterns first, then the ignore files of the deepest directory up to the
+/// root, then the explicit ignore file.
+struct TreeWalk<'repo> {
+    repo: &'repo Git2Repository,
+    folder: Option<PathBuf>,
+    patterns: Gitignore,
+    ignore_file: Gitignore,
+    /// Ignore files of the directories being walked, root first.
+    scopes: Vec<Gitignore>,
+    /// Text files kept, with their paths relative to the repository root.
+    files: Vec<(PathBuf, String)>,
+}
+
+impl TreeWalk<'_> {
+    fn walk(&mut self, tree: &Tree, dir: &Path) -> Result<(), Error> {
+        let mut builder = GitignoreBuilder::new(dir);
+        for name in TREE_IGNORE_FILES {
+            if let Some(entry) = tree.get_name(name)
+                && let Ok(blob) = self.repo.find_blob(entry.id())
+            {
+                for line in String::from_utf8_lossy(blob.content()).lines() {
+                    // Invalid lines are skipped, as git does
+                    let _ = builder.add_line(Some(dir.join(name)), line);
                 }
+            }
+        }
+        self.scopes
+            .push(builder.build().unwrap_or_else(|_| Gitignore::empty()));
 
-                let relative_path = match path.strip_prefix(repo_path) {
-                    Ok(p) => p,
-                    Err(e) => {
-                        eprintln!("Failed to strip prefix for {:?}: {}", path, e);
+        for entry in tree.iter() {
+            let Some(name) = entry.name() else {
+                continue;
+            };
+            let path = dir.join(name);
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    if !self.in_folder(&path, true) || self.is_ignored(&path, true) {
                         continue;
                     }
-                };
-
-                // Determine which bucket this file belongs to
-                let bucket_key = determine_bucket(relative_path, split_folders);
-
-                if let Ok(raw_content) = fs::read_to_string(path).await {
-                    let alias = get_language_alias(path);
-                    let with_headers = !no_headers;
-                    let mut file_output = String::new();
-
-                    // Adjust content if it is markdown to avoid header conflicts
-                    let content = if alias == "markdown" {
-                        raw_content
-                            .lines()
-                            .map(|line| {
-                                if line.trim_start().starts_with('#') {
-                                    format!("##{}", line)
-                                } else {
-                                    line.to_string()
-                                }
-                            })
-                            .collect::<Vec<_>>()
-                            .join("\n")
-                    } else {
-                        raw_content
-                    };
-
-                    if with_headers {
-                        // Use ### for merged files context, ## for single file context
-                        let prefix = if merge_files { "###" } else { "##" };
-                        file_output.push_str(&format!(
-                            "{} File: {}\n",
-                            prefix,
-                            relative_path.display()
-                        ));
+                    let subtree = self
+                        .repo
+                        .find_tree(entry.id())
+                        .map_err(|e| Error::git(format!("Failed to read tree {:?}", path), e))?;
+                    self.walk(&subtree, &path)?;
+                }
+                // Symbolic links are not followed
+                Some(ObjectType::Blob) if entry.filemode() != i32::from(FileMode::Link) => {
+                    if !self.in_folder(&path, false)
+                        || is_binary_extension(&path)
+                        || self.is_ignored(&path, false)
+                    {
+                        continue;
                     }
-
-                    // Add warning note for markdown files
-                    if alias == "markdown" {
-                        // Wrap in five backticks to avoid conflicts with existing triple backticks
-                        file_output.push_str(&format!("`````{}\n", alias));
-                        file_output.push_str("> **Note to AI agents:** Headers in this file have been modified (prepended with '##') to avoid conflict with the main document structure.\n\n");
-                        file_output.push_str(&content);
-                        file_output.push_str("\n`````\n\n");
-                    } else {
-                        file_output.push_str(&format!("```{}\n", alias));
-                        file_output.push_str(&content);
-                        file_output.push_str("\n```\n\n");
+                    let blob = self
+                        .repo
+                        .find_blob(entry.id())
+                        .map_err(|e| Error::git(format!("Failed to read {:?}", path), e))?;
+                    // Like unreadable files on disk, non-UTF-8 blobs are skipped
+                    if let Ok(content) = String::from_utf8(blob.content().to_vec()) {
+                        self.files.push((path, content));
                     }
+                }
+                // Submodules
+                _ => {}
+            }
+        }
 
-                    // Append to the correct bucket
-                    if let Some(bucket_content) = content_buckets.get_mut(&bucket_key) {
-                        bucket_content.push_str(&file_output);
-                    } else {
-                        // Fallback to default if bucket somehow missing
-                        if let Some(default_content) = content_buckets.get_mut("default") {
-                            default_content.push_str(&file_output);
-                        }
-                    }
-                } else {
-                    // Fail silently for read errors (likely binary or permissions), or log verbose
-                    // eprintln!("Warning: Could not read file {:?}", path);
+        self.scopes.pop();
+        Ok(())
+    }
+
+    /// Whether `path` is inside the folder being processed, or a directory on the way to it.
+    fn in_folder(&self, path: &Path, is_dir: bool) -> bool {
+        match &self.folder {
+            Some(folder) => path.starts_with(folder) || (is_dir && folder.starts_with(path)),
+            None => true,
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.patterns.matched(path, is_dir).is_ignore() {
+            return true;
+        }
+        // The deepest ignore file with a matching pattern decides
+        for scope in self.scopes.iter().rev() {
+            let matched = scope.matched(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        self.ignore_file.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// Build buckets from the files changed in a pull request, without a checkout.
+/// Depending on `pr_content`, each file contributes its full content at the head commit,
+/// its patch, or both (in that order). Removed files only have a patch.
+///
+/// Only the filter's `ignore_file` and `ignore_patterns` filter the files (in that order of
+/// increasing precedence), since the repository's own ignore files are not available.
+/// Its `folder` is not applied.
+pub async fn process_pull_request_files(
+    client: &GitHubClient,
+    repo: &str,
+    pull_request: &PullRequest,
+    pr_content: PrContent,
+    filter: &FileFilter,
+    tokenizer: &dyn Tokenizer,
+) -> Result<HashMap<String, Bucket>, Error> {
+    let matcher = change_matcher(
+        filter.ignore_file.as_deref(),
+        filter.ignore_patterns.as_deref(),
+    );
+    let files: Vec<_> = pull_request
+        .files
+        .iter()
+        .filter(|file| !is_excluded_change(&matcher, Path::new(&file.filename)))
+        .collect();
+
+    let full_contents: HashMap<&str, Option<String>> = if pr_content.includes_full() {
+        let paths: Vec<&str> = files
+            .iter()
+            .filter(|file| file.status != "removed")
+            .map(|file| file.filename.as_str())
+            .collect();
+        let contents = client
+            .file_contents(repo, &pull_request.head_sha, &paths)
+            .await?;
+        paths.into_iter().zip(contents).collect()
+    } else {
+        HashMap::new()
+    };
+
+    let changes = files
+        .into_iter()
+        .map(|file| {
+            let content = match full_contents.get(file.filename.as_str()) {
+                Some(Some(content)) => Some(content.clone()),
+                Some(None) => {
+                    info!("Skipping file {} (not UTF-8 text)", file.filename);
+                    None
                 }
+                // Not requested, or removed in the pull request
+                None => None,
+            };
+            let patch = if pr_content.includes_patch() || file.status == "removed" {
+                if file.patch.is_none() {
+                    info!(
+                        "Skipping patch of {} (no patch, maybe binary)",
+                        file.filename
+                    );
+                }
+                file.patch.clone()
+            } else {
+                None
+            };
+            ChangedFile {
+                path: PathBuf::from(&file.filename),
+                content,
+                patch,
             }
-            Err(err) => eprintln!("Error walking directory: {}", err),
+        })
+        .collect();
+
+    Ok(change_buckets(
+        changes,
+        filter.split_folders.as_deref(),
+        tokenizer,
+    ))
+}
+
+/// The abbreviated form of a commit SHA shown in messages.
+fn short_sha(sha: &str) -> &str {
+    sha.get(..7).unwrap_or(sha)
+}
+
+/// Build buckets from the changes between two commits of the git repository at
+/// `repo_path`, optionally limited to `folder`. Returns the SHA of the head commit
+/// and the buckets.
+///
+/// Like in PR mode, only `ignore_file_path` and `ignore_patterns` filter the files.
+pub async fn process_diff_files(
+    repo_path: &Path,
+    diff: &LocalDiff,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+) -> Result<(String, HashMap<String, Bucket>), Error> {
+    let changes = diff_repository(repo_path, diff, folder).await?;
+    info!(
+        "Comparing {} ({}..{}): {} changed files",
+        diff.range,
+        short_sha(&changes.base),
+        short_sha(&changes.head),
+        changes.files.len()
+    );
+
+    let matcher = change_matcher(ignore_file_path, ignore_patterns);
+    let files = changes
+        .files
+        .into_iter()
+        .filter(|file| !is_excluded_change(&matcher, &file.path))
+        .collect();
+    Ok((
+        changes.head,
+        change_buckets(files, split_folders, tokenizer),
+    ))
+}
+
+/// Build buckets from the uncommitted changes of the git working tree at `repo_path`,
+/// optionally limited to `folder`. Returns the SHA of HEAD, if any, and the buckets.
+///
+/// Untracked files ignored by `.gitignore` are left out; otherwise, like in PR mode,
+/// only `ignore_file_path` and `ignore_patterns` filter the files.
+pub async fn process_working_tree_changes(
+    repo_path: &Path,
+    changes: &LocalChanges,
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+    split_folders: Option<&[String]>,
+    folder: Option<&str>,
+    tokenizer: &dyn Tokenizer,
+) -> Result<(Option<String>, HashMap<String, Bucket>), Error> {
+    let (commit, files) = working_tree_changes(repo_path, changes, folder).await?;
+    info!("{} changes: {} files", changes.scope, files.len());
+
+    let matcher = change_matcher(ignore_file_path, ignore_patterns);
+    let files = files
+        .into_iter()
+        .filter(|file| !is_excluded_change(&matcher, &file.path))
+        .collect();
+    Ok((commit, change_buckets(files, split_folders, tokenizer)))
+}
+
+/// Build buckets from changed files: the full file, then its patch, for each file
+/// that has them.
+pub fn change_buckets(
+    changes: Vec<ChangedFile>,
+    split_folders: Option<&[String]>,
+    tokenizer: &dyn Tokenizer,
+) -> HashMap<String, Bucket> {
+    let mut content_buckets: HashMap<String, Bucket> = HashMap::new();
+    content_buckets.insert("default".to_string(), Bucket::default());
+    for f in split_folders.unwrap_or_default() {
+        content_buckets.insert(f.to_string(), Bucket::default());
+    }
+
+    for change in changes {
+        let bucket_key = determine_bucket(&change.path, split_folders);
+        let Some(bucket) = content_buckets.get_mut(&bucket_key) else {
+            continue;
+        };
+        if let Some(content) = change.content {
+            let alias = get_language_alias(&change.path);
+            bucket.push(FileEntry::new(
+                change.path.clone(),
+                alias,
+                content,
+                tokenizer,
+            ));
+        }
+        if let Some(patch) = change.patch {
+            bucket.push(FileEntry::patch(change.path, patch, tokenizer));
         }
     }
+    content_buckets
+}
 
-    Ok(content_buckets)
+/// Matcher for changed files, which are filtered without a checkout: `ignore_patterns`
+/// take precedence over the patterns of `ignore_file_path`, as in
+/// [`collect_repository_files`].
+fn change_matcher(
+    ignore_file_path: Option<&Path>,
+    ignore_patterns: Option<&[String]>,
+) -> Gitignore {
+    let mut builder = GitignoreBuilder::new("");
+    if let Some(ignore_path) = ignore_file_path
+        && ignore_path.exists()
+        && let Some(err) = builder.add(ignore_path)
+    {
+        warn!("Error adding ignore file {:?}: {}", ignore_path, err);
+    }
+    for pattern in ignore_patterns.unwrap_or_default() {
+        if let Err(err) = builder.add_line(None, pattern) {
+            warn!("Invalid ignore pattern {:?}: {}", pattern, err);
+        }
+    }
+    builder.build().unwrap_or_else(|err| {
+        warn!("Failed to build ignore patterns: {}", err);
+        Gitignore::empty()
+    })
+}
+
+/// Whether a changed file (relative path) is ignored or binary.
+fn is_excluded_change(matcher: &Gitignore, path: &Path) -> bool {
+    is_binary_extension(path) || matcher.matched_path_or_any_parents(path, false).is_ignore()
+}
+
+/// Compile gitignore-style patterns into a matcher rooted at `root`.
+/// Invalid patterns are reported and skipped.
+pub(crate) fn build_pattern_matcher(root: &Path, patterns: &[String]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(err) = builder.add_line(None, pattern) {
+            warn!("Invalid ignore pattern {:?}: {}", pattern, err);
+        }
+    }
+    builder.build().unwrap_or_else(|err| {
+        warn!("Failed to build ignore patterns: {}", err);
+        Gitignore::empty()
+    })
 }
 
 /// Helper to determine which bucket a file belongs to based on split configuration
@@ -266,100 +953,429 @@ fn is_binary_extension(path: &Path) -> bool {
     }
 }
 
-/// Handle multiple repositories and write output files
+/// Handle multiple repositories and write output files in the configured format,
+/// streaming each file block to the sink (see [`stream_results`]).
+/// When `options.max_tokens` is set, output files exceeding it are split into numbered parts.
+/// Returns the paths of the written files, which are none when writing to stdout.
 pub async fn handle_results(
-    repositories: Vec<Repository>,
+    repositories: &[Repository],
+    merge_files: bool,
+    sink: &OutputSink,
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    match sink {
+        OutputSink::Directory(output_dir) => {
+            let mut sink = DirectorySink::new(output_dir.clone());
+            stream_results(repositories, merge_files, &mut sink, tokenizer, options).await
+        }
+        OutputSink::Stdout { separator } => {
+            let mut sink = WriterSink::new(tokio::io::stdout(), separator.clone());
+            stream_results(repositories, merge_files, &mut sink, tokenizer, options).await
+        }
+    }
+}
+
+/// Render the output documents of multiple repositories in the configured format,
+/// without writing them anywhere.
+/// When `options.max_tokens` is set, documents exceeding it are split into numbered parts.
+pub async fn render_results(
+    repositories: &[Repository],
     merge_files: bool,
-    output_dir: &std::path::Path,
-) -> Result<Vec<PathBuf>, String> {
-    let mut output_paths = Vec::new();
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Vec<Document>, Error> {
+    let mut rendered = Vec::new();
+    for mut document in plan_documents(repositories, merge_files) {
+        document.load().await?;
+        rendered.extend(render_document(document, tokenizer, options)?);
+    }
+    Ok(rendered)
+}
+
+/// Write the output documents of multiple repositories to `sink`, one file block at a
+/// time: the content of a file is read when its block is rendered and dropped once
+/// written, so memory use does not depend on the size of the repositories. Only entries
+/// listed from a working directory or clone are read this way; those of a commit's tree,
+/// a diff, uncommitted changes or a pull request already hold their content.
+///
+/// Documents split into parts (`options.max_tokens`) or rendered with a template need all
+/// of their files at once and are rendered in memory, one document at a time.
+/// Returns the paths of the written files, if the sink writes files.
+pub async fn stream_results<S: DocumentSink>(
+    repositories: &[Repository],
+    merge_files: bool,
+    sink: &mut S,
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    let documents = plan_documents(repositories, merge_files);
+    let in_memory = options.max_tokens.is_some() || options.template.is_some();
+    let mut paths = Vec::new();
+
+    if !sink.accepts_several() {
+        if in_memory {
+            // How many parts there are is only known once everything is rendered
+            let rendered = render_results(repositories, merge_files, tokenizer, options).await?;
+            return write_documents(sink, &rendered).await;
+        }
+        if documents.len() > 1 {
+            let names: Vec<String> = documents
+                .iter()
+                .map(|d| d.file_name(None, options))
+                .collect();
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            return Err(several_documents_error(&names));
+        }
+    }
+
+    for mut document in documents {
+        if in_memory {
+            document.load().await?;
+            for rendered in render_document(document, tokenizer, options)? {
+                paths.extend(write_document(sink, &rendered).await?);
+            }
+        } else {
+            paths.extend(stream_document(document, sink, tokenizer, options).await?);
+        }
+    }
+    Ok(paths)
+}
+
+/// Group the repositories' buckets into output documents, leaving out empty ones.
+fn plan_documents(repositories: &[Repository], merge_files: bool) -> Vec<OutputDocument> {
+    let mut documents: Vec<OutputDocument> = Vec::new();
 
     // For merged content (all repos in one file)
-    let mut merged_default_content = String::new();
+    let mut merged_default = OutputDocument::new(
+        "all_repos".to_string(),
+        "Merged Repository Contents".to_string(),
+        None,
+        None,
+    );
     // For merged content in split folders (e.g. all "src" folders from all repos)
-    // Map<bucket_name, content>
-    let mut merged_split_content: HashMap<String, String> = HashMap::new();
+    // Map<bucket_name, document>, sorted so that documents come in the same order every run
+    let mut merged_split: BTreeMap<String, OutputDocument> = BTreeMap::new();
 
     for repository in repositories {
-        // Content is now a HashMap
-        let buckets = repository.content.unwrap_or_default();
+        let Some(content) = &repository.content else {
+            continue;
+        };
+        let buckets = sorted_buckets(content);
 
         if merge_files {
             // Append default content
-            if let Some(content) = buckets.get("default")
-                && !content.is_empty()
-            {
-                merged_default_content.push_str(&format!("## Repository: {}\n", repository.name));
-                merged_default_content.push_str(content);
+            if let Some(bucket) = content.get("default") {
+                merged_default.extend(repository, bucket);
             }
 
             // Append split content
-            for (bucket, content) in &buckets {
-                if bucket == "default" || content.is_empty() {
+            for &(bucket_name, bucket) in &buckets {
+                if bucket_name == "default" || bucket.is_empty() {
                     continue;
                 }
 
-                let merged_bucket = merged_split_content.entry(bucket.clone()).or_default();
-                merged_bucket.push_str(&format!("## Repository: {}\n", repository.name));
-                merged_bucket.push_str(content);
+                let safe_bucket = bucket_name.replace("/", "_").replace("\\", "_");
+                merged_split
+                    .entry(bucket_name.to_string())
+                    .or_insert_with(|| {
+                        OutputDocument::new(
+                            format!("all_repos_{}", safe_bucket),
+                            format!("Merged Repository Contents ({})", bucket_name),
+                            None,
+                            Some(bucket_name),
+                        )
+                    })
+                    .extend(repository, bucket);
             }
         } else {
             // Individual repo mode
 
             // 1. Process default bucket
-            if let Some(content) = buckets.get("default")
-                && !content.is_empty()
-            {
-                let output_file_name = format!("{}_processed.md", repository.name);
-                let output_path = output_dir.join(output_file_name);
-                let mut final_content = format!("# Repository: {}\n", repository.name);
-                final_content.push_str(content);
-                write_content_to_file(&output_path, &final_content).await?;
-                output_paths.push(output_path);
+            if let Some(bucket) = content.get("default") {
+                let mut document = OutputDocument::new(
+                    repository.name.clone(),
+                    format!("Repository: {}", repository.name),
+                    Some(&repository.name),
+                    None,
+                );
+                document.extend(repository, bucket);
+                documents.push(document);
             }
 
             // 2. Process split buckets
-            for (bucket, content) in &buckets {
-                if bucket == "default" || content.is_empty() {
+            for &(bucket_name, bucket) in &buckets {
+                if bucket_name == "default" {
                     continue;
                 }
 
                 // e.g. repo-name_src_processed.md
                 // Sanitize bucket name for filename
-                let safe_bucket = bucket.replace("/", "_").replace("\\", "_");
-                let output_file_name = format!("{}_{}_processed.md", repository.name, safe_bucket);
-                let output_path = output_dir.join(output_file_name);
-
-                let mut final_content = format!("# Repository: {} ({})\n", repository.name, bucket);
-                final_content.push_str(content);
-                write_content_to_file(&output_path, &final_content).await?;
-                output_paths.push(output_path);
+                let safe_bucket = bucket_name.replace("/", "_").replace("\\", "_");
+                let mut document = OutputDocument::new(
+                    format!("{}_{}", repository.name, safe_bucket),
+                    format!("Repository: {} ({})", repository.name, bucket_name),
+                    Some(&repository.name),
+                    Some(bucket_name),
+                );
+                document.extend(repository, bucket);
+                documents.push(document);
             }
         }
     }
 
-    // Write merged files if active
     if merge_files {
-        if !merged_default_content.is_empty() {
-            let output_path = output_dir.join("all_repos_processed.md");
-            let final_content =
-                String::from("# Merged Repository Contents\n") + &merged_default_content;
-            write_content_to_file(&output_path, &final_content).await?;
-            output_paths.push(output_path);
-        }
-
-        for (bucket, content) in merged_split_content {
-            if !content.is_empty() {
-                let safe_bucket = bucket.replace("/", "_").replace("\\", "_");
-                let output_path =
-                    output_dir.join(format!("all_repos_{}_processed.md", safe_bucket));
-                let final_content =
-                    format!("# Merged Repository Contents ({})\n", bucket) + &content;
-                write_content_to_file(&output_path, &final_content).await?;
-                output_paths.push(output_path);
-            }
+        documents.push(merged_default);
+        documents.extend(merged_split.into_values());
+    }
+
+    documents.retain(|document| !document.items.is_empty());
+    documents
+}
+
+/// A repository's buckets: "default" first, then the split folders alphabetically.
+fn sorted_buckets(buckets: &HashMap<String, Bucket>) -> Vec<(&str, &Bucket)> {
+    let mut sorted: Vec<(&str, &Bucket)> = buckets
+        .iter()
+        .map(|(name, bucket)| (name.as_str(), bucket))
+        .collect();
+    sorted.sort_by_key(|&(name, _)| (name != "default", name));
+    sorted
+}
+
+/// One logical output file, before it is split into parts.
+struct OutputDocument {
+    /// File name prefix, e.g. "owner-repo" or "owner-repo_src".
+    stem: String,
+    /// Title written as the top-level header.
+    title: String,
+    /// The repository, unless several repositories are merged.
+    repository: Option<String>,
+    /// The split folder, if any.
+    bucket: Option<String>,
+    /// `(repository, commit SHA)` of the repositories in the document.
+    commits: Vec<(String, String)>,
+    items: Vec<DocumentItem>,
+}
+
+impl OutputDocument {
+    fn new(stem: String, title: String, repository: Option<&str>, bucket: Option<&str>) -> Self {
+        Self {
+            stem,
+            title,
+            repository: repository.map(str::to_string),
+            bucket: bucket.map(str::to_string),
+            commits: Vec::new(),
+            items: Vec::new(),
+        }
+    }
+
+    fn info(&self, part: Option<(usize, usize)>) -> DocumentInfo<'_> {
+        DocumentInfo {
+            title: &self.title,
+            repository: self.repository.as_deref(),
+            bucket: self.bucket.as_deref(),
+            commits: &self.commits,
+            part,
+        }
+    }
+
+    /// Output file name, e.g. "owner-repo_src_processed.md" or
+    /// "owner-repo_part2_processed.md".
+    fn file_name(&self, part: Option<(usize, usize)>, options: &RenderOptions) -> String {
+        match part {
+            Some((index, _)) => format!(
+                "{}_part{}_processed.{}",
+                self.stem,
+                index,
+                options.extension()
+            ),
+            None => format!("{}_processed.{}", self.stem, options.extension()),
         }
     }
 
-    Ok(output_paths)
+    /// Read the content of files not kept in memory.
+    async fn load(&mut self) -> Result<(), Error> {
+        let loads: Vec<_> = self.items.iter_mut().map(|item| item.file.load()).collect();
+        stream::iter(loads)
+            .buffer_unordered(CONCURRENT_READS)
+            .try_collect()
+            .await
+    }
+
+    /// Add a repository's files, tagging them with the repository when merging.
+    fn extend(&mut self, repository: &Repository, bucket: &Bucket) {
+        let label = self
+            .repository
+            .is_none()
+            .then_some(repository.name.as_str());
+        self.items.extend(
+            bucket
+                .files
+                .iter()
+                .map(|file| DocumentItem::new(label, file.clone())),
+        );
+
+        if let Some(commit) = &repository.commit
+            && !self
+                .commits
+                .iter()
+                .any(|(name, _)| *name == repository.name)
+        {
+            self.commits.push((repository.name.clone(), commit.clone()));
+        }
+    }
+}
+
+/// Write one document with the built-in format, reading and rendering one file at a time.
+async fn stream_document<S: DocumentSink>(
+    mut document: OutputDocument,
+    sink: &mut S,
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Option<PathBuf>, Error> {
+    let format = options.format;
+    let items = std::mem::take(&mut document.items);
+    let info = document.info(None);
+
+    sink.begin(&document.file_name(None, options)).await?;
+    // The header only needs the paths and token counts, which are already known
+    sink.write(&format.render_header(&info, &items, options, tokenizer))
+        .await?;
+    // Files are read ahead of the one being written, but written in order
+    let loads: Vec<_> = items
+        .into_iter()
+        .map(|mut item| async move { item.file.load().await.map(|()| item) })
+        .collect();
+    let mut loaded = stream::iter(loads).buffered(CONCURRENT_READS).enumerate();
+    let mut previous = None;
+    while let Some((index, item)) = loaded.next().await {
+        let mut item = item?;
+        let block = format.render_item(&item, index, previous.as_ref(), options);
+        sink.write(&block).await?;
+        item.file.content = String::new();
+        previous = Some(item);
+    }
+    sink.write(format.render_footer()).await?;
+    sink.end().await
+}
+
+/// Render a document as one file, or as several `_partN` files if it exceeds `max_tokens`.
+fn render_document(
+    mut document: OutputDocument,
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Vec<Document>, Error> {
+    let items = std::mem::take(&mut document.items);
+    let parts = match options.max_tokens {
+        Some(budget) => split_into_parts(items, &document.info(None), budget, tokenizer, options)?,
+        None => vec![items],
+    };
+
+    let count = parts.len();
+    let mut rendered = Vec::new();
+    for (index, items) in parts.iter().enumerate() {
+        let part = (count > 1).then_some((index + 1, count));
+        let info = document.info(part);
+        let content = options.render(&info, items, tokenizer);
+        rendered.push(Document::new(
+            document.file_name(part, options),
+            &info,
+            items,
+            content,
+        ));
+    }
+    Ok(rendered)
+}
+
+/// Split a document's files into parts that each fit in `budget` tokens once rendered,
+/// including the part header. Files are only split when one alone exceeds the budget.
+fn split_into_parts(
+    items: Vec<DocumentItem>,
+    info: &DocumentInfo,
+    budget: usize,
+    tokenizer: &dyn Tokenizer,
+    options: &RenderOptions,
+) -> Result<Vec<Vec<DocumentItem>>, Error> {
+    // Leaves room for the digits of the part number and token totals in the header
+    const HEADER_SLACK: usize = 8;
+
+    let fixed = options.fixed_cost(info, tokenizer) + HEADER_SLACK;
+    if fixed >= budget {
+        return Err(Error::invalid(format!(
+            "Token budget of {} is too small for the output header of {:?}",
+            budget, info.title
+        )));
+    }
+    let available = budget - fixed;
+
+    let cost = |item: &DocumentItem| options.item_cost(info, item, tokenizer);
+
+    let mut fitted: Vec<DocumentItem> = Vec::new();
+    for item in items {
+        if cost(&item) <= available {
+            fitted.push(item);
+            continue;
+        }
+
+        // Render the file without content, labelled as a segment, to find the overhead
+        let mut wrapper = item.clone();
+        wrapper.file.content = String::new();
+        wrapper.file.segment = Some((999, 999));
+        let content_budget = available.saturating_sub(cost(&wrapper));
+        let measure =
+            |text: &str| tokenizer.count(&options.encode_content(&item.file.language, text));
+        for segment in chunking::split_entry(&item.file, content_budget, &measure, tokenizer)? {
+            fitted.push(DocumentItem {
+                repository: item.repository.clone(),
+                file: segment,
+            });
+        }
+    }
+
+    let costs: Vec<usize> = fitted.iter().map(cost).collect();
+    let ranges = chunking::plan_parts(&costs, available);
+
+    let mut fitted = fitted.into_iter();
+    Ok(ranges
+        .into_iter()
+        .map(|range| fitted.by_ref().take(range.len()).collect())
+        .collect())
+}
+
+/// Build a human-readable report of the token count of each repository and in total.
+pub fn token_report(repositories: &[Repository], tokenizer: &dyn Tokenizer) -> String {
+    let mut report = format!("Token counts ({}):\n", tokenizer.name());
+    let mut grand_total = 0;
+    for repository in repositories {
+        let Some(buckets) = &repository.content else {
+            continue;
+        };
+        let total: usize = buckets.values().map(Bucket::total_tokens).sum();
+        report.push_str(&format!("{}: {} tokens\n", repository.name, total));
+        grand_total += total;
+    }
+    report.push_str(&format!("Total: {} tokens", grand_total));
+    report
+}
+
+/// Build a human-readable report of per-bucket and per-file token counts.
+pub fn token_details(repositories: &[Repository]) -> String {
+    let mut report = String::new();
+    for repository in repositories {
+        let Some(buckets) = &repository.content else {
+            continue;
+        };
+        report.push_str(&format!("{}:\n", repository.name));
+        for (name, bucket) in sorted_buckets(buckets) {
+            if bucket.is_empty() {
+                continue;
+            }
+            report.push_str(&format!("  [{}] {} tokens\n", name, bucket.total_tokens()));
+            for file in &bucket.files {
+                report.push_str(&format!("    - {}: {}\n", file.path.display(), file.tokens));
+            }
+        }
+    }
+    report
 }
@@ -0,0 +1,328 @@
+// src/remote.rs
+use crate::auth::redact_url;
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Where a repository is cloned from, parsed from a command-line source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteSpec {
+    /// `owner/repo` shorthand for a GitHub repository.
+    GitHub { owner: String, repo: String },
+    /// A URL with a scheme: `https://`, `http://`, `ssh://`, `git://` or `file://`.
+    Url {
+        url: String,
+        host: String,
+        path: String,
+    },
+    /// scp-style SSH syntax: `[user@]host:path`.
+    Scp {
+        url: String,
+        host: String,
+        path: String,
+    },
+    /// A repository (bare or not) on the local file system.
+    Local(PathBuf),
+}
+
+const SCHEMES: &[&str] = &["https", "http", "ssh", "git", "git+ssh", "file"];
+
+impl RemoteSpec {
+    /// Parse a repository source.
+    ///
+    /// - `owner/repo` is a GitHub repository.
+    /// - `scheme://host/path` is used as is.
+    /// - `[user@]host:path` is an scp-style SSH remote.
+    /// - Anything starting with `/`, `./`, `../` or `~/` is a local repository, as is
+    ///   any other existing directory that is not `owner/repo` shorthand.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let source = source.trim();
+        if source.is_empty() {
+            return Err(Error::invalid("Empty repository source"));
+        }
+
+        if let Some((scheme, rest)) = source.split_once("://") {
+            if !SCHEMES.contains(&scheme.to_lowercase().as_str()) {
+                return Err(Error::invalid(format!(
+                    "Unsupported URL scheme {:?} in {:?} (expected one of {})",
+                    scheme,
+                    redact_url(source),
+                    SCHEMES.join(", ")
+                )));
+            }
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            // Drop user info and port
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            let host = host.split(':').next().unwrap_or(host);
+            return Ok(RemoteSpec::Url {
+                url: source.to_string(),
+                host: host.to_lowercase(),
+                path: path.to_string(),
+            });
+        }
+
+        if is_explicit_path(source) {
+            return Ok(RemoteSpec::Local(expand_home(source)));
+        }
+
+        if let Some((authority, path)) = source.split_once(':')
+            && !authority.contains('/')
+            && !path.is_empty()
+        {
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            return Ok(RemoteSpec::Scp {
+                url: source.to_string(),
+                host: host.to_lowercase(),
+                path: path.to_string(),
+            });
+        }
+
+        let segments: Vec<&str> = source.split('/').collect();
+        if let [owner, repo] = segments[..]
+            && is_name(owner)
+            && is_name(repo.trim_end_matches(".git"))
+        {
+            return Ok(RemoteSpec::GitHub {
+                owner: owner.to_string(),
+                repo: repo.trim_end_matches(".git").to_string(),
+            });
+        }
+
+        if Path::new(source).is_dir() {
+            return Ok(RemoteSpec::Local(PathBuf::from(source)));
+        }
+
+        Err(Error::invalid(format!(
+            "Unrecognized repository source {:?} (expected owner/repo, a URL, \
+             user@host:path or a local path)",
+            source
+        )))
+    }
+
+    /// The URL handed to git.
+    pub fn url(&self) -> String {
+        match self {
+            RemoteSpec::GitHub { owner, repo } => {
+                format!("https://github.com/{}/{}.git", owner, repo)
+            }
+            RemoteSpec::Url { url, .. } | RemoteSpec::Scp { url, .. } => url.clone(),
+            RemoteSpec::Local(path) => path
+                .canonicalize()
+                .unwrap_or_else(|_| path.clone())
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    /// A file-name-safe name: the path segments joined by `-` (e.g. `owner-repo` or
+    /// `group-subgroup-repo`), or the directory name for local and `file://` repositories.
+    /// A trailing `.git` is dropped.
+    pub fn name(&self) -> String {
+        let name = match self {
+            RemoteSpec::GitHub { owner, repo } => format!("{}-{}", owner, repo),
+            RemoteSpec::Url { host, path, .. } | RemoteSpec::Scp { host, path, .. } => {
+                let segments: Vec<&str> = path
+                    .trim_end_matches('/')
+                    .trim_end_matches(".git")
+                    .split('/')
+                    .filter(|s| !s.is_empty() && *s != "~")
+                    .collect();
+                match segments.last() {
+                    None => host.clone(),
+                    // file:// URLs have no host; name them like local paths
+                    Some(last) if host.is_empty() => last.to_string(),
+                    Some(_) => segments.join("-"),
+                }
+            }
+            RemoteSpec::Local(path) => path
+                .canonicalize()
+                .unwrap_or_else(|_| path.clone())
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|s| s.trim_end_matches(".git").to_string())
+                .unwrap_or_else(|| "local-repo".to_string()),
+        };
+        sanitize_name(&name)
+    }
+
+    /// The host name, if the remote is not local.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            RemoteSpec::GitHub { .. } => Some("github.com"),
+            RemoteSpec::Url { host, .. } | RemoteSpec::Scp { host, .. } if !host.is_empty() => {
+                Some(host)
+            }
+            _ => None,
+        }
+    }
+
+    /// `owner/repo` if the remote path has exactly that shape, on any host.
+    pub fn slug(&self) -> Option<String> {
+        match self {
+            RemoteSpec::GitHub { owner, repo } => Some(format!("{}/{}", owner, repo)),
+            RemoteSpec::Url { path, .. } | RemoteSpec::Scp { path, .. } => {
+                let path = path.trim_end_matches('/').trim_end_matches(".git");
+                match path.split('/').collect::<Vec<_>>()[..] {
+                    [owner, repo] if is_name(owner) && is_name(repo) => {
+                        Some(format!("{}/{}", owner, repo))
+                    }
+                    _ => None,
+                }
+            }
+            RemoteSpec::Local(_) => None,
+        }
+    }
+
+    /// `owner/repo` if the remote is a repository on github.com.
+    pub fn github_slug(&self) -> Option<String> {
+        self.host()
+            .filter(|host| *host == "github.com")
+            .and_then(|_| self.slug())
+    }
+}
+
+/// Split `source@ref` into the source and the ref.
+/// Only an `@` after the start of the repository path counts, so URL user info
+/// (`https://user@host/...`) is never mistaken for a ref, while refs may contain `/`.
+pub(crate) fn split_ref(source: &str) -> (&str, Option<&str>) {
+    // Skip the scheme and host, if any
+    let mut start = source.rfind(':').map_or(0, |i| i + 1);
+    if source[start..].starts_with("//") {
+        start += 2;
+        start += source[start..].find('/').unwrap_or(source.len() - start);
+    }
+
+    let at = source[start..].find('/').and_then(|slash| {
+        source[start + slash..]
+            .find('@')
+            .map(|at| start + slash + at)
+    });
+    match at {
+        Some(at) if at + 1 < source.len() => (&source[..at], Some(&source[at + 1..])),
+        _ => (source, None),
+    }
+}
+
+fn is_explicit_path(source: &str) -> bool {
+    source.starts_with('/')
+        || source.starts_with("./")
+        || source.starts_with("../")
+        || source.starts_with("~/")
+        || source == "."
+        || source == ".."
+        || Path::new(source).is_absolute()
+}
+
+fn expand_home(source: &str) -> PathBuf {
+    match (source.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(source),
+    }
+}
+
+/// A GitHub owner or repository name.
+fn is_name(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remotes() {
+        let cases = [
+            (
+                "owner/repo",
+                "https://github.com/owner/repo.git",
+                "owner-repo",
+                Some("owner/repo"),
+            ),
+            (
+                "https://github.com/owner/repo.git",
+                "https://github.com/owner/repo.git",
+                "owner-repo",
+                Some("owner/repo"),
+            ),
+            (
+                "https://gitlab.com/group/subgroup/repo",
+                "https://gitlab.com/group/subgroup/repo",
+                "group-subgroup-repo",
+                None,
+            ),
+            (
+                "ssh://git@gitea.example.com:2222/team/repo.git",
+                "ssh://git@gitea.example.com:2222/team/repo.git",
+                "team-repo",
+                None,
+            ),
+            (
+                "git@github.com:owner/repo.git",
+                "git@github.com:owner/repo.git",
+                "owner-repo",
+                Some("owner/repo"),
+            ),
+            (
+                "git@bitbucket.org:team/repo.git",
+                "git@bitbucket.org:team/repo.git",
+                "team-repo",
+                None,
+            ),
+            (
+                "file:///srv/git/project.git",
+                "file:///srv/git/project.git",
+                "project",
+                None,
+            ),
+        ];
+        for (source, url, name, slug) in cases {
+            let spec = RemoteSpec::parse(source).unwrap();
+            assert_eq!(spec.url(), url, "{}", source);
+            assert_eq!(spec.name(), name, "{}", source);
+            assert_eq!(spec.github_slug().as_deref(), slug, "{}", source);
+        }
+
+        let local = RemoteSpec::parse("/srv/git/project.git").unwrap();
+        assert_eq!(
+            local,
+            RemoteSpec::Local(PathBuf::from("/srv/git/project.git"))
+        );
+        assert_eq!(local.name(), "project");
+
+        assert!(RemoteSpec::parse("ftp://host/repo").is_err());
+        assert!(RemoteSpec::parse("not a repo").is_err());
+        assert!(RemoteSpec::parse("").is_err());
+    }
+
+    #[test]
+    fn test_split_ref() {
+        assert_eq!(split_ref("owner/repo"), ("owner/repo", None));
+        assert_eq!(split_ref("owner/repo@"), ("owner/repo@", None));
+        assert_eq!(
+            split_ref("owner/repo@feature/x"),
+            ("owner/repo", Some("feature/x"))
+        );
+        assert_eq!(
+            split_ref("https://user@host/owner/repo"),
+            ("https://user@host/owner/repo", None)
+        );
+        assert_eq!(
+            split_ref("git@github.com:owner/repo.git@abc123"),
+            ("git@github.com:owner/repo.git", Some("abc123"))
+        );
+    }
+}
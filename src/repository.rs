@@ -1,4 +1,8 @@
 // src/repository.rs
+use crate::auth::redact_url;
+use crate::bucket::Bucket;
+use crate::error::Error;
+use crate::remote::{RemoteSpec, split_ref};
 use std::{
     collections::HashMap,
     fmt,
@@ -10,24 +14,49 @@ pub struct Repository {
     pub url: String,
     pub name: String,
     pub path: PathBuf,
+    /// Branch, tag or commit to check out after cloning (default branch if unset).
+    pub git_ref: Option<String>,
+    /// SHA of the commit that was processed, once known.
+    pub commit: Option<String>,
     // Content is now a HashMap to support splitting.
     // Key: "default" for main content, or folder name for split content.
-    pub content: Option<HashMap<String, String>>,
+    pub content: Option<HashMap<String, Bucket>>,
 }
 
 impl Repository {
-    /// Creates a new Repository instance from a remote GitHub URL.
-    pub fn new(base_download_dir: &Path, repo_url: &str) -> Self {
-        let url = format!("https://github.com/{}.git", repo_url);
-        let name = repo_url.replace("/", "-");
+    /// Creates a new Repository instance from a remote source: `owner/repo` shorthand
+    /// for GitHub, a URL, scp-style `user@host:path` or a local repository path
+    /// (see [`RemoteSpec::parse`]).
+    /// The source may end with `@ref` to select a branch, tag or commit;
+    /// otherwise `default_ref` is used.
+    pub fn new(
+        base_download_dir: &Path,
+        source: &str,
+        default_ref: Option<&str>,
+    ) -> Result<Self, Error> {
+        // An existing path is never split, in case a directory name contains '@'
+        let (source, git_ref) = match split_ref(source) {
+            (repository, Some(git_ref)) if !Path::new(source).exists() => {
+                (repository, Some(git_ref))
+            }
+            _ => (source, default_ref),
+        };
+        let remote = RemoteSpec::parse(source)?;
+        let mut name = remote.name();
+        if let Some(git_ref) = git_ref {
+            // Keep repositories fetched at different refs apart
+            name = format!("{}@{}", name, git_ref.replace(['/', '\\'], "-"));
+        }
         let path = base_download_dir.join(&name);
 
-        Self {
-            url,
+        Ok(Self {
+            url: remote.url(),
             name,
             path,
+            git_ref: git_ref.map(str::to_string),
+            commit: None,
             content: None,
-        }
+        })
     }
 
     /// Creates a new Repository instance from a local file system path.
@@ -47,6 +76,8 @@ impl Repository {
             url: "local".to_string(), // URL is not applicable
             name,
             path: local_path.to_path_buf(), // The path is the provided local path
+            git_ref: None,
+            commit: None,
             content: None,
         }
     }
@@ -62,9 +93,40 @@ impl fmt::Display for Repository {
             f,
             "Repository(name: {}, url: {}, path: {:?}, has_content: {})",
             self.name,
-            self.url,
+            redact_url(&self.url),
             self.path,
             self.has_content()
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_with_ref() {
+        let base = Path::new("temp");
+        let repository = Repository::new(base, "owner/repo", None).unwrap();
+        assert_eq!(repository.url, "https://github.com/owner/repo.git");
+        assert_eq!(repository.name, "owner-repo");
+        assert_eq!(repository.git_ref, None);
+
+        let repository = Repository::new(base, "owner/repo@v1.2.0", Some("main")).unwrap();
+        assert_eq!(repository.url, "https://github.com/owner/repo.git");
+        assert_eq!(repository.name, "owner-repo@v1.2.0");
+        assert_eq!(repository.git_ref.as_deref(), Some("v1.2.0"));
+
+        let repository = Repository::new(base, "owner/repo", Some("feature/x")).unwrap();
+        assert_eq!(repository.name, "owner-repo@feature-x");
+        assert_eq!(repository.path, base.join("owner-repo@feature-x"));
+        assert_eq!(repository.git_ref.as_deref(), Some("feature/x"));
+
+        let repository =
+            Repository::new(base, "git@gitlab.com:group/repo.git@release/2.0", None).unwrap();
+        assert_eq!(repository.url, "git@gitlab.com:group/repo.git");
+        assert_eq!(repository.name, "group-repo@release-2.0");
+
+        assert!(Repository::new(base, "not a repo", None).is_err());
+    }
+}
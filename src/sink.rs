@@ -0,0 +1,180 @@
+// src/sink.rs
+use crate::document::Document;
+use crate::error::Error;
+use crate::io_utils::ensure_output_directory;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// Receives output documents while they are rendered, one file block at a time, so that
+/// a repository's content never has to be held in memory as a whole.
+///
+/// Documents are written one after another: [`begin`](Self::begin), any number of
+/// [`write`](Self::write) calls, then [`end`](Self::end).
+pub trait DocumentSink: Send {
+    /// Whether the sink takes more than one document. Checked before anything is written.
+    fn accepts_several(&self) -> bool {
+        true
+    }
+
+    /// Start the document that would be written to the file `name`,
+    /// e.g. "owner-repo_src_processed.md".
+    fn begin(&mut self, name: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Append rendered output to the current document.
+    fn write(&mut self, chunk: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Finish the current document. Returns the path of the written file, if any.
+    fn end(&mut self) -> impl Future<Output = Result<Option<PathBuf>, Error>> + Send;
+}
+
+/// Writes each document to a file of its own in a directory.
+#[derive(Debug)]
+pub struct DirectorySink {
+    dir: PathBuf,
+    current: Option<(PathBuf, BufWriter<fs::File>)>,
+}
+
+impl DirectorySink {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, current: None }
+    }
+}
+
+impl DocumentSink for DirectorySink {
+    async fn begin(&mut self, name: &str) -> Result<(), Error> {
+        ensure_output_directory(&self.dir).await?;
+        let path = self.dir.join(name);
+        let file = fs::File::create(&path)
+            .await
+            .map_err(|e| Error::io(format!("Failed to create output file {:?}", path), e))?;
+        self.current = Some((path, BufWriter::new(file)));
+        Ok(())
+    }
+
+    async fn write(&mut self, chunk: &str) -> Result<(), Error> {
+        let (path, file) = self
+            .current
+            .as_mut()
+            .expect("write called outside of a document");
+        file.write_all(chunk.as_bytes())
+            .await
+            .map_err(|e| Error::io(format!("Failed to write to output file {:?}", path), e))
+    }
+
+    async fn end(&mut self) -> Result<Option<PathBuf>, Error> {
+        let (path, mut file) = self
+            .current
+            .take()
+            .expect("end called outside of a document");
+        file.flush()
+            .await
+            .map_err(|e| Error::io(format!("Failed to write to output file {:?}", path), e))?;
+        Ok(Some(path))
+    }
+}
+
+/// Writes every document to the same writer, such as stdout. Several documents are only
+/// accepted with a `separator`, a line written before each document in which `{name}` is
+/// replaced by its file name.
+#[derive(Debug)]
+pub struct WriterSink<W> {
+    writer: W,
+    separator: Option<String>,
+    // Whether the document so far ends in the middle of a line
+    open_line: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> WriterSink<W> {
+    pub fn new(writer: W, separator: Option<String>) -> Self {
+        Self {
+            writer,
+            separator,
+            open_line: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer
+            .write_all(bytes)
+            .await
+            .map_err(|e| Error::io("Failed to write output", e))
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> DocumentSink for WriterSink<W> {
+    fn accepts_several(&self) -> bool {
+        self.separator.is_some()
+    }
+
+    async fn begin(&mut self, name: &str) -> Result<(), Error> {
+        self.open_line = false;
+        if let Some(separator) = &self.separator {
+            let line = format!("{}\n", separator.replace("{name}", name));
+            self.write_bytes(line.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, chunk: &str) -> Result<(), Error> {
+        if !chunk.is_empty() {
+            self.open_line = !chunk.ends_with('\n');
+        }
+        self.write_bytes(chunk.as_bytes()).await
+    }
+
+    async fn end(&mut self) -> Result<Option<PathBuf>, Error> {
+        // Keep the next separator on a line of its own
+        if self.separator.is_some() && self.open_line {
+            self.write_bytes(b"\n").await?;
+        }
+        self.writer
+            .flush()
+            .await
+            .map_err(|e| Error::io("Failed to write output", e))?;
+        Ok(None)
+    }
+}
+
+/// Write documents rendered in memory, checking first that `sink` takes them all, so
+/// that nothing is written otherwise. Returns the paths of the written files, if any.
+pub async fn write_documents<S: DocumentSink>(
+    sink: &mut S,
+    documents: &[Document],
+) -> Result<Vec<PathBuf>, Error> {
+    if documents.len() > 1 && !sink.accepts_several() {
+        let names: Vec<&str> = documents.iter().map(|d| d.name.as_str()).collect();
+        return Err(several_documents_error(&names));
+    }
+    let mut paths = Vec::new();
+    for document in documents {
+        paths.extend(write_document(sink, document).await?);
+    }
+    Ok(paths)
+}
+
+/// Write one document rendered in memory.
+pub(crate) async fn write_document<S: DocumentSink>(
+    sink: &mut S,
+    document: &Document,
+) -> Result<Option<PathBuf>, Error> {
+    sink.begin(&document.name).await?;
+    sink.write(&document.content).await?;
+    sink.end().await
+}
+
+/// The error for several documents sent to a sink taking only one.
+pub(crate) fn several_documents_error(names: &[&str]) -> Error {
+    Error::invalid(format!(
+        "Cannot write {} output documents to a single stream such as stdout without a \
+         separator ({}). Pass a separator to write them one after another, or merge them \
+         into one document (e.g. without split folders or a token budget).",
+        names.len(),
+        names.join(", ")
+    ))
+}
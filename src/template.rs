@@ -0,0 +1,493 @@
+// src/template.rs
+use crate::bucket::DocumentItem;
+use crate::error::Error;
+use crate::format::DocumentInfo;
+use crate::tokenizer::Tokenizer;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+/// Variables available anywhere in a template.
+const DOCUMENT_VARIABLES: &[&str] = &[
+    "title",
+    "repo",
+    "bucket",
+    "commit",
+    "part",
+    "parts",
+    "total_tokens",
+    "tokenizer",
+    "file_count",
+];
+
+/// Variables available inside `{{#each files}}`.
+const FILE_VARIABLES: &[&str] = &[
+    "path", "label", "language", "content", "tokens", "index", "segment", "kind", "repo",
+];
+
+/// A user-defined output template.
+///
+/// Supported syntax:
+/// - `{{name}}` inserts a variable.
+/// - `{{#each files}}…{{/each}}` repeats its body for every file.
+/// - `{{#if name}}…{{else}}…{{/if}}` and `{{#unless name}}…{{/unless}}` test whether
+///   a variable is non-empty (`0` and `false` count as empty).
+///
+/// Block tags on a line of their own do not leave an empty line behind.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+    extension: String,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Variable(String),
+    Condition {
+        name: String,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each(Vec<Node>),
+}
+
+/// Values for one render: document-level variables and one map per file.
+#[derive(Debug, Default)]
+pub struct TemplateContext {
+    pub document: HashMap<&'static str, String>,
+    pub files: Vec<HashMap<&'static str, String>>,
+}
+
+impl TemplateContext {
+    /// Build the variables for one output file.
+    pub fn new(info: &DocumentInfo, items: &[DocumentItem], tokenizer: &dyn Tokenizer) -> Self {
+        let (part, parts) = match info.part {
+            Some((index, count)) => (index.to_string(), count.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let total: usize = items.iter().map(|item| item.file.tokens).sum();
+
+        let mut document = HashMap::new();
+        document.insert("title", info.title.to_string());
+        document.insert("repo", info.repository.unwrap_or_default().to_string());
+        document.insert("bucket", info.bucket.unwrap_or_default().to_string());
+        let commit = match info.commits {
+            [(_, commit)] if info.repository.is_some() => commit.clone(),
+            commits => commits
+                .iter()
+                .map(|(repository, commit)| format!("{}: {}", repository, commit))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        document.insert("commit", commit);
+        document.insert("part", part);
+        document.insert("parts", parts);
+        document.insert("total_tokens", total.to_string());
+        document.insert("tokenizer", tokenizer.name().to_string());
+        document.insert("file_count", items.len().to_string());
+
+        Self {
+            document,
+            files: items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| Self::file(item, index + 1))
+                .collect(),
+        }
+    }
+
+    /// The variables of a single file. `index` is 1-based.
+    /// `repo` is only set here when several repositories are merged.
+    pub fn file(item: &DocumentItem, index: usize) -> HashMap<&'static str, String> {
+        let file = &item.file;
+        let mut variables = HashMap::new();
+        variables.insert("path", file.path.display().to_string());
+        variables.insert("label", item.label());
+        variables.insert("language", file.language.clone());
+        variables.insert("content", file.content.clone());
+        variables.insert("tokens", file.tokens.to_string());
+        variables.insert("index", index.to_string());
+        variables.insert("kind", file.kind.as_str().to_string());
+        variables.insert(
+            "segment",
+            file.segment
+                .map(|(i, n)| format!("{} of {}", i, n))
+                .unwrap_or_default(),
+        );
+        if let Some(repository) = &item.repository {
+            variables.insert("repo", repository.clone());
+        }
+        variables
+    }
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Tag(&'a str),
+}
+
+impl Template {
+    /// Load a template from a file. The output extension is taken from the file name
+    /// (`prompt.xml` → `xml`, `prompt.md.tmpl` → `md`, otherwise `txt`).
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        let source = fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::io(format!("Failed to read template {:?}", path), e))?;
+        let mut template = Self::parse_source(&source).map_err(|e| Error::Config {
+            message: format!("Invalid template {:?}: {}", path, e),
+            source: None,
+        })?;
+        template.extension = extension_for(path);
+        Ok(template)
+    }
+
+    /// Parse a template from a string.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        Self::parse_source(source).map_err(|e| Error::Config {
+            message: format!("Invalid template: {}", e),
+            source: None,
+        })
+    }
+
+    fn parse_source(source: &str) -> Result<Self, String> {
+        let tokens = trim_standalone_tags(tokenize(source)?);
+        let mut tokens = tokens.into_iter();
+        let (nodes, end) = parse_nodes(&mut tokens, false)?;
+        if let Some(end) = end {
+            return Err(format!("Unexpected {{{{{}}}}}", end));
+        }
+        if !contains_each(&nodes) {
+            return Err("Template has no {{#each files}} block".to_string());
+        }
+        Ok(Self {
+            nodes,
+            extension: "txt".to_string(),
+        })
+    }
+
+    /// Extension used for output files rendered with this template.
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    /// Render the whole template.
+    pub fn render(&self, context: &TemplateContext) -> String {
+        let mut output = String::new();
+        render_nodes(&self.nodes, context, None, &mut output);
+        output
+    }
+
+    /// Render only the body of `{{#each files}}` for a single file.
+    /// Used to measure how much a file adds to the output.
+    pub fn render_file(
+        &self,
+        context: &TemplateContext,
+        file: &HashMap<&'static str, String>,
+    ) -> String {
+        let mut output = String::new();
+        if let Some(body) = find_each(&self.nodes) {
+            render_nodes(body, context, Some(file), &mut output);
+        }
+        output
+    }
+}
+
+fn extension_for(path: &Path) -> String {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension {
+        "" => "txt".to_string(),
+        "tmpl" | "tpl" | "hbs" | "template" => path
+            .file_stem()
+            .map(Path::new)
+            .and_then(|stem| stem.extension())
+            .and_then(|e| e.to_str())
+            .unwrap_or("txt")
+            .to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "Unclosed {{ tag".to_string())?;
+        tokens.push(Token::Tag(after[..end].trim()));
+        rest = &after[end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+fn is_block_tag(tag: &str) -> bool {
+    tag.starts_with('#') || tag.starts_with('/') || tag == "else"
+}
+
+/// Drop the whitespace and newline around block tags that sit alone on a line.
+fn trim_standalone_tags(mut tokens: Vec<Token<'_>>) -> Vec<Token<'_>> {
+    for i in 0..tokens.len() {
+        let Token::Tag(tag) = tokens[i] else {
+            continue;
+        };
+        if !is_block_tag(tag) {
+            continue;
+        }
+
+        // Text before the tag must end with a newline (or be the start) plus indentation
+        let before_ok = match i.checked_sub(1).map(|j| &tokens[j]) {
+            None => true,
+            Some(Token::Text(text)) => {
+                let line_start = text.rfind('\n').map_or(0, |p| p + 1);
+                text[line_start..].trim().is_empty() && (line_start > 0 || i == 1)
+            }
+            Some(Token::Tag(_)) => false,
+        };
+        // Text after the tag must be whitespace up to a newline (or the end)
+        let after_ok = match tokens.get(i + 1) {
+            None => true,
+            Some(Token::Text(text)) => {
+                let line_end = text.find('\n').unwrap_or(text.len());
+                text[..line_end].trim().is_empty()
+            }
+            Some(Token::Tag(_)) => false,
+        };
+        if !(before_ok && after_ok) {
+            continue;
+        }
+
+        if i > 0
+            && let Token::Text(text) = tokens[i - 1]
+        {
+            let line_start = text.rfind('\n').map_or(0, |p| p + 1);
+            tokens[i - 1] = Token::Text(&text[..line_start]);
+        }
+        if let Some(Token::Text(text)) = tokens.get(i + 1) {
+            let text = *text;
+            let cut = text.find('\n').map_or(text.len(), |p| p + 1);
+            tokens[i + 1] = Token::Text(&text[cut..]);
+        }
+    }
+    tokens
+}
+
+/// Parse nodes until the end of input or a closing/else tag, which is returned.
+fn parse_nodes<'a>(
+    tokens: &mut impl Iterator<Item = Token<'a>>,
+    in_each: bool,
+) -> Result<(Vec<Node>, Option<String>), String> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.next() {
+        let tag = match token {
+            Token::Text(text) => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text.to_string()));
+                }
+                continue;
+            }
+            Token::Tag(tag) => tag,
+        };
+
+        if tag.starts_with('/') || tag == "else" {
+            return Ok((nodes, Some(tag.to_string())));
+        }
+
+        if let Some(block) = tag.strip_prefix('#') {
+            let mut words = block.split_whitespace();
+            let keyword = words.next().unwrap_or("");
+            let name = words.next().unwrap_or("").to_string();
+            if words.next().is_some() {
+                return Err(format!("Too many arguments in {{{{{}}}}}", tag));
+            }
+            match keyword {
+                "each" => {
+                    if name != "files" {
+                        return Err(format!(
+                            "Only {{{{#each files}}}} is supported, found {{{{{}}}}}",
+                            tag
+                        ));
+                    }
+                    if in_each {
+                        return Err("{{#each files}} cannot be nested".to_string());
+                    }
+                    let (body, end) = parse_nodes(tokens, true)?;
+                    expect_end(end, "/each")?;
+                    nodes.push(Node::Each(body));
+                }
+                "if" | "unless" => {
+                    check_variable(&name, in_each)?;
+                    let (then, end) = parse_nodes(tokens, in_each)?;
+                    let closing = format!("/{}", keyword);
+                    let otherwise = if end.as_deref() == Some("else") {
+                        let (otherwise, end) = parse_nodes(tokens, in_each)?;
+                        expect_end(end, &closing)?;
+                        otherwise
+                    } else {
+                        expect_end(end, &closing)?;
+                        Vec::new()
+                    };
+                    nodes.push(Node::Condition {
+                        name,
+                        negate: keyword == "unless",
+                        then,
+                        otherwise,
+                    });
+                }
+                other => return Err(format!("Unknown block {{{{#{}}}}}", other)),
+            }
+            continue;
+        }
+
+        check_variable(tag, in_each)?;
+        nodes.push(Node::Variable(tag.to_string()));
+    }
+    Ok((nodes, None))
+}
+
+fn expect_end(end: Option<String>, expected: &str) -> Result<(), String> {
+    match end {
+        Some(tag) if tag == expected => Ok(()),
+        Some(tag) => Err(format!(
+            "Expected {{{{{}}}}} but found {{{{{}}}}}",
+            expected, tag
+        )),
+        None => Err(format!("Missing {{{{{}}}}}", expected)),
+    }
+}
+
+fn check_variable(name: &str, in_each: bool) -> Result<(), String> {
+    if DOCUMENT_VARIABLES.contains(&name) || (in_each && FILE_VARIABLES.contains(&name)) {
+        return Ok(());
+    }
+    if FILE_VARIABLES.contains(&name) {
+        return Err(format!(
+            "{{{{{}}}}} is only available inside {{{{#each files}}}}",
+            name
+        ));
+    }
+    Err(format!("Unknown variable {{{{{}}}}}", name))
+}
+
+fn contains_each(nodes: &[Node]) -> bool {
+    find_each(nodes).is_some()
+}
+
+fn find_each(nodes: &[Node]) -> Option<&[Node]> {
+    nodes.iter().find_map(|node| match node {
+        Node::Each(body) => Some(body.as_slice()),
+        Node::Condition {
+            then, otherwise, ..
+        } => find_each(then).or_else(|| find_each(otherwise)),
+        _ => None,
+    })
+}
+
+fn lookup<'a>(
+    name: &str,
+    context: &'a TemplateContext,
+    file: Option<&'a HashMap<&'static str, String>>,
+) -> &'a str {
+    file.and_then(|f| f.get(name))
+        .or_else(|| context.document.get(name))
+        .map_or("", String::as_str)
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    context: &TemplateContext,
+    file: Option<&HashMap<&'static str, String>>,
+    output: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Variable(name) => output.push_str(lookup(name, context, file)),
+            Node::Condition {
+                name,
+                negate,
+                then,
+                otherwise,
+            } => {
+                let value = lookup(name, context, file);
+                let truthy = !(value.is_empty() || value == "0" || value == "false");
+                let branch = if truthy != *negate { then } else { otherwise };
+                render_nodes(branch, context, file, output);
+            }
+            Node::Each(body) => {
+                for file in &context.files {
+                    render_nodes(body, context, Some(file), output);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> TemplateContext {
+        let mut context = TemplateContext::default();
+        context.document.insert("repo", "owner-repo".to_string());
+        context.document.insert("part", String::new());
+        for (path, tokens) in [("a.rs", "3"), ("b.md", "0")] {
+            let mut file = HashMap::new();
+            file.insert("path", path.to_string());
+            file.insert("tokens", tokens.to_string());
+            file.insert("content", format!("content of {}", path));
+            context.files.push(file);
+        }
+        context
+    }
+
+    #[test]
+    fn test_render_loops_and_conditionals() {
+        let template = Template::parse(
+            "Repo: {{repo}}{{#if part}} (part {{part}}){{/if}}\n\
+             {{#each files}}\n\
+             <{{path}}{{#if tokens}} tokens={{tokens}}{{else}} empty{{/if}}>\n\
+             {{content}}\n\
+             {{/each}}\n\
+             {{#unless part}}done{{/unless}}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&context()),
+            "Repo: owner-repo\n<a.rs tokens=3>\ncontent of a.rs\n<b.md empty>\ncontent of b.md\ndone\n"
+        );
+
+        let file = &context().files[0];
+        assert_eq!(
+            template.render_file(&context(), file),
+            "<a.rs tokens=3>\ncontent of a.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Template::parse("no files").is_err());
+        assert!(Template::parse("{{#each files}}{{nope}}{{/each}}").is_err());
+        assert!(Template::parse("{{path}}{{#each files}}{{/each}}").is_err());
+        assert!(Template::parse("{{#each files}}{{#if path}}{{/each}}").is_err());
+        assert!(Template::parse("{{#each files}}{{/each}").is_err());
+        assert!(Template::parse("{{#each repos}}{{/each}}").is_err());
+    }
+
+    #[test]
+    fn test_extension_for() {
+        assert_eq!(extension_for(Path::new("prompt.xml")), "xml");
+        assert_eq!(extension_for(Path::new("prompt.md.tmpl")), "md");
+        assert_eq!(extension_for(Path::new("prompt.tmpl")), "txt");
+        assert_eq!(extension_for(Path::new("prompt")), "txt");
+    }
+}
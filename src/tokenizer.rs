@@ -0,0 +1,143 @@
+// src/tokenizer.rs
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tiktoken_rs::CoreBPE;
+
+/// Counts how many tokens a piece of text costs in a model's context window.
+pub trait Tokenizer: Send + Sync {
+    /// Short name used in reports and output headers (e.g. "cl100k").
+    fn name(&self) -> &str;
+
+    /// Number of tokens `text` encodes to.
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Cheap estimator assuming roughly four characters per token.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CharEstimator;
+
+impl Tokenizer for CharEstimator {
+    fn name(&self) -> &str {
+        "chars/4"
+    }
+
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// Byte-pair encoding tokenizer backed by the bundled tiktoken vocabularies.
+pub struct BpeTokenizer {
+    name: &'static str,
+    bpe: &'static CoreBPE,
+}
+
+impl BpeTokenizer {
+    /// The `cl100k_base` encoding (GPT-3.5/GPT-4 family).
+    pub fn cl100k() -> Self {
+        Self {
+            name: "cl100k",
+            bpe: tiktoken_rs::cl100k_base_singleton(),
+        }
+    }
+
+    /// The `o200k_base` encoding (GPT-4o family).
+    pub fn o200k() -> Self {
+        Self {
+            name: "o200k",
+            bpe: tiktoken_rs::o200k_base_singleton(),
+        }
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn count(&self, text: &str) -> usize {
+        // Special tokens such as "<|endoftext|>" are counted as plain text
+        self.bpe.encode_ordinary(text).len()
+    }
+}
+
+/// The built-in tokenizers selectable from the CLI and configuration file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenizerKind {
+    #[default]
+    Cl100k,
+    O200k,
+    Chars,
+}
+
+impl TokenizerKind {
+    /// Instantiate the tokenizer. BPE vocabularies are loaded once per process.
+    pub fn build(self) -> Arc<dyn Tokenizer> {
+        match self {
+            TokenizerKind::Cl100k => Arc::new(BpeTokenizer::cl100k()),
+            TokenizerKind::O200k => Arc::new(BpeTokenizer::o200k()),
+            TokenizerKind::Chars => Arc::new(CharEstimator),
+        }
+    }
+}
+
+impl FromStr for TokenizerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cl100k" | "cl100k_base" => Ok(TokenizerKind::Cl100k),
+            "o200k" | "o200k_base" => Ok(TokenizerKind::O200k),
+            "chars" => Ok(TokenizerKind::Chars),
+            other => Err(format!(
+                "Unknown tokenizer {:?} (expected cl100k, o200k or chars)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TokenizerKind::Cl100k => "cl100k",
+            TokenizerKind::O200k => "o200k",
+            TokenizerKind::Chars => "chars",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_estimator() {
+        assert_eq!(CharEstimator.count(""), 0);
+        assert_eq!(CharEstimator.count("abc"), 1);
+        assert_eq!(CharEstimator.count("abcd"), 1);
+        assert_eq!(CharEstimator.count("abcde"), 2);
+    }
+
+    #[test]
+    fn test_bpe_tokenizers() {
+        let cl100k = BpeTokenizer::cl100k();
+        assert_eq!(cl100k.count("hello world"), 2);
+        assert_eq!(cl100k.count("<|endoftext|>"), 7);
+
+        let o200k = BpeTokenizer::o200k();
+        assert_eq!(o200k.count("hello world"), 2);
+    }
+
+    #[test]
+    fn test_tokenizer_kind_from_str() {
+        assert_eq!("cl100k".parse(), Ok(TokenizerKind::Cl100k));
+        assert_eq!("O200K_BASE".parse(), Ok(TokenizerKind::O200k));
+        assert_eq!("chars".parse(), Ok(TokenizerKind::Chars));
+        assert!("gpt2".parse::<TokenizerKind>().is_err());
+    }
+}
@@ -1,7 +1,23 @@
 // tests/integration_tests.rs
-use git2prompt::{io_utils, processing, repository::Repository};
+use git2prompt::{
+    Document, DocumentStats, Error, Generator, PromptOptions,
+    auth::{Auth, Token},
+    bucket::{Bucket, DocumentItem, EntryKind},
+    cache::Cache,
+    format::{DocumentInfo, OutputFormat, RenderOptions},
+    git_utils::{self, ChangeScope, CloneOptions, LocalChanges, LocalDiff},
+    github::{GitHubClient, PrContent, PullRequestOptions},
+    io_utils::{self, OutputSink},
+    order::FileOrder,
+    processing::{self, FileFilter},
+    repository::Repository,
+    sink::{self, DirectorySink, DocumentSink, WriterSink},
+    template::Template,
+    tokenizer::{CharEstimator, Tokenizer, TokenizerKind},
+};
 use std::fs as stdfs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
 
 /// A helper struct that cleans up a file or directory when it goes out of scope.
@@ -10,8 +26,10 @@ struct TestCleanup {
 }
 
 impl TestCleanup {
-    fn new(path: &PathBuf) -> Self {
-        Self { path: path.clone() }
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
     }
 }
 
@@ -22,17 +40,17 @@ impl Drop for TestCleanup {
                 if let Err(e) = stdfs::remove_dir_all(&self.path) {
                     eprintln!("Failed to clean up test directory {:?}: {}", self.path, e);
                 }
-            } else if self.path.is_file() {
-                if let Err(e) = stdfs::remove_file(&self.path) {
-                    eprintln!("Failed to clean up test file {:?}: {}", self.path, e);
-                }
+            } else if self.path.is_file()
+                && let Err(e) = stdfs::remove_file(&self.path)
+            {
+                eprintln!("Failed to clean up test file {:?}: {}", self.path, e);
             }
         }
     }
 }
 
 /// Helper to create a dummy repository
-async fn setup_dummy_repo(path: &PathBuf) -> std::io::Result<()> {
+async fn setup_dummy_repo(path: &Path) -> std::io::Result<()> {
     fs::create_dir_all(path.join("src")).await?;
     fs::write(
         path.join("src/main.rs"),
@@ -43,26 +61,46 @@ async fn setup_dummy_repo(path: &PathBuf) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Render a bucket as Markdown, the way it is written to an output file
+fn render_markdown(bucket: &Bucket, no_headers: bool, merged: bool) -> String {
+    let repository = merged.then_some("test-repo");
+    let items: Vec<DocumentItem> = bucket
+        .files
+        .iter()
+        .map(|file| DocumentItem::new(repository, file.clone()))
+        .collect();
+    let options = RenderOptions {
+        no_headers,
+        ..Default::default()
+    };
+    let info = DocumentInfo {
+        title: "Test",
+        repository: (!merged).then_some("test-repo"),
+        bucket: None,
+        commits: &[],
+        part: None,
+    };
+    OutputFormat::Markdown.render(&info, &items, &options, &CharEstimator)
+}
+
 #[tokio::test]
-async fn test_process_repository_files() -> Result<(), Box<dyn std::error::Error>> {
+async fn test_collect_repository_files() -> Result<(), Box<dyn std::error::Error>> {
     let test_repo_path = PathBuf::from("test_temp_repo");
     let _cleanup = TestCleanup::new(&test_repo_path);
     setup_dummy_repo(&test_repo_path).await?;
 
-    use processing::process_repository_files;
+    use processing::collect_repository_files;
 
     let src_main_path = PathBuf::from("src").join("main.rs");
     let readme_path = PathBuf::from("README.md");
 
-    // Case 1: With headers
-    let buckets_with_headers =
-        process_repository_files(&test_repo_path, false, false, None, None, None)
-            .await
-            .unwrap();
+    let buckets = collect_repository_files(&test_repo_path, None, None, None, None, &CharEstimator)
+        .await
+        .unwrap();
+    let bucket = buckets.get("default").expect("Default bucket missing");
 
-    let content_with_headers = buckets_with_headers
-        .get("default")
-        .expect("Default bucket missing");
+    // Case 1: With headers
+    let content_with_headers = render_markdown(bucket, false, false);
 
     assert!(content_with_headers.contains(&format!("## File: {}", src_main_path.display())));
     assert!(content_with_headers.contains("fn main() { println!(\"Hello\"); }"));
@@ -76,14 +114,7 @@ async fn test_process_repository_files() -> Result<(), Box<dyn std::error::Error
     );
 
     // Case 2: No headers
-    let buckets_no_headers =
-        process_repository_files(&test_repo_path, true, false, None, None, None)
-            .await
-            .unwrap();
-
-    let content_no_headers = buckets_no_headers
-        .get("default")
-        .expect("Default bucket missing");
+    let content_no_headers = render_markdown(bucket, true, false);
 
     assert!(!content_no_headers.contains(&format!("## File: {}", src_main_path.display())));
     assert!(content_no_headers.contains("fn main() { println!(\"Hello\"); }"));
@@ -96,13 +127,7 @@ async fn test_process_repository_files() -> Result<(), Box<dyn std::error::Error
     );
 
     // Case 3: Merged files (Header check changes from ## to ###)
-    let buckets_merged = process_repository_files(&test_repo_path, false, true, None, None, None)
-        .await
-        .unwrap();
-
-    let content_merged = buckets_merged
-        .get("default")
-        .expect("Default bucket missing");
+    let content_merged = render_markdown(bucket, false, true);
 
     assert!(content_merged.contains(&format!("### File: {}", src_main_path.display())));
     assert!(content_merged.contains("fn main() { println!(\"Hello\"); }"));
@@ -116,6 +141,203 @@ async fn test_process_repository_files() -> Result<(), Box<dyn std::error::Error
 }
 
 #[tokio::test]
+async fn test_parallel_walk_matches_sequential() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_parallel_walk");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    setup_dummy_repo(&test_repo_path).await?;
+    for d in ["b", "a", "a/z", "c"] {
+        fs::create_dir_all(test_repo_path.join(d)).await?;
+        for f in ["2.rs", "1.rs", "10.rs"] {
+            fs::write(test_repo_path.join(d).join(f), format!("// {}/{}", d, f)).await?;
+        }
+    }
+
+    let sequential = processing::read_repository_files(
+        &test_repo_path,
+        None,
+        None,
+        None,
+        &CharEstimator,
+        true,
+        1,
+    )?;
+    let parallel = processing::read_repository_files(
+        &test_repo_path,
+        None,
+        None,
+        None,
+        &CharEstimator,
+        true,
+        4,
+    )?;
+    let paths = |files: &[git2prompt::bucket::FileEntry]| {
+        files.iter().map(|f| f.path.clone()).collect::<Vec<_>>()
+    };
+
+    // Both walks read the same files, sorted by path
+    assert_eq!(sequential.len(), 14);
+    assert_eq!(paths(&sequential), paths(&parallel));
+    let mut sorted = paths(&sequential);
+    sorted.sort();
+    assert_eq!(paths(&sequential), sorted);
+    assert_eq!(sequential[0].path, PathBuf::from("README.md"));
+    assert_eq!(sequential[1].path, PathBuf::from("a").join("1.rs"));
+    assert_eq!(sequential[4].path, PathBuf::from("a/z").join("1.rs"));
+    for (a, b) in sequential.iter().zip(&parallel) {
+        assert_eq!(a.content, b.content);
+        assert_eq!(a.tokens, b.tokens);
+    }
+
+    // Without keeping the content, the parallel walk holds only paths and token counts
+    let unloaded = processing::read_repository_files(
+        &test_repo_path,
+        None,
+        None,
+        None,
+        &CharEstimator,
+        false,
+        0,
+    )?;
+    assert_eq!(paths(&unloaded), paths(&sequential));
+    for (a, b) in unloaded.iter().zip(&sequential) {
+        assert!(a.content.is_empty());
+        assert_eq!(a.source, Some(test_repo_path.join(&a.path)));
+        assert_eq!(a.tokens, b.tokens);
+    }
+
+    // Reading concurrently while streaming keeps the order of the blocks
+    let buckets =
+        processing::list_repository_files(&test_repo_path, None, None, None, None, &CharEstimator)
+            .await?;
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+    let repositories = [repository];
+    let options = RenderOptions::default();
+    let rendered =
+        processing::render_results(&repositories, false, &CharEstimator, &options).await?;
+    let mut sink = WriterSink::new(Vec::new(), None);
+    processing::stream_results(&repositories, false, &mut sink, &CharEstimator, &options).await?;
+    assert_eq!(String::from_utf8(sink.into_inner())?, rendered[0].content);
+    assert_eq!(
+        rendered[0].files,
+        paths(&sequential)
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+/// A rendered document holding `content`, named `name`
+fn document(name: &str, content: &str) -> Document {
+    Document {
+        name: name.to_string(),
+        repository: None,
+        bucket: None,
+        part: None,
+        content: content.to_string(),
+        files: Vec::new(),
+        stats: DocumentStats::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_directory_sink() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dir = PathBuf::from("test_temp_dir");
+    let _cleanup = TestCleanup::new(&test_dir);
+
+    // The directory is created on the first document
+    let output_dir = test_dir.join("output");
+    let documents = [
+        document("test_output.txt", "This is a test content."),
+        document("second.txt", "More content."),
+    ];
+    let paths =
+        sink::write_documents(&mut DirectorySink::new(output_dir.clone()), &documents).await?;
+    assert_eq!(
+        paths,
+        [
+            output_dir.join("test_output.txt"),
+            output_dir.join("second.txt")
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(&paths[0]).await?,
+        "This is a test content."
+    );
+    assert_eq!(fs::read_to_string(&paths[1]).await?, "More content.");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stdout_sink() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_stdout_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    setup_dummy_repo(&test_repo_path).await?;
+
+    // Splitting "src" off yields two documents, which stdout only takes with a separator
+    let split = vec!["src".to_string()];
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(
+        processing::collect_repository_files(
+            &test_repo_path,
+            None,
+            None,
+            Some(&split),
+            None,
+            &CharEstimator,
+        )
+        .await?,
+    );
+    let Err(error) = processing::handle_results(
+        &[repository],
+        false,
+        &OutputSink::Stdout { separator: None },
+        &CharEstimator,
+        &RenderOptions::default(),
+    )
+    .await
+    else {
+        panic!("several documents should not be written to stdout without a separator");
+    };
+    assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+    let message = error.to_string();
+    assert!(message.contains("2 output documents"), "{}", message);
+    assert!(
+        message.contains("test_stdout_repo_src_processed.md"),
+        "{}",
+        message
+    );
+
+    // Each document is preceded by the separator, which starts on a line of its own
+    let documents = [document("a.md", "first"), document("b.md", "second\n")];
+    let mut sink = WriterSink::new(Vec::new(), Some("=== {name} ===".to_string()));
+    assert!(
+        sink::write_documents(&mut sink, &documents)
+            .await?
+            .is_empty()
+    );
+    assert_eq!(
+        String::from_utf8(sink.into_inner())?,
+        "=== a.md ===\nfirst\n=== b.md ===\nsecond\n"
+    );
+
+    let mut sink = WriterSink::new(Vec::new(), None);
+    sink::write_documents(&mut sink, &documents[..1]).await?;
+    assert_eq!(String::from_utf8(sink.into_inner())?, "first");
+
+    // Without a separator nothing is written unless there is a single document
+    let mut sink = WriterSink::new(Vec::new(), None);
+    assert!(sink::write_documents(&mut sink, &documents).await.is_err());
+    assert!(sink.into_inner().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[allow(deprecated)]
 async fn test_write_content_to_file() -> Result<(), Box<dyn std::error::Error>> {
     let test_dir = PathBuf::from("test_temp_dir");
     let _cleanup = TestCleanup::new(&test_dir);
@@ -131,6 +353,28 @@ async fn test_write_content_to_file() -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_deprecated_process_repository_files() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_deprecated_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    setup_dummy_repo(&test_repo_path).await?;
+
+    let content =
+        processing::process_repository_files(&test_repo_path, false, false, None, None, None)
+            .await?;
+    assert!(content["default"].contains("## File: src/main.rs\n```rust\n"));
+    let merged =
+        processing::process_repository_files(&test_repo_path, true, true, None, None, None).await?;
+    assert!(merged["default"].starts_with("## Repository: test_deprecated_repo\n"));
+    assert!(!merged["default"].contains("File:"));
+
+    io_utils::ensure_directories(&test_repo_path.join("a"), &test_repo_path.join("b")).await?;
+    assert!(test_repo_path.join("a").is_dir() && test_repo_path.join("b").is_dir());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_ignore_patterns_file_based() -> Result<(), Box<dyn std::error::Error>> {
     // Tests the ignore crate integration using a real ignore file
@@ -159,17 +403,17 @@ async fn test_ignore_patterns_file_based() -> Result<(), Box<dyn std::error::Err
     let abs_ignore_path = current_dir.join(&ignore_path);
 
     // Pass the ignore file path to the processor
-    let buckets = processing::process_repository_files(
+    let buckets = processing::collect_repository_files(
         &abs_repo_path,
-        true,
-        true,
         Some(&abs_ignore_path),
         None,
         None,
+        None,
+        &CharEstimator,
     )
     .await?;
 
-    let content = buckets.get("default").unwrap();
+    let content = render_markdown(buckets.get("default").unwrap(), true, true);
 
     // Verify files were ignored using strict header check (safest) OR content check
     // "secret.txt" content is "This is a secret."
@@ -216,21 +460,36 @@ async fn test_git_ignore_advanced_syntax() -> Result<(), Box<dyn std::error::Err
     let ignore_content = "*.log\n*.swp";
     fs::write(test_repo_path.join(".git2promptignore"), ignore_content).await?;
 
-    let buckets = processing::process_repository_files(
+    let buckets = processing::collect_repository_files(
         &test_repo_path,
-        true,
-        false,
         None, // No custom file, rely on .git2promptignore discovery
         None,
         None,
+        None,
+        &CharEstimator,
     )
     .await?;
 
-    let content = buckets.get("default").unwrap();
+    let paths: Vec<&Path> = buckets["default"]
+        .files
+        .iter()
+        .map(|file| file.path.as_path())
+        .collect();
 
-    assert!(content.contains("keep"), "Should contain keep.rs");
-    assert!(!content.contains("log"), "Should not contain .log file");
-    assert!(!content.contains("swp"), "Should not contain .swp file");
+    assert!(
+        paths.contains(&Path::new("keep.rs")),
+        "Should contain keep.rs"
+    );
+    assert!(
+        !paths.contains(&Path::new("ignore.log")),
+        "Should not contain .log file"
+    );
+    assert!(
+        !paths.contains(&Path::new("temp.swp")),
+        "Should not contain .swp file"
+    );
+    // The ignore file itself is content like any other
+    assert!(paths.contains(&Path::new(".git2promptignore")));
 
     Ok(())
 }
@@ -257,25 +516,33 @@ async fn test_split_folders() -> Result<(), Box<dyn std::error::Error>> {
 
     let split_folders = vec!["docs".to_string()];
 
-    let buckets = processing::process_repository_files(
+    let buckets = processing::collect_repository_files(
         &test_repo_path,
-        true,
-        false,
+        None,
         None,
         Some(&split_folders),
         None,
+        &CharEstimator,
     )
     .await?;
 
     // Check "default" bucket
-    let default_content = buckets.get("default").expect("Default bucket missing");
+    let default_content = render_markdown(
+        buckets.get("default").expect("Default bucket missing"),
+        true,
+        false,
+    );
     assert!(default_content.contains("fn main() {}"));
     assert!(default_content.contains("# Root"));
     assert!(!default_content.contains("Documentation")); // Should be moved
     assert!(!default_content.contains("Deep Docs")); // Should be moved
 
     // Check "docs" bucket
-    let docs_content = buckets.get("docs").expect("Docs bucket missing");
+    let docs_content = render_markdown(
+        buckets.get("docs").expect("Docs bucket missing"),
+        true,
+        false,
+    );
     assert!(docs_content.contains("Documentation"));
     assert!(docs_content.contains("Deep Docs")); // Recursive split check
     assert!(!docs_content.contains("fn main() {}"));
@@ -301,3 +568,1955 @@ async fn test_local_repo_name_resolution() -> Result<(), Box<dyn std::error::Err
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_config_ignore_patterns() -> Result<(), Box<dyn std::error::Error>> {
+    // Patterns coming only from .git2promptconfig must exclude files on their own
+    let test_repo_path = PathBuf::from("test_config_patterns_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+
+    fs::create_dir_all(test_repo_path.join("node_modules/pkg")).await?;
+    fs::create_dir_all(test_repo_path.join("src")).await?;
+    fs::write(test_repo_path.join("node_modules/pkg/index.js"), "vendored").await?;
+    fs::write(test_repo_path.join("src/main.rs"), "fn main() {}").await?;
+    fs::write(test_repo_path.join("debug.log"), "noisy log line").await?;
+    fs::write(test_repo_path.join("keep.log"), "important log line").await?;
+
+    let config_path = test_repo_path.join("test.git2promptconfig");
+    fs::write(
+        &config_path,
+        "ignore_patterns = [\"node_modules/\", \"*.log\", \"!keep.log\", \"*.git2promptconfig\"]",
+    )
+    .await?;
+    let config = git2prompt::config::Config::load_from_file(&config_path).await?;
+
+    let buckets = processing::collect_repository_files(
+        &test_repo_path,
+        None,
+        config.ignore_patterns.as_deref(),
+        None,
+        None,
+        &CharEstimator,
+    )
+    .await?;
+
+    let content = render_markdown(buckets.get("default").unwrap(), true, false);
+    assert!(content.contains("fn main() {}"));
+    assert!(
+        !content.contains("vendored"),
+        "node_modules/ should be ignored"
+    );
+    assert!(
+        !content.contains("noisy log line"),
+        "*.log should be ignored"
+    );
+    assert!(
+        content.contains("important log line"),
+        "Negations within the config patterns should re-include files"
+    );
+    assert!(
+        !content.contains("ignore_patterns"),
+        "Config file should be ignored"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_load_errors() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dir = PathBuf::from("test_config_errors");
+    let _cleanup = TestCleanup::new(&test_dir);
+    fs::create_dir_all(&test_dir).await?;
+
+    // A missing file is the default configuration
+    let config = git2prompt::config::Config::load_from_file(&test_dir.join("missing.toml")).await?;
+    assert!(config.ignore_patterns.is_none());
+
+    // A malformed file is an error, with the TOML error as its source
+    let config_path = test_dir.join("broken.toml");
+    fs::write(&config_path, "ignore_patterns = [\"unterminated").await?;
+    let error = git2prompt::config::Config::load_from_file(&config_path)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Config { .. }), "{:?}", error);
+    assert!(std::error::Error::source(&error).is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_generator_options() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dir = PathBuf::from("test_generator_options");
+    let _cleanup = TestCleanup::new(&test_dir);
+    let repo_path = test_dir.join("generator-repo");
+    let output_dir = test_dir.join("output");
+    setup_dummy_repo(&repo_path).await?;
+    fs::create_dir_all(repo_path.join("docs")).await?;
+    fs::write(repo_path.join("docs/guide.md"), "Guide").await?;
+    fs::write(repo_path.join("notes.log"), "noisy log line").await?;
+
+    // Configuration keys and per-run settings deserialize into the same options
+    let options: PromptOptions = toml::from_str(
+        "ignore_patterns = [\"*.log\"]\nsplit_folders = [\"docs\"]\nno_headers = true\n\
+         tokenizer = \"chars\"\nformat = \"xml\"\ndiff = \"main...feature\"\nchanges = \"staged\"",
+    )?;
+    assert_eq!(options.ignore_patterns, ["*.log"]);
+    assert_eq!(options.format, OutputFormat::Xml);
+    assert!(options.diff.as_ref().is_some_and(|diff| diff.merge_base));
+    assert_eq!(options.changes, Some(ChangeScope::Staged));
+    assert!(toml::from_str::<PromptOptions>("diff = \"main\"").is_err());
+
+    // A token in the options is never printed
+    let options: PromptOptions = toml::from_str("token = \" ghp_secret \"")?;
+    assert_eq!(
+        options.token.as_ref().map(Token::expose),
+        Some("ghp_secret")
+    );
+    assert!(!format!("{:?}", options).contains("ghp_secret"));
+    let options = PromptOptions::builder().token("ghp_secret").build();
+    assert!(!format!("{:?}", options).contains("ghp_secret"));
+    assert_eq!(
+        options.auth().config_token.as_ref().map(Token::expose),
+        Some("ghp_secret")
+    );
+
+    // The configuration file converts to the same options
+    let config: git2prompt::config::Config =
+        toml::from_str("ignore_patterns = [\"*.log\"]\nno_headers = true")?;
+    let from_config = PromptOptions::from(config);
+    assert_eq!(from_config.ignore_patterns, ["*.log"]);
+    assert!(from_config.no_headers);
+
+    // Neither does the configuration print its token
+    let config: git2prompt::config::Config = toml::from_str("token = \"ghp_secret\"")?;
+    assert!(!format!("{:?}", config).contains("ghp_secret"));
+    assert_eq!(
+        PromptOptions::from(config)
+            .token
+            .as_ref()
+            .map(Token::expose),
+        Some("ghp_secret")
+    );
+
+    let options = PromptOptions::builder()
+        .ignore_patterns(["*.log"])
+        .split_folders(["docs"])
+        .no_headers(true)
+        .tokenizer(TokenizerKind::Chars)
+        .output_dir(&output_dir)
+        .build();
+    let generator = Generator::new(options).await?;
+    let mut paths = generator.process_local(&repo_path).await?;
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            output_dir.join("generator-repo_docs_processed.md"),
+            output_dir.join("generator-repo_processed.md"),
+        ]
+    );
+    let content = fs::read_to_string(output_dir.join("generator-repo_processed.md")).await?;
+    assert!(content.contains("fn main()"));
+    assert!(!content.contains("## File:"), "Headers should be removed");
+    assert!(
+        !content.contains("noisy log line"),
+        "*.log should be ignored"
+    );
+    assert!(!content.contains("Guide"), "docs/ should be split out");
+
+    // Conflicting or unsupported combinations are rejected rather than ignored
+    let remote = ["owner/repo".to_string()];
+    for (builder, local) in [
+        (
+            PromptOptions::builder()
+                .rev("HEAD")
+                .changes(ChangeScope::Staged),
+            true,
+        ),
+        (PromptOptions::builder().pr(1), true),
+        (PromptOptions::builder().rev("HEAD"), false),
+        (
+            PromptOptions::builder()
+                .pr(1)
+                .diff("main..feature".parse::<git_utils::DiffRange>()?),
+            false,
+        ),
+    ] {
+        let generator = Generator::new(
+            builder
+                .tokenizer(TokenizerKind::Chars)
+                .no_cache(true)
+                .build(),
+        )
+        .await?;
+        let result = if local {
+            generator.generate_local(&repo_path).await
+        } else {
+            generator.generate_sources(&remote).await
+        };
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "{:?}",
+            result.map(|documents| documents.len())
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_generate_documents_in_memory() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dir = PathBuf::from("test_generate_in_memory");
+    let _cleanup = TestCleanup::new(&test_dir);
+    let repo_path = test_dir.join("memory-repo");
+    let output_dir = test_dir.join("output");
+    setup_dummy_repo(&repo_path).await?;
+
+    let options = PromptOptions::builder()
+        .split_folders(["src"])
+        .tokenizer(TokenizerKind::Chars)
+        .output_dir(&output_dir)
+        .build();
+    let generator = Generator::new(options).await?;
+    let mut documents = generator.generate_local(&repo_path).await?;
+    documents.sort_by(|a, b| a.name.cmp(&b.name));
+    assert!(!output_dir.exists(), "Nothing should be written");
+
+    assert_eq!(documents.len(), 2);
+    let (default, src) = (&documents[0], &documents[1]);
+    assert_eq!(src.name, "memory-repo_src_processed.md");
+    assert_eq!(src.repository.as_deref(), Some("memory-repo"));
+    assert_eq!(src.bucket.as_deref(), Some("src"));
+    assert_eq!(src.files, ["src/main.rs"]);
+    assert!(src.content.contains("println!(\"Hello\")"));
+    assert_eq!(src.stats.files, 1);
+    assert_eq!(
+        src.stats.tokens,
+        CharEstimator.count("fn main() { println!(\"Hello\"); }")
+    );
+    assert_eq!(src.stats.bytes, src.content.len());
+    assert_eq!(default.name, "memory-repo_processed.md");
+    assert_eq!(default.bucket, None);
+    assert_eq!(default.files, ["README.md"]);
+
+    // Writing to a directory is one way of consuming the same documents
+    let paths =
+        sink::write_documents(&mut DirectorySink::new(output_dir.clone()), &documents).await?;
+    assert_eq!(paths.len(), 2);
+    assert_eq!(fs::read_to_string(&paths[1]).await?, src.content);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_sinks() -> Result<(), Box<dyn std::error::Error>> {
+    let test_dir = PathBuf::from("test_streaming_sinks");
+    let _cleanup = TestCleanup::new(&test_dir);
+    let split = vec!["src".to_string()];
+    let mut repositories = Vec::new();
+    for name in ["stream-a", "stream-b"] {
+        let path = test_dir.join(name);
+        setup_dummy_repo(&path).await?;
+        let buckets = processing::list_repository_files(
+            &path,
+            None,
+            None,
+            Some(&split),
+            None,
+            &CharEstimator,
+        )
+        .await?;
+        // Only the paths and token counts are kept in memory
+        let files = &buckets["src"].files;
+        assert!(
+            files
+                .iter()
+                .all(|f| f.content.is_empty() && f.source.is_some())
+        );
+        assert!(files.iter().all(|f| f.tokens > 0));
+        let mut repository = Repository::from_local_path(&path);
+        repository.content = Some(buckets);
+        repositories.push(repository);
+    }
+
+    /// Records each chunk written to each document
+    #[derive(Default)]
+    struct Recorder {
+        documents: Vec<(String, Vec<String>)>,
+    }
+    impl DocumentSink for Recorder {
+        async fn begin(&mut self, name: &str) -> Result<(), Error> {
+            self.documents.push((name.to_string(), Vec::new()));
+            Ok(())
+        }
+        async fn write(&mut self, chunk: &str) -> Result<(), Error> {
+            self.documents.last_mut().unwrap().1.push(chunk.to_string());
+            Ok(())
+        }
+        async fn end(&mut self) -> Result<Option<PathBuf>, Error> {
+            Ok(None)
+        }
+    }
+
+    // Streaming writes the same documents as rendering in memory, one file block at a time
+    for format in [
+        OutputFormat::Markdown,
+        OutputFormat::Xml,
+        OutputFormat::Json,
+        OutputFormat::Jsonl,
+    ] {
+        let options = RenderOptions {
+            format,
+            token_header: true,
+            ..Default::default()
+        };
+        let expected =
+            processing::render_results(&repositories, true, &CharEstimator, &options).await?;
+        let mut recorder = Recorder::default();
+        processing::stream_results(&repositories, true, &mut recorder, &CharEstimator, &options)
+            .await?;
+        assert_eq!(recorder.documents.len(), expected.len());
+        for ((name, chunks), document) in recorder.documents.iter().zip(&expected) {
+            assert_eq!(name, &document.name);
+            assert_eq!(chunks.concat(), document.content, "{}", format);
+            // The header, one block per file and the footer
+            assert_eq!(chunks.len(), document.stats.files + 2);
+        }
+    }
+
+    // A writer of one's own receives the documents one after another
+    let mut sink = WriterSink::new(Vec::new(), Some("--- {name}".to_string()));
+    processing::stream_results(
+        &repositories,
+        true,
+        &mut sink,
+        &CharEstimator,
+        &RenderOptions::default(),
+    )
+    .await?;
+    let written = String::from_utf8(sink.into_inner())?;
+    assert!(
+        written.starts_with("--- all_repos_processed.md\n# Merged Repository Contents\n"),
+        "{}",
+        written
+    );
+    assert!(written.contains("--- all_repos_src_processed.md\n"));
+    assert!(written.contains("## Repository: stream-b\n"));
+
+    // Without a separator it only takes a single document, and nothing is written
+    let mut sink = WriterSink::new(Vec::new(), None);
+    let Err(error) = processing::stream_results(
+        &repositories,
+        true,
+        &mut sink,
+        &CharEstimator,
+        &RenderOptions::default(),
+    )
+    .await
+    else {
+        panic!("several documents should not be written without a separator");
+    };
+    assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+    assert!(sink.into_inner().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_changed_after_walk() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_changed_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    setup_dummy_repo(&test_repo_path).await?;
+
+    let buckets =
+        processing::list_repository_files(&test_repo_path, None, None, None, None, &CharEstimator)
+            .await?;
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+    let repositories = [repository];
+
+    // The file grows between the walk and the output
+    let changed = "fn main() { println!(\"Hello again, with more tokens\"); }";
+    fs::write(test_repo_path.join("src/main.rs"), changed).await?;
+
+    // The new content is written, with the token counts of the walk, which the header
+    // streamed before any file is read agrees with
+    let options = RenderOptions {
+        token_header: true,
+        ..Default::default()
+    };
+    let rendered =
+        processing::render_results(&repositories, false, &CharEstimator, &options).await?;
+    assert!(rendered[0].content.contains(changed));
+    assert_eq!(
+        rendered[0].stats.tokens,
+        CharEstimator.count("# Test Repo")
+            + CharEstimator.count("fn main() { println!(\"Hello\"); }")
+    );
+
+    let mut sink = WriterSink::new(Vec::new(), None);
+    processing::stream_results(&repositories, false, &mut sink, &CharEstimator, &options).await?;
+    assert_eq!(String::from_utf8(sink.into_inner())?, rendered[0].content);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_order() -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = PathBuf::from("test_file_order");
+    let _cleanup = TestCleanup::new(&repo_path);
+    let repo = git2::Repository::init(&repo_path)?;
+
+    // Commits dated explicitly, so that their order does not depend on timing
+    let commit_at = |files: &[(&str, &str)], time: i64| {
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            let full_path = repo_path.join(path);
+            stdfs::create_dir_all(full_path.parent().unwrap()).unwrap();
+            stdfs::write(&full_path, content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature =
+            git2::Signature::new("Test", "test@example.com", &git2::Time::new(time, 0)).unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "commit",
+            &tree,
+            &parents,
+        )
+        .unwrap();
+    };
+    commit_at(
+        &[
+            ("README.md", "# Ordered"),
+            ("src/a.rs", &"// a long file\n".repeat(20)),
+        ],
+        1_000,
+    );
+    commit_at(&[("docs/guide.md", "Guide")], 2_000);
+    commit_at(&[("src/b.rs", "// b")], 3_000);
+    let third = repo.head()?.peel_to_commit()?.id().to_string();
+    stdfs::write(repo_path.join("notes.txt"), "untracked")?;
+
+    let files = |order: FileOrder, priority: &[&str]| {
+        let options = PromptOptions::builder()
+            .split_folders(["src", "docs"])
+            .tokenizer(TokenizerKind::Chars)
+            .order(order)
+            .priority(priority.iter().copied())
+            .build();
+        let repo_path = repo_path.clone();
+        async move {
+            let generator = Generator::new(options).await.unwrap();
+            let documents = generator.generate_local(&repo_path).await.unwrap();
+            documents
+                .into_iter()
+                .map(|document| (document.name, document.files))
+                .collect::<Vec<_>>()
+        }
+    };
+
+    // Documents come in a fixed order: the default one, then split folders alphabetically
+    let by_path = files(FileOrder::Path, &[]).await;
+    assert_eq!(
+        by_path,
+        [
+            (
+                "test_file_order_processed.md".to_string(),
+                vec!["README.md".to_string(), "notes.txt".to_string()]
+            ),
+            (
+                "test_file_order_docs_processed.md".to_string(),
+                vec!["docs/guide.md".to_string()]
+            ),
+            (
+                "test_file_order_src_processed.md".to_string(),
+                vec!["src/a.rs".to_string(), "src/b.rs".to_string()]
+            ),
+        ]
+    );
+    assert_eq!(files(FileOrder::Path, &[]).await, by_path);
+
+    // Newest first, uncommitted files ahead of everything
+    let recent = files(FileOrder::Recent, &[]).await;
+    assert_eq!(recent[0].1, ["notes.txt", "README.md"]);
+    assert_eq!(recent[2].1, ["src/b.rs", "src/a.rs"]);
+
+    // Smallest first
+    let by_size = files(FileOrder::Size, &[]).await;
+    assert_eq!(by_size[2].1, ["src/b.rs", "src/a.rs"]);
+
+    // Priority patterns come first, in the order given
+    let prioritized = files(FileOrder::Recent, &["*.txt", "src/a.rs"]).await;
+    assert_eq!(prioritized[2].1, ["src/a.rs", "src/b.rs"]);
+    let prioritized = files(FileOrder::Path, &["notes.txt"]).await;
+    assert_eq!(prioritized[0].1, ["notes.txt", "README.md"]);
+
+    // A merge dates the files it brings in from the commit that changed them
+    let base = repo.head()?.peel_to_commit()?;
+    let side_tree = {
+        let mut index = repo.index()?;
+        stdfs::write(repo_path.join("src/a.rs"), "// a, changed on a branch")?;
+        index.add_path(Path::new("src/a.rs"))?;
+        index.write()?;
+        repo.find_tree(index.write_tree()?)?
+    };
+    let signature =
+        |time| git2::Signature::new("Test", "test@example.com", &git2::Time::new(time, 0));
+    let side = repo.commit(
+        None,
+        &signature(3_500)?,
+        &signature(3_500)?,
+        "side",
+        &side_tree,
+        &[&base],
+    )?;
+    let side = repo.find_commit(side)?;
+    repo.commit(
+        Some("HEAD"),
+        &signature(4_000)?,
+        &signature(4_000)?,
+        "merge",
+        &side_tree,
+        &[&base, &side],
+    )?;
+    let changed = git_utils::last_changed(
+        &repo_path,
+        None,
+        ["README.md", "src/a.rs", "notes.txt", "missing.rs"]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+    )
+    .await?;
+    assert_eq!(changed[Path::new("README.md")], 1_000);
+    assert_eq!(changed[Path::new("src/a.rs")], 3_500);
+    // Untracked files date from their modification time
+    assert!(changed[Path::new("notes.txt")] > 4_000);
+    assert!(!changed.contains_key(Path::new("missing.rs")));
+
+    // Files are dated as of the commit processed, not HEAD
+    let at_head = files(FileOrder::Recent, &[]).await;
+    assert_eq!(at_head[2].1, ["src/a.rs", "src/b.rs"]);
+    let generator = Generator::new(
+        PromptOptions::builder()
+            .split_folders(["src", "docs"])
+            .tokenizer(TokenizerKind::Chars)
+            .order(FileOrder::Recent)
+            .rev(&third)
+            .build(),
+    )
+    .await?;
+    let at_rev = generator.generate_local(&repo_path).await?;
+    assert_eq!(at_rev[2].files, ["src/b.rs", "src/a.rs"]);
+
+    // PR mode has no history to order by
+    let generator = Generator::new(
+        PromptOptions::builder()
+            .pr(1)
+            .order(FileOrder::Recent)
+            .no_cache(true)
+            .tokenizer(TokenizerKind::Chars)
+            .build(),
+    )
+    .await?;
+    let result = generator
+        .generate_sources(&["owner/repo".to_string()])
+        .await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))));
+
+    // The order can be configured as well
+    let config: git2prompt::config::Config = toml::from_str(
+        r#"
+        order = "docs-first"
+        priority = ["src/b.rs"]
+        "#,
+    )?;
+    let options = PromptOptions::from(config);
+    assert_eq!(options.order, FileOrder::DocsFirst);
+    assert_eq!(options.priority, ["src/b.rs"]);
+    assert!(toml::from_str::<git2prompt::config::Config>("order = \"random\"").is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_ignore_patterns_precedence() -> Result<(), Box<dyn std::error::Error>> {
+    // Config patterns are applied last: a negation in .git2promptignore cannot re-include them
+    let test_repo_path = PathBuf::from("test_config_precedence_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+
+    fs::create_dir_all(test_repo_path.join("docs")).await?;
+    fs::write(test_repo_path.join("docs/guide.txt"), "guide text").await?;
+    fs::write(test_repo_path.join("notes.txt"), "notes text").await?;
+    fs::write(
+        test_repo_path.join(".git2promptignore"),
+        "!docs/\n.git2promptignore",
+    )
+    .await?;
+
+    let patterns = vec!["docs/".to_string()];
+
+    // Restricting to the folder must still honour patterns anchored at the repository root
+    let buckets = processing::collect_repository_files(
+        &test_repo_path,
+        None,
+        Some(&patterns),
+        None,
+        Some("docs"),
+        &CharEstimator,
+    )
+    .await?;
+    assert!(!render_markdown(buckets.get("default").unwrap(), true, false).contains("guide text"));
+
+    let buckets = processing::collect_repository_files(
+        &test_repo_path,
+        None,
+        Some(&patterns),
+        None,
+        None,
+        &CharEstimator,
+    )
+    .await?;
+    let content = render_markdown(buckets.get("default").unwrap(), true, false);
+    assert!(!content.contains("guide text"));
+    assert!(content.contains("notes text"));
+
+    // A folder outside the repository is rejected rather than walked
+    let outside = std::env::current_dir()?.join(&test_repo_path).join("docs");
+    for folder in [
+        "../test_config_precedence_repo/docs",
+        outside.to_str().unwrap(),
+    ] {
+        let result = processing::collect_repository_files(
+            &test_repo_path,
+            None,
+            Some(&patterns),
+            None,
+            Some(folder),
+            &CharEstimator,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "{folder} was accepted"
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_token_counts_and_header() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_token_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    setup_dummy_repo(&test_repo_path).await?;
+
+    let output_dir = PathBuf::from("test_token_output");
+    let _output_cleanup = TestCleanup::new(&output_dir);
+    fs::create_dir_all(&output_dir).await?;
+
+    let tokenizer = CharEstimator;
+    let buckets =
+        processing::collect_repository_files(&test_repo_path, None, None, None, None, &tokenizer)
+            .await?;
+
+    // Every file carries the token count of its content
+    let bucket = buckets.get("default").unwrap();
+    assert_eq!(bucket.files.len(), 2);
+    for file in &bucket.files {
+        assert!(file.tokens > 0);
+        assert_eq!(file.tokens, file.content.chars().count().div_ceil(4));
+    }
+    let total_tokens = bucket.total_tokens();
+
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+    let repositories = vec![repository];
+
+    let paths = processing::handle_results(
+        &repositories,
+        false,
+        &OutputSink::Directory(output_dir.clone()),
+        &tokenizer,
+        &RenderOptions {
+            token_header: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(paths.len(), 1);
+    let written = fs::read_to_string(&paths[0]).await?;
+    assert!(written.contains(&format!("Total tokens: {} (chars/4)", total_tokens)));
+    assert!(written.contains("- README.md: "));
+
+    let report = processing::token_report(&repositories, &tokenizer);
+    assert!(report.contains(&format!("test_token_repo: {} tokens", total_tokens)));
+    assert!(report.contains(&format!("Total: {} tokens", total_tokens)));
+    assert!(!report.contains("README.md"));
+    let details = processing::token_details(&repositories);
+    assert!(details.contains("[default]"));
+    assert!(details.contains("- README.md: "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_tokens_chunking() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_chunk_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    let output_dir = PathBuf::from("test_chunk_output");
+    let _output_cleanup = TestCleanup::new(&output_dir);
+    fs::create_dir_all(&test_repo_path).await?;
+    fs::create_dir_all(&output_dir).await?;
+
+    // Three small files and one that cannot fit in a single part on its own
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(test_repo_path.join(name), format!("{} ", name).repeat(20)).await?;
+    }
+    let big: String = (0..200).map(|i| format!("big line {:03}\n", i)).collect();
+    fs::write(test_repo_path.join("d_big.txt"), &big).await?;
+
+    let tokenizer = CharEstimator;
+    let buckets =
+        processing::collect_repository_files(&test_repo_path, None, None, None, None, &tokenizer)
+            .await?;
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+
+    let budget = 300;
+    let paths = processing::handle_results(
+        &[repository],
+        false,
+        &OutputSink::Directory(output_dir.clone()),
+        &tokenizer,
+        &RenderOptions {
+            max_tokens: Some(budget),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let count = paths.len();
+    assert!(count > 2, "Expected several parts, got {}", count);
+    let mut all_content = String::new();
+    for (i, path) in paths.iter().enumerate() {
+        assert_eq!(
+            path.file_name().unwrap().to_string_lossy(),
+            format!("test_chunk_repo_part{}_processed.md", i + 1)
+        );
+        let content = fs::read_to_string(path).await?;
+        assert!(
+            tokenizer.count(&content) <= budget,
+            "Part {} over budget",
+            i + 1
+        );
+        assert!(content.starts_with(&format!(
+            "# Repository: test_chunk_repo (part {} of {})\n",
+            i + 1,
+            count
+        )));
+        assert!(content.contains("Files in this part:"));
+        all_content.push_str(&content);
+    }
+
+    // Small files are never split, the big one is split into labelled segments
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        assert_eq!(
+            all_content.matches(&format!("## File: {}\n", name)).count(),
+            1
+        );
+    }
+    assert!(all_content.contains("- d_big.txt (segment 1 of "));
+    for i in 0..200 {
+        assert!(all_content.contains(&format!("big line {:03}\n", i)));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_formats_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    let output_dir = PathBuf::from("test_formats_output");
+    let _output_cleanup = TestCleanup::new(&output_dir);
+    setup_dummy_repo(&test_repo_path).await?;
+    fs::write(
+        test_repo_path.join("generic.rs"),
+        "fn id<T>(t: T) -> T { t }",
+    )
+    .await?;
+    fs::create_dir_all(&output_dir).await?;
+
+    let tokenizer = CharEstimator;
+    let buckets =
+        processing::collect_repository_files(&test_repo_path, None, None, None, None, &tokenizer)
+            .await?;
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+    let repositories = [repository];
+    let sink = OutputSink::Directory(output_dir.clone());
+
+    let write = |format: OutputFormat| {
+        let repositories = &repositories;
+        let sink = &sink;
+        async move {
+            let options = RenderOptions {
+                format,
+                ..Default::default()
+            };
+            let paths =
+                processing::handle_results(repositories, false, sink, &CharEstimator, &options)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            assert_eq!(paths.len(), 1);
+            assert_eq!(
+                paths[0].file_name().unwrap().to_string_lossy(),
+                format!("test_formats_repo_processed.{}", format.extension())
+            );
+            fs::read_to_string(&paths[0])
+                .await
+                .map_err(|e| e.to_string())
+        }
+    };
+
+    // XML: one <document> per file, content escaped, markdown left untouched
+    let xml = write(OutputFormat::Xml).await?;
+    assert!(xml.starts_with("<documents title=\"Repository: test_formats_repo\">\n"));
+    assert!(xml.ends_with("</documents>\n"));
+    assert_eq!(xml.matches("<document index=").count(), 3);
+    assert!(xml.contains("<source>README.md</source>\n<document_content>\n# Test Repo\n"));
+    assert!(xml.contains("fn id&lt;T&gt;(t: T) -&gt; T { t }"));
+
+    // JSON: an array of {path, language, content, tokens}
+    let json: serde_json::Value = serde_json::from_str(&write(OutputFormat::Json).await?)?;
+    let files = json.as_array().expect("JSON output should be an array");
+    assert_eq!(files.len(), 3);
+    let main = files
+        .iter()
+        .find(|f| f["path"].as_str().unwrap().ends_with("main.rs"))
+        .unwrap();
+    assert_eq!(main["language"], "rust");
+    assert_eq!(main["content"], "fn main() { println!(\"Hello\"); }");
+    assert_eq!(main["tokens"], 8);
+
+    // JSONL: one object per line
+    let jsonl = write(OutputFormat::Jsonl).await?;
+    let lines: Vec<serde_json::Value> = jsonl
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(lines.len(), 3);
+    assert!(lines.iter().any(|l| l["content"] == "# Test Repo"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_template() -> Result<(), Box<dyn std::error::Error>> {
+    let test_repo_path = PathBuf::from("test_template_repo");
+    let _cleanup = TestCleanup::new(&test_repo_path);
+    let output_dir = PathBuf::from("test_template_output");
+    let _output_cleanup = TestCleanup::new(&output_dir);
+    setup_dummy_repo(&test_repo_path).await?;
+    fs::create_dir_all(&output_dir).await?;
+
+    let template_path = output_dir.join("prompt.md.tmpl");
+    fs::write(
+        &template_path,
+        "Preamble for {{repo}}{{#if part}} (part {{part}}){{/if}}: {{file_count}} files\n\
+         {{#each files}}\n\
+         <file index=\"{{index}}\" path=\"{{path}}\" language=\"{{language}}\" tokens=\"{{tokens}}\">\n\
+         {{content}}\n\
+         </file>\n\
+         {{/each}}\n\
+         Footer: {{total_tokens}} tokens\n",
+    )
+    .await?;
+    let template = Template::load(&template_path).await?;
+    assert_eq!(template.extension(), "md");
+
+    let tokenizer = CharEstimator;
+    let buckets =
+        processing::collect_repository_files(&test_repo_path, None, None, None, None, &tokenizer)
+            .await?;
+    let mut repository = Repository::from_local_path(&test_repo_path);
+    repository.content = Some(buckets);
+
+    let options = RenderOptions {
+        template: Some(Arc::new(template)),
+        ..Default::default()
+    };
+    let sink = OutputSink::Directory(output_dir.clone());
+    let paths =
+        processing::handle_results(&[repository], false, &sink, &tokenizer, &options).await?;
+    assert_eq!(paths.len(), 1);
+    assert_eq!(
+        paths[0].file_name().unwrap().to_string_lossy(),
+        "test_template_repo_processed.md"
+    );
+
+    let content = fs::read_to_string(&paths[0]).await?;
+    assert!(content.starts_with("Preamble for test_template_repo: 2 files\n<file index=\"1\" "));
+    // Content is inserted unchanged: no fences, no header demotion
+    assert!(content.contains("language=\"markdown\" tokens=\"3\">\n# Test Repo\n</file>\n"));
+    assert!(content.contains(
+        "language=\"rust\" tokens=\"8\">\nfn main() { println!(\"Hello\"); }\n</file>\n"
+    ));
+    assert!(content.ends_with("</file>\nFooter: 11 tokens\n"));
+    assert!(!content.contains("```"));
+
+    // Invalid templates are rejected when loaded
+    fs::write(&template_path, "{{#each files}}{{pth}}{{/each}}").await?;
+    assert!(Template::load(&template_path).await.is_err());
+
+    Ok(())
+}
+
+/// Write files into a repository's working tree and commit them on HEAD
+fn commit_files(repo: &git2::Repository, files: &[(&str, &str)], message: &str) -> git2::Oid {
+    let workdir = repo.workdir().unwrap().to_path_buf();
+    let mut index = repo.index().unwrap();
+    for (path, content) in files {
+        let full_path = workdir.join(path);
+        stdfs::create_dir_all(full_path.parent().unwrap()).unwrap();
+        stdfs::write(&full_path, content).unwrap();
+        index.add_path(Path::new(path)).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+    let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_clone_at_ref() -> Result<(), Box<dyn std::error::Error>> {
+    let origin_path = PathBuf::from("test_ref_origin");
+    let _cleanup = TestCleanup::new(&origin_path);
+    let clone_dir = PathBuf::from("test_ref_clones");
+    let _clone_cleanup = TestCleanup::new(&clone_dir);
+
+    // v1.0 tag, a later commit on the default branch, and a "feature/x" branch
+    let origin = git2::Repository::init(&origin_path)?;
+    let v1 = commit_files(&origin, &[("lib.rs", "// version 1")], "v1");
+    origin.tag_lightweight("v1.0", &origin.find_object(v1, None)?, false)?;
+    let v2 = commit_files(&origin, &[("lib.rs", "// version 2")], "v2");
+    let default_branch = origin.head()?.name().unwrap().to_string();
+    origin.branch("feature/x", &origin.find_commit(v1)?, false)?;
+    origin.set_head("refs/heads/feature/x")?;
+    origin.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+    let feature = commit_files(&origin, &[("feature.rs", "// feature")], "feature");
+    origin.set_head(&default_branch)?;
+
+    let url = origin_path.canonicalize()?.to_string_lossy().to_string();
+    let clone_at = |git_ref: Option<&str>| {
+        let mut repository = Repository::new(&clone_dir, "owner/repo", git_ref).unwrap();
+        repository.url = url.clone();
+        repository
+    };
+
+    for (git_ref, expected_commit, expected_content) in [
+        (None, v2, "// version 2"),
+        (Some("v1.0"), v1, "// version 1"),
+        (Some("feature/x"), feature, "// version 1"),
+        (Some(&v2.to_string()[..10]), v2, "// version 2"),
+    ] {
+        let repository = clone_at(git_ref);
+        let repo = git_utils::clone_repository(&repository, &CloneOptions::default()).await?;
+        assert_eq!(
+            git_utils::head_commit(&repo)?,
+            expected_commit.to_string(),
+            "ref {:?}",
+            git_ref
+        );
+        let content = fs::read_to_string(repository.path.join("lib.rs")).await?;
+        assert_eq!(content, expected_content, "ref {:?}", git_ref);
+    }
+
+    let missing = clone_at(Some("no-such-ref"));
+    let Err(error) = git_utils::clone_repository(&missing, &CloneOptions::default()).await else {
+        panic!("cloning at a missing ref should fail");
+    };
+    assert!(matches!(error, Error::Git { .. }), "{:?}", error);
+    assert!(error.to_string().contains("no-such-ref"), "{}", error);
+
+    // The resolved commit is written into the output header
+    let mut repository = clone_at(Some("v1.0"));
+    git_utils::clone_repository(&repository, &CloneOptions::default()).await?;
+    repository.commit = Some(v1.to_string());
+    repository.content = Some(
+        processing::collect_repository_files(
+            &repository.path,
+            None,
+            None,
+            None,
+            None,
+            &CharEstimator,
+        )
+        .await?,
+    );
+    let output_dir = clone_dir.join("output");
+    fs::create_dir_all(&output_dir).await?;
+    let paths = processing::handle_results(
+        &[repository],
+        false,
+        &OutputSink::Directory(output_dir.clone()),
+        &CharEstimator,
+        &RenderOptions::default(),
+    )
+    .await?;
+    assert_eq!(
+        paths[0].file_name().unwrap().to_string_lossy(),
+        "owner-repo@v1.0_processed.md"
+    );
+    let output = fs::read_to_string(&paths[0]).await?;
+    assert!(output.starts_with(&format!(
+        "# Repository: owner-repo@v1.0\nCommit: {}\n\n",
+        v1
+    )));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clone_cache_updates_in_place() -> Result<(), Box<dyn std::error::Error>> {
+    let origin_path = PathBuf::from("test_cache_origin");
+    let _cleanup = TestCleanup::new(&origin_path);
+    let cache_dir = PathBuf::from("test_cache_dir");
+    let _cache_cleanup = TestCleanup::new(&cache_dir);
+
+    let origin = git2::Repository::init(&origin_path)?;
+    let v1 = commit_files(&origin, &[("lib.rs", "// version 1")], "v1");
+    origin.tag_lightweight("v1.0", &origin.find_object(v1, None)?, false)?;
+
+    let cache = Cache::new(cache_dir.clone(), None);
+    let mut repository = Repository::new(&cache_dir, "owner/repo", None)?;
+    repository.url = origin_path.canonicalize()?.to_string_lossy().to_string();
+    repository.path = cache.entry_path(&repository);
+    let options = CloneOptions {
+        reuse_existing: true,
+        ..Default::default()
+    };
+
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v1.to_string());
+    // Survives an update in place, but not a fresh clone
+    let sentinel = repository.path.join(".git").join("sentinel");
+    stdfs::write(&sentinel, "")?;
+
+    // A new commit on the default branch is fetched and checked out
+    let v2 = commit_files(&origin, &[("lib.rs", "// version 2")], "v2");
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v2.to_string());
+    assert_eq!(
+        fs::read_to_string(repository.path.join("lib.rs")).await?,
+        "// version 2"
+    );
+    assert!(sentinel.exists());
+
+    // Refs are resolved against the fetched state
+    repository.git_ref = Some("v1.0".to_string());
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v1.to_string());
+    assert!(sentinel.exists());
+
+    // A clone checked out at a ref is still updated in place, for branches too
+    let branch = origin.head()?.shorthand().unwrap_or_default().to_string();
+    let v3 = commit_files(&origin, &[("lib.rs", "// version 3")], "v3");
+    repository.git_ref = Some(branch);
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v3.to_string());
+    let v4 = commit_files(&origin, &[("lib.rs", "// version 4")], "v4");
+    repository.git_ref = None;
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v4.to_string());
+    repository.git_ref = Some("v1.0".to_string());
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v1.to_string());
+    assert!(sentinel.exists());
+
+    // A clone of another URL is replaced
+    let moved_path = PathBuf::from("test_cache_origin_moved");
+    let _moved_cleanup = TestCleanup::new(&moved_path);
+    git2::build::RepoBuilder::new().clone(&repository.url, &moved_path)?;
+    repository.url = moved_path.canonicalize()?.to_string_lossy().to_string();
+    let repo = git_utils::clone_repository(&repository, &options).await?;
+    assert_eq!(git_utils::head_commit(&repo)?, v1.to_string());
+    assert!(!sentinel.exists());
+
+    // Eviction: least recently used first, never the clones of the current run
+    let stale = cache_dir.join("stale-0000");
+    stdfs::create_dir_all(stale.join(".git"))?;
+    stdfs::write(stale.join("big.bin"), vec![0u8; 4096])?;
+    cache.touch(&stale)?;
+    stdfs::File::options()
+        .write(true)
+        .open(stale.join(".git").join("git2prompt-last-used"))?
+        .set_modified(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60))?;
+    cache.touch(&repository.path)?;
+    let unrelated = cache_dir.join("not-a-clone");
+    stdfs::create_dir_all(&unrelated)?;
+
+    let entries = cache.entries()?;
+    assert_eq!(
+        entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>(),
+        vec![stale.clone(), repository.path.clone()]
+    );
+    let bounded = Cache::new(cache_dir.clone(), Some(1));
+    // Not while another run uses it
+    let locks = [cache.lock(&stale)?, cache.lock(&repository.path)?];
+    assert!(
+        bounded
+            .prune(std::slice::from_ref(&repository.path))?
+            .is_empty()
+    );
+    assert!(cache.clean()?.is_empty());
+    assert!(stale.exists() && repository.path.exists());
+    drop(locks);
+    let removed = bounded.prune(std::slice::from_ref(&repository.path))?;
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].path, stale);
+    assert!(!stale.exists());
+    assert!(repository.path.exists());
+
+    let removed = cache.clean()?;
+    assert_eq!(removed.len(), 1);
+    assert!(!repository.path.exists());
+    assert!(unrelated.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_work_dir_keeps_unrelated_files() -> Result<(), Box<dyn std::error::Error>> {
+    let origin_path = PathBuf::from("test_work_dir_origin");
+    let _cleanup = TestCleanup::new(&origin_path);
+    let work_dir = PathBuf::from("test_work_dir");
+    let _work_cleanup = TestCleanup::new(&work_dir);
+    let output_dir = PathBuf::from("test_work_dir_output");
+    let _output_cleanup = TestCleanup::new(&output_dir);
+
+    let origin = git2::Repository::init(&origin_path)?;
+    commit_files(&origin, &[("lib.rs", "// lib")], "init");
+    let source = origin_path.canonicalize()?.to_string_lossy().to_string();
+
+    // A user's file in the work directory survives the run
+    stdfs::create_dir_all(&work_dir)?;
+    let keep = work_dir.join("keep.txt");
+    stdfs::write(&keep, "mine")?;
+
+    let paths = Generator::new(
+        PromptOptions::builder()
+            .no_cache(true)
+            .output_dir(&output_dir)
+            .work_dir(work_dir.clone())
+            .tokenizer(TokenizerKind::Chars)
+            .build(),
+    )
+    .await?
+    .process_sources(std::slice::from_ref(&source))
+    .await?;
+    assert_eq!(paths.len(), 1);
+    assert!(paths[0].starts_with(&output_dir));
+    assert!(fs::read_to_string(&paths[0]).await?.contains("// lib"));
+    assert!(keep.exists());
+    assert_eq!(stdfs::read_dir(&work_dir)?.count(), 1);
+
+    // A work directory created by the run is removed again
+    let created = work_dir.join("created");
+    Generator::new(
+        PromptOptions::builder()
+            .no_cache(true)
+            .output_dir(&output_dir)
+            .work_dir(created.clone())
+            .tokenizer(TokenizerKind::Chars)
+            .build(),
+    )
+    .await?
+    .process_sources(&[source])
+    .await?;
+    assert!(!created.exists());
+    assert!(keep.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_diff_between_commits() -> Result<(), Box<dyn std::error::Error>> {
+    let origin_path = PathBuf::from("test_diff_origin");
+    let _cleanup = TestCleanup::new(&origin_path);
+    let clone_dir = PathBuf::from("test_diff_clones");
+    let _clone_cleanup = TestCleanup::new(&clone_dir);
+
+    let origin = git2::Repository::init(&origin_path)?;
+    let v1 = commit_files(
+        &origin,
+        &[("src/lib.rs", "fn old() {}\n"), ("old.rs", "gone\n")],
+        "v1",
+    );
+    origin.tag_lightweight("v1", &origin.find_object(v1, None)?, false)?;
+    let mut index = origin.index()?;
+    index.remove_path(Path::new("old.rs"))?;
+    index.write()?;
+    stdfs::remove_file(origin_path.join("old.rs"))?;
+    let head = commit_files(
+        &origin,
+        &[
+            ("src/lib.rs", "fn new() {}\n"),
+            ("src/new.rs", "fn added() {}\n"),
+            ("logo.png", "not really a png"),
+        ],
+        "v2",
+    );
+
+    // Offline, on the repository itself
+    let diff = LocalDiff {
+        range: "v1..HEAD".parse()?,
+        content: PrContent::Both,
+    };
+    let (commit, buckets) =
+        processing::process_diff_files(&origin_path, &diff, None, None, None, None, &CharEstimator)
+            .await?;
+    assert_eq!(commit, head.to_string());
+    let entries: Vec<(String, EntryKind, &str)> = buckets["default"]
+        .files
+        .iter()
+        .map(|f| (f.path.display().to_string(), f.kind, f.content.as_str()))
+        .collect();
+    assert_eq!(
+        entries,
+        vec![
+            (
+                "old.rs".to_string(),
+                EntryKind::Patch,
+                "@@ -1 +0,0 @@\n-gone"
+            ),
+            ("src/lib.rs".to_string(), EntryKind::File, "fn new() {}\n"),
+            (
+                "src/lib.rs".to_string(),
+                EntryKind::Patch,
+                "@@ -1 +1 @@\n-fn old() {}\n+fn new() {}"
+            ),
+            ("src/new.rs".to_string(), EntryKind::File, "fn added() {}\n"),
+            (
+                "src/new.rs".to_string(),
+                EntryKind::Patch,
+                "@@ -0,0 +1 @@\n+fn added() {}"
+            ),
+        ]
+    );
+
+    // Through a clone, limited to a folder
+    let mut repository = Repository::new(&clone_dir, "owner/repo", None)?;
+    repository.url = origin_path.canonicalize()?.to_string_lossy().to_string();
+    let repository = processing::process_single_repository(
+        repository,
+        FileFilter {
+            folder: Some("src".to_string()),
+            ..Default::default()
+        },
+        None,
+        Some(LocalDiff {
+            range: "v1...HEAD".parse()?,
+            content: PrContent::Patch,
+        }),
+        CloneOptions::default(),
+        Arc::new(CharEstimator),
+    )
+    .await?;
+    assert_eq!(repository.commit, Some(head.to_string()));
+    let paths: Vec<String> = repository.content.unwrap()["default"]
+        .files
+        .iter()
+        .map(|f| f.label())
+        .collect();
+    assert_eq!(paths, vec!["src/lib.rs (patch)", "src/new.rs (patch)"]);
+
+    let missing = LocalDiff {
+        range: "v1..no-such-ref".parse()?,
+        content: PrContent::Patch,
+    };
+    let error = processing::process_diff_files(
+        &origin_path,
+        &missing,
+        None,
+        None,
+        None,
+        None,
+        &CharEstimator,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Git { .. }), "{:?}", error);
+    assert!(error.to_string().contains("no-such-ref"), "{}", error);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_files_from_commit_tree() -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = PathBuf::from("test_tree_repo");
+    let _cleanup = TestCleanup::new(&repo_path);
+
+    let repo = git2::Repository::init(&repo_path)?;
+    let v1 = commit_files(
+        &repo,
+        &[
+            (".gitignore", "*.log\n"),
+            ("debug.log", "tracked but ignored"),
+            ("logo.png", "binary"),
+            ("docs/guide.md", "# Guide"),
+            ("src/.git2promptignore", "generated/\n!keep.log\n"),
+            ("src/lib.rs", "// version 1"),
+            ("src/keep.log", "kept"),
+            ("src/generated/out.rs", "// generated"),
+        ],
+        "v1",
+    );
+    repo.tag_lightweight("v1", &repo.find_object(v1, None)?, false)?;
+    commit_files(&repo, &[("src/lib.rs", "// version 2")], "v2");
+    // Uncommitted changes stay out and untouched
+    stdfs::write(repo_path.join("src/lib.rs"), "// uncommitted")?;
+    stdfs::write(repo_path.join("junk.txt"), "junk")?;
+
+    let files_at =
+        |rev: &'static str, patterns: Option<Vec<String>>, folder: Option<&'static str>| {
+            let repo_path = repo_path.clone();
+            async move {
+                let (commit, buckets) = processing::process_tree_files(
+                    &repo_path,
+                    rev,
+                    None,
+                    patterns.as_deref(),
+                    None,
+                    folder,
+                    &CharEstimator,
+                )
+                .await
+                .unwrap();
+                let files: Vec<(String, String)> = buckets["default"]
+                    .files
+                    .iter()
+                    .map(|f| (f.path.display().to_string(), f.content.clone()))
+                    .collect();
+                (commit, files)
+            }
+        };
+    let file = |path: &str, content: &str| (path.to_string(), content.to_string());
+
+    let (commit, files) = files_at("v1", None, None).await;
+    assert_eq!(commit, v1.to_string());
+    assert_eq!(
+        files,
+        vec![
+            file(".gitignore", "*.log\n"),
+            file("docs/guide.md", "# Guide"),
+            file("src/.git2promptignore", "generated/\n!keep.log\n"),
+            file("src/keep.log", "kept"),
+            file("src/lib.rs", "// version 1"),
+        ]
+    );
+
+    let (_, files) = files_at("HEAD", Some(vec!["docs/".to_string()]), Some("src")).await;
+    assert_eq!(
+        files,
+        vec![
+            file("src/.git2promptignore", "generated/\n!keep.log\n"),
+            file("src/keep.log", "kept"),
+            file("src/lib.rs", "// version 2")
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(repo_path.join("src/lib.rs")).await?,
+        "// uncommitted"
+    );
+
+    let error = processing::process_tree_files(
+        &repo_path,
+        "no-such-rev",
+        None,
+        None,
+        None,
+        None,
+        &CharEstimator,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Git { .. }), "{:?}", error);
+    assert!(error.to_string().contains("no-such-rev"), "{}", error);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_working_tree_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = PathBuf::from("test_changes_repo");
+    let _cleanup = TestCleanup::new(&repo_path);
+
+    let repo = git2::Repository::init(&repo_path)?;
+    commit_files(
+        &repo,
+        &[("a.rs", "a1\n"), ("b.rs", "b1\n"), ("c.rs", "c1\n")],
+        "initial",
+    );
+    // a.rs: staged; b.rs: modified, not staged; c.rs: deleted; d.rs: untracked
+    stdfs::write(repo_path.join("a.rs"), "a2\n")?;
+    let mut index = repo.index()?;
+    index.add_path(Path::new("a.rs"))?;
+    index.write()?;
+    stdfs::write(repo_path.join("a.rs"), "a3\n")?;
+    stdfs::write(repo_path.join("b.rs"), "b2\n")?;
+    stdfs::remove_file(repo_path.join("c.rs"))?;
+    stdfs::write(repo_path.join("d.rs"), "d1\n")?;
+
+    let path = repo_path.as_path();
+    let changes_of = |scope, content| async move {
+        let (commit, buckets) = processing::process_working_tree_changes(
+            path,
+            &LocalChanges { scope, content },
+            None,
+            None,
+            None,
+            None,
+            &CharEstimator,
+        )
+        .await
+        .unwrap();
+        assert!(commit.is_some());
+        let mut entries: Vec<(String, String)> = buckets["default"]
+            .files
+            .iter()
+            .map(|f| (f.label(), f.content.clone()))
+            .collect();
+        entries.sort();
+        entries
+    };
+    let entry = |label: &str, content: &str| (label.to_string(), content.to_string());
+
+    // The staged content, not the working tree's
+    assert_eq!(
+        changes_of(ChangeScope::Staged, PrContent::Full).await,
+        vec![entry("a.rs", "a2\n")]
+    );
+    assert_eq!(
+        changes_of(ChangeScope::Unstaged, PrContent::Full).await,
+        vec![
+            entry("a.rs", "a3\n"),
+            entry("b.rs", "b2\n"),
+            entry("c.rs (patch)", "@@ -1 +0,0 @@\n-c1"),
+            entry("d.rs", "d1\n"),
+        ]
+    );
+    // Patches against HEAD, including untracked files
+    assert_eq!(
+        changes_of(ChangeScope::All, PrContent::Patch).await,
+        vec![
+            entry("a.rs (patch)", "@@ -1 +1 @@\n-a1\n+a3"),
+            entry("b.rs (patch)", "@@ -1 +1 @@\n-b1\n+b2"),
+            entry("c.rs (patch)", "@@ -1 +0,0 @@\n-c1"),
+            entry("d.rs (patch)", "@@ -0,0 +1 @@\n+d1"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sparse_clone_of_folder() -> Result<(), Box<dyn std::error::Error>> {
+    let work_path = PathBuf::from("test_sparse_work");
+    let _cleanup = TestCleanup::new(&work_path);
+    let bare_path = PathBuf::from("test_sparse_bare.git");
+    let _bare_cleanup = TestCleanup::new(&bare_path);
+    let clone_dir = PathBuf::from("test_sparse_clones");
+    let _clone_cleanup = TestCleanup::new(&clone_dir);
+
+    // Build the history in a working repository, then serve it from a bare clone
+    let work = git2::Repository::init(&work_path)?;
+    let v1 = commit_files(
+        &work,
+        &[
+            (".gitignore", "*.log\n"),
+            ("docs/guide.md", "# Guide"),
+            ("src/lib.rs", "pub mod nested;"),
+            ("src/debug.log", "noise"),
+            ("src/nested/mod.rs", "// nested"),
+        ],
+        "initial",
+    );
+    work.tag_lightweight("v1", &work.find_object(v1, None)?, false)?;
+    commit_files(&work, &[("src/lib.rs", "pub mod nested; // v2")], "v2");
+    git2::build::RepoBuilder::new()
+        .bare(true)
+        .clone(&work_path.canonicalize()?.to_string_lossy(), &bare_path)?;
+
+    let options = CloneOptions {
+        sparse_folder: Some("src".to_string()),
+        ..Default::default()
+    };
+
+    // Local bare repositories are valid sources, named after their directory
+    for (source, expected_name, expected_lib) in [
+        (
+            "./test_sparse_bare.git",
+            "test_sparse_bare",
+            "pub mod nested; // v2",
+        ),
+        (
+            "./test_sparse_bare.git@v1",
+            "test_sparse_bare@v1",
+            "pub mod nested;",
+        ),
+    ] {
+        let repository = Repository::new(&clone_dir, source, None)?;
+        assert_eq!(repository.name, expected_name);
+        let git_ref = repository.git_ref.clone();
+        git_utils::clone_repository(&repository, &options).await?;
+
+        // Only the folder and the ignore files above it are checked out
+        assert!(!repository.path.join("docs").exists(), "ref {:?}", git_ref);
+        assert!(repository.path.join(".gitignore").exists());
+        assert_eq!(
+            fs::read_to_string(repository.path.join("src/lib.rs")).await?,
+            expected_lib
+        );
+
+        let buckets = processing::collect_repository_files(
+            &repository.path,
+            None,
+            None,
+            None,
+            Some("src"),
+            &CharEstimator,
+        )
+        .await?;
+        let mut paths: Vec<String> = buckets["default"]
+            .files
+            .iter()
+            .map(|f| f.path.display().to_string())
+            .collect();
+        paths.sort();
+        // The root .gitignore still excludes src/debug.log
+        assert_eq!(paths, vec!["src/lib.rs", "src/nested/mod.rs"]);
+    }
+
+    Ok(())
+}
+
+/// A pull request as returned by the GitHub API, with head commit `sha`.
+fn pull_request_json(sha: &str) -> String {
+    serde_json::json!({
+        "title": "Rename old to new",
+        "body": "Replaces old with new.",
+        "user": {"login": "alice"},
+        "base": {"ref": "main", "sha": "0000000"},
+        "head": {"ref": "feature", "sha": sha}
+    })
+    .to_string()
+}
+
+/// A request received by [`MockApi`]: the request line and its headers, lower-cased names.
+#[derive(Debug, Clone)]
+struct MockRequest {
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl MockRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A local HTTP stand-in for the GitHub API serving canned responses by request target
+/// (path and query). Unknown targets get a 404.
+struct MockApi {
+    base_url: String,
+    requests: Arc<std::sync::Mutex<Vec<MockRequest>>>,
+}
+
+impl MockApi {
+    async fn start(routes: Vec<(String, u16, String)>) -> std::io::Result<Self> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let routes = Arc::new(routes);
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).await.is_err() {
+                        return;
+                    }
+                    let target = request_line
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_string();
+                    let mut headers = Vec::new();
+                    loop {
+                        let mut line = String::new();
+                        match reader.read_line(&mut line).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) if line.trim().is_empty() => break,
+                            Ok(_) => {
+                                if let Some((name, value)) = line.split_once(':') {
+                                    headers.push((
+                                        name.trim().to_lowercase(),
+                                        value.trim().to_string(),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    recorded.lock().unwrap().push(MockRequest {
+                        target: target.clone(),
+                        headers,
+                    });
+
+                    let (status, body) = routes
+                        .iter()
+                        .find(|(route, _, _)| *route == target)
+                        .map(|(_, status, body)| (*status, body.clone()))
+                        .unwrap_or((404, r#"{"message":"Not Found"}"#.to_string()));
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = writer.write_all(response.as_bytes()).await;
+                    let _ = writer.shutdown().await;
+                });
+            }
+        });
+
+        Ok(Self { base_url, requests })
+    }
+
+    fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[tokio::test]
+async fn test_pr_mode_against_mock_api() -> Result<(), Box<dyn std::error::Error>> {
+    let work_dir = PathBuf::from("test_pr_mock_repos");
+    let _cleanup = TestCleanup::new(&work_dir);
+
+    let files = serde_json::json!([
+        {"filename": "src/lib.rs", "status": "modified", "patch": "@@ -1 +1 @@\n-old\n+new"},
+        {"filename": "docs/guide.md", "status": "added", "patch": "@@ -0,0 +1 @@\n+# Guide"},
+        {"filename": "logo.png", "status": "added"}
+    ]);
+    let api = MockApi::start(vec![
+        (
+            "/api/v3/repos/owner/repo/pulls/7".to_string(),
+            200,
+            pull_request_json("abc123"),
+        ),
+        (
+            "/api/v3/repos/owner/repo/pulls/9".to_string(),
+            429,
+            r#"{"message":"API rate limit exceeded"}"#.to_string(),
+        ),
+        (
+            "/api/v3/repos/owner/repo/pulls/7/files?per_page=100&page=1".to_string(),
+            200,
+            files.to_string(),
+        ),
+        (
+            "/api/v3/repos/owner/repo/pulls/7/commits?per_page=100&page=1".to_string(),
+            200,
+            r#"[{"sha": "0123456789abcdef", "commit": {"message": "Rename old to new", "author": {"name": "Alice"}}}]"#.to_string(),
+        ),
+        (
+            "/api/v3/repos/owner/repo/pulls/7/reviews?per_page=100&page=1".to_string(),
+            200,
+            r#"[{"user": {"login": "bob"}, "state": "CHANGES_REQUESTED", "body": "Almost there"}]"#.to_string(),
+        ),
+        (
+            "/api/v3/repos/owner/repo/pulls/7/comments?per_page=100&page=1".to_string(),
+            200,
+            r#"[{"user": {"login": "bob"}, "body": "Why new?", "path": "src/lib.rs", "line": 1, "diff_hunk": "@@ -1 +1 @@\n-old\n+new"}]"#.to_string(),
+        ),
+        (
+            "/api/v3/repos/owner/repo/issues/7/comments?per_page=100&page=1".to_string(),
+            200,
+            r#"[{"user": null, "body": "Looks useful"}]"#.to_string(),
+        ),
+    ])
+    .await?;
+    let api_url = format!("{}/api/v3/", api.base_url);
+
+    let auth = Auth {
+        token: Some(Token::new("secret-token", "test")),
+        ..Default::default()
+    };
+    let repository = Repository::new(&work_dir, "owner/repo", None)?;
+    let repository = processing::process_single_repository(
+        repository,
+        FileFilter::default(),
+        Some(PullRequestOptions {
+            number: 7,
+            api_url: Some(api_url.clone()),
+            content: PrContent::Patch,
+            metadata: true,
+        }),
+        None,
+        CloneOptions {
+            auth: auth.clone(),
+            ..Default::default()
+        },
+        Arc::new(CharEstimator),
+    )
+    .await?;
+
+    assert_eq!(repository.commit.as_deref(), Some("abc123"));
+    let buckets = repository.content.expect("PR mode should produce content");
+
+    // The description and discussion come first
+    let (metadata, changes) = buckets["default"].files.split_first().unwrap();
+    assert_eq!(metadata.kind, EntryKind::Metadata);
+    assert_eq!(metadata.label(), "PR-7.md (metadata)");
+    for expected in [
+        "# Pull Request #7: Rename old to new",
+        "- Author: @alice",
+        "- Base: main",
+        "- Head: feature (abc123)",
+        "Replaces old with new.",
+        "- `0123456` Rename old to new (Alice)",
+        "### @bob: CHANGES_REQUESTED\n\nAlmost there",
+        "### src/lib.rs:1 (@bob)\n\n```diff\n@@ -1 +1 @@\n-old\n+new\n```\n\nWhy new?",
+        "### @ghost\n\nLooks useful",
+    ] {
+        assert!(
+            metadata.content.contains(expected),
+            "{:?} not in {}",
+            expected,
+            metadata.content
+        );
+    }
+
+    assert!(
+        changes
+            .iter()
+            .all(|f| f.kind == EntryKind::Patch && f.language == "diff")
+    );
+    let mut files: Vec<(String, String)> = changes
+        .iter()
+        .map(|f| (f.path.display().to_string(), f.content.clone()))
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            (
+                "docs/guide.md".to_string(),
+                "@@ -0,0 +1 @@\n+# Guide".to_string()
+            ),
+            (
+                "src/lib.rs".to_string(),
+                "@@ -1 +1 @@\n-old\n+new".to_string()
+            ),
+        ]
+    );
+
+    let requests = api.requests();
+    assert_eq!(requests.len(), 6);
+    assert!(
+        requests
+            .iter()
+            .any(|r| r.target == "/api/v3/repos/owner/repo/pulls/7/files?per_page=100&page=1")
+    );
+    for request in &requests {
+        // The mock API is served over plain HTTP, which never receives a token
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(request.header("user-agent"), Some("git2prompt"));
+    }
+
+    // API errors are reported with their status
+    let repository = Repository::new(&work_dir, "owner/repo", None)?;
+    let error = processing::process_single_repository(
+        repository,
+        FileFilter::default(),
+        Some(PullRequestOptions {
+            number: 8,
+            api_url: Some(api_url),
+            content: PrContent::Patch,
+            metadata: true,
+        }),
+        None,
+        CloneOptions {
+            auth,
+            ..Default::default()
+        },
+        Arc::new(CharEstimator),
+    )
+    .await
+    .unwrap_err();
+    assert!(error.is_not_found(), "{:?}", error);
+    assert!(!error.is_rate_limited());
+    assert!(error.to_string().contains("404"), "{}", error);
+
+    let client =
+        GitHubClient::new(Some(&format!("{}/api/v3", api.base_url)), Auth::default()).await;
+    let error = client.pull_request("owner/repo", 9).await.unwrap_err();
+    assert!(error.is_rate_limited(), "{:?}", error);
+    assert!(!error.is_not_found());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pr_mode_paginates_and_fetches_full_files() -> Result<(), Box<dyn std::error::Error>> {
+    let work_dir = PathBuf::from("test_pr_full_repos");
+    let _cleanup = TestCleanup::new(&work_dir);
+
+    // A full first page means there may be more
+    let first_page: Vec<_> = (0..100)
+        .map(|i| {
+            serde_json::json!({
+                "filename": format!("generated/file{}.txt", i),
+                "status": "added",
+                "patch": "@@ -0,0 +1 @@\n+generated"
+            })
+        })
+        .collect();
+    let second_page = serde_json::json!([
+        {"filename": "src/lib.rs", "status": "modified", "patch": "@@ -1 +1 @@\n-old\n+new"},
+        {"filename": "src/old.rs", "status": "removed", "patch": "@@ -1 +0,0 @@\n-gone"}
+    ]);
+    let api = MockApi::start(vec![
+        (
+            "/repos/owner/repo/pulls/3".to_string(),
+            200,
+            pull_request_json("abc123"),
+        ),
+        (
+            "/repos/owner/repo/pulls/3/files?per_page=100&page=1".to_string(),
+            200,
+            serde_json::Value::from(first_page).to_string(),
+        ),
+        (
+            "/repos/owner/repo/pulls/3/files?per_page=100&page=2".to_string(),
+            200,
+            second_page.to_string(),
+        ),
+        (
+            "/repos/owner/repo/contents/src/lib.rs?ref=abc123".to_string(),
+            200,
+            "fn new() {}\n".to_string(),
+        ),
+    ])
+    .await?;
+
+    let repository = Repository::new(&work_dir, "owner/repo", None)?;
+    let repository = processing::process_single_repository(
+        repository,
+        FileFilter {
+            ignore_patterns: Some(vec!["generated/".to_string()]),
+            ..Default::default()
+        },
+        Some(PullRequestOptions {
+            number: 3,
+            api_url: Some(api.base_url.clone()),
+            content: PrContent::Both,
+            metadata: false,
+        }),
+        None,
+        CloneOptions::default(),
+        Arc::new(CharEstimator),
+    )
+    .await?;
+
+    let requests = api.requests();
+    assert!(
+        requests
+            .iter()
+            .any(|r| r.target.ends_with("/files?per_page=100&page=2"))
+    );
+    // Ignored and removed files are not downloaded
+    assert_eq!(
+        requests
+            .iter()
+            .filter(|r| r.target.contains("/contents/"))
+            .count(),
+        1
+    );
+
+    let files = &repository.content.as_ref().unwrap()["default"].files;
+    let entries: Vec<(String, EntryKind, &str)> = files
+        .iter()
+        .map(|f| (f.path.display().to_string(), f.kind, f.content.as_str()))
+        .collect();
+    assert_eq!(
+        entries,
+        vec![
+            ("src/lib.rs".to_string(), EntryKind::File, "fn new() {}\n"),
+            (
+                "src/lib.rs".to_string(),
+                EntryKind::Patch,
+                "@@ -1 +1 @@\n-old\n+new"
+            ),
+            (
+                "src/old.rs".to_string(),
+                EntryKind::Patch,
+                "@@ -1 +0,0 @@\n-gone"
+            ),
+        ]
+    );
+
+    // Patches are rendered as diffs, distinct from the full file
+    let options = RenderOptions::default();
+    let items: Vec<DocumentItem> = files
+        .iter()
+        .map(|f| DocumentItem::new(None, f.clone()))
+        .collect();
+    let info = DocumentInfo {
+        title: "PR",
+        repository: Some(&repository.name),
+        bucket: None,
+        commits: &[],
+        part: None,
+    };
+    let markdown = options.render(&info, &items, &CharEstimator);
+    assert!(markdown.contains("## File: src/lib.rs\n```rust\nfn new() {}\n"));
+    assert!(markdown.contains("## File: src/lib.rs (patch)\n```diff\n@@ -1 +1 @@"));
+
+    let xml = RenderOptions {
+        format: OutputFormat::Xml,
+        ..Default::default()
+    }
+    .render(&info, &items, &CharEstimator);
+    assert!(xml.contains(r#"kind="patch""#), "{}", xml);
+
+    Ok(())
+}